@@ -0,0 +1,15 @@
+use near_sdk::Balance;
+
+pub type Weight = u128;
+
+pub const BONE: Balance = 1_000_000_000_000_000_000_000_000;
+
+/// Decimals of pool shares, matching `BONE`.
+pub const POOL_DECIMALS: u8 = 24;
+
+pub const MIN_BPOW_BASE: Balance = 1;
+pub const MAX_BPOW_BASE: Balance = 2 * BONE - 1;
+pub const BPOW_PRECISION: Balance = BONE / 10_000_000_000;
+
+pub const MAX_IN_RATIO: Balance = BONE / 2;
+pub const MAX_OUT_RATIO: Balance = BONE / 3 + 1;
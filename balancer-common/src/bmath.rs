@@ -0,0 +1,356 @@
+use near_sdk::Balance;
+use uint::construct_uint;
+
+use crate::bconst::{Weight, BONE, BPOW_PRECISION, MAX_BPOW_BASE, MIN_BPOW_BASE};
+
+construct_uint! {
+    /// 256-bit unsigned integer used for intermediate results of fixed-point math.
+    pub struct U256(4);
+}
+
+pub fn btoi(a: Balance) -> Balance {
+    a / BONE
+}
+
+pub fn bfloor(a: Balance) -> Balance {
+    btoi(a) * BONE
+}
+
+pub fn badd(a: Balance, b: Balance) -> Balance {
+    a.checked_add(b).expect("ERR_ADD_OVERFLOW")
+}
+
+pub fn bsub(a: Balance, b: Balance) -> Balance {
+    let (c, flag) = bsub_sign(a, b);
+    assert!(!flag, "ERR_SUB_UNDERFLOW");
+    c
+}
+
+/// Returns absolute difference of `a` and `b` and whether it is negative.
+pub fn bsub_sign(a: Balance, b: Balance) -> (Balance, bool) {
+    if a >= b {
+        (a - b, false)
+    } else {
+        (b - a, true)
+    }
+}
+
+/// Multiplies two fixed-point numbers, rounding half up.
+pub fn bmul(a: Balance, b: Balance) -> Balance {
+    let c0 = U256::from(a) * U256::from(b);
+    let c1 = c0 + U256::from(BONE / 2);
+    let c2 = c1 / U256::from(BONE);
+    assert!(c2 <= U256::from(Balance::MAX), "ERR_MUL_OVERFLOW");
+    c2.as_u128()
+}
+
+/// Divides two fixed-point numbers, rounding half up.
+pub fn bdiv(a: Balance, b: Balance) -> Balance {
+    assert_ne!(b, 0, "ERR_DIV_ZERO");
+    let c0 = U256::from(a) * U256::from(BONE);
+    let c1 = c0 + U256::from(b / 2);
+    let c2 = c1 / U256::from(b);
+    assert!(c2 <= U256::from(Balance::MAX), "ERR_DIV_INTERNAL");
+    c2.as_u128()
+}
+
+/// Raises fixed-point `a` to the integer power `n` by repeated squaring.
+pub fn bpowi(a: Balance, n: Balance) -> Balance {
+    let mut a = a;
+    let mut n = n;
+    let mut z = if n % 2 != 0 { a } else { BONE };
+    n /= 2;
+    while n != 0 {
+        a = bmul(a, a);
+        if n % 2 != 0 {
+            z = bmul(z, a);
+        }
+        n /= 2;
+    }
+    z
+}
+
+/// Computes `base ^ exp` where both are fixed-point, using whole power for the integer part of
+/// `exp` and binomial approximation for the fractional part.
+pub fn bpow(base: Balance, exp: Balance) -> Balance {
+    assert!(base >= MIN_BPOW_BASE, "ERR_BPOW_BASE_TOO_LOW");
+    assert!(base <= MAX_BPOW_BASE, "ERR_BPOW_BASE_TOO_HIGH");
+
+    let whole = bfloor(exp);
+    let remain = bsub(exp, whole);
+    let whole_pow = bpowi(base, btoi(whole));
+    if remain == 0 {
+        return whole_pow;
+    }
+    let partial_result = bpow_approx(base, remain, BPOW_PRECISION);
+    bmul(whole_pow, partial_result)
+}
+
+/// Binomial series for `base ^ exp`, where `exp` is fractional. Stops once term is below `precision`.
+pub fn bpow_approx(base: Balance, exp: Balance, precision: Balance) -> Balance {
+    let a = exp;
+    let (x, xneg) = bsub_sign(base, BONE);
+    let mut term = BONE;
+    let mut sum = term;
+    let mut negative = false;
+
+    // term(k) = numer / denom
+    //         = (product(a - i - 1, i=1-->k) * x^k) / (k!)
+    // each iteration, multiply previous term by (a-(k-1)) * x / k
+    // continue until term is less than precision
+    let mut i = 1;
+    while term >= precision {
+        let big_k = i * BONE;
+        let (c, cneg) = bsub_sign(a, bsub(big_k, BONE));
+        term = bmul(term, bmul(c, x));
+        term = bdiv(term, big_k);
+        if term == 0 {
+            break;
+        }
+        if xneg {
+            negative = !negative;
+        }
+        if cneg {
+            negative = !negative;
+        }
+        if negative {
+            sum = bsub(sum, term);
+        } else {
+            sum = badd(sum, term);
+        }
+        i += 1;
+    }
+    sum
+}
+
+/**********************************************************************************************
+// calcSpotPrice                                                                             //
+// sP = spotPrice                                                                            //
+// bI = tokenBalanceIn                ( bI / wI )         1                                  //
+// bO = tokenBalanceOut         sP =  -----------  *  ----------                             //
+// wI = tokenWeightIn                 ( bO / wO )     ( 1 - sF )                             //
+// wO = tokenWeightOut                                                                       //
+// sF = swapFee                                                                              //
+**********************************************************************************************/
+pub fn calc_spot_price(
+    balance_in: Balance,
+    weight_in: Weight,
+    balace_out: Balance,
+    weight_out: Weight,
+    swap_fee: Balance,
+) -> Balance {
+    let numer = bdiv(balance_in, weight_in);
+    let denom = bdiv(balace_out, weight_out);
+    let ratio = bdiv(numer, denom);
+    let scale = bdiv(BONE, bsub(BONE, swap_fee));
+    bmul(ratio, scale)
+}
+
+/**********************************************************************************************
+// calcOutGivenIn                                                                            //
+// aO = tokenAmountOut                                                                       //
+// bO = tokenBalanceOut                                                                      //
+// bI = tokenBalanceIn              /      /            bI             \    (wI / wO) \      //
+// aI = tokenAmountIn    aO = bO * |  1 - | --------------------------  | ^            |     //
+// wI = tokenWeightIn               \      \ ( bI + ( aI * ( 1 - sF )) /              /      //
+// wO = tokenWeightOut                                                                       //
+// sF = swapFee                                                                              //
+**********************************************************************************************/
+pub fn calc_out_given_in(
+    token_balance_in: Balance,
+    token_weight_in: Weight,
+    token_balance_out: Balance,
+    token_weight_out: Weight,
+    token_amount_in: Balance,
+    swap_fee: Balance,
+) -> Balance {
+    let weight_ratio = bdiv(token_weight_in, token_weight_out);
+    let adjusted_in = bmul(token_amount_in, bsub(BONE, swap_fee));
+    let y = bdiv(token_balance_in, badd(token_balance_in, adjusted_in));
+    let power = bpow(y, weight_ratio);
+    bmul(token_balance_out, bsub(BONE, power))
+}
+
+/**********************************************************************************************
+// calcInGivenOut                                                                            //
+// aI = tokenAmountIn                                                                        //
+// bO = tokenBalanceOut               /  /     bO      \    (wO / wI)      \                 //
+// bI = tokenBalanceIn          bI * |  | ------------  | ^            - 1  |                 //
+// aO = tokenAmountOut    aI =        \  \ ( bO - aO ) /                   /                 //
+// wI = tokenWeightIn           --------------------------------------------                 //
+// wO = tokenWeightOut                          ( 1 - sF )                                   //
+// sF = swapFee                                                                              //
+**********************************************************************************************/
+pub fn calc_in_given_out(
+    token_balance_in: Balance,
+    token_weight_in: Weight,
+    token_balance_out: Balance,
+    token_weight_out: Weight,
+    token_amount_out: Balance,
+    swap_fee: Balance,
+) -> Balance {
+    let weight_ratio = bdiv(token_weight_out, token_weight_in);
+    let diff = bsub(token_balance_out, token_amount_out);
+    let y = bdiv(token_balance_out, diff);
+    let power = bsub(bpow(y, weight_ratio), BONE);
+    bdiv(bmul(token_balance_in, power), bsub(BONE, swap_fee))
+}
+
+/**********************************************************************************************
+// calcPoolOutGivenSingleIn                                                                  //
+// pAo = poolAmountOut         /                                              \              //
+// tAi = tokenAmountIn        ///      /     //    wI \      \\       \     wI \             //
+// wI = tokenWeightIn        //| tAi *| 1 - || 1 - --  | * sF || + tBi \    --  \            //
+// tW = totalWeight     pAo=||  \      \     \\    tW /      //         | ^ tW   | * pS - pS //
+// tBi = tokenBalanceIn      \\  ------------------------------------- /        /            //
+// pS = poolSupply            \\                    tBi               /        /             //
+// sF = swapFee                \                                              /              //
+**********************************************************************************************/
+pub fn calc_pool_out_given_single_in(
+    token_balance_in: Balance,
+    token_weight_in: Weight,
+    pool_supply: Balance,
+    total_weight: Weight,
+    token_amount_in: Balance,
+    swap_fee: Balance,
+) -> Balance {
+    // Charge the trading fee for the proportion of tokenAi
+    // which is implicitly traded to the other pool tokens.
+    // That proportion is (1 - weightTokenIn)
+    let normalized_weight = bdiv(token_weight_in, total_weight);
+    let zaz = bmul(bsub(BONE, normalized_weight), swap_fee);
+    let token_amount_in_after_fee = bmul(token_amount_in, bsub(BONE, zaz));
+
+    let new_token_balance_in = badd(token_balance_in, token_amount_in_after_fee);
+    let token_in_ratio = bdiv(new_token_balance_in, token_balance_in);
+
+    let pool_ratio = bpow(token_in_ratio, normalized_weight);
+    let new_pool_supply = bmul(pool_ratio, pool_supply);
+    bsub(new_pool_supply, pool_supply)
+}
+
+/**********************************************************************************************
+// calcSingleInGivenPoolOut                                                                  //
+// tAi = tokenAmountIn              //(pS + pAo)\     /    1    \\                           //
+// pS = poolSupply                 || ---------  | ^ | --------- || * bI - bI                //
+// pAo = poolAmountOut              \\    pS    /     \(wI / tW)//                           //
+// bI = balanceIn          tAi =  --------------------------------------------               //
+// wI = weightIn                              /      wI  \                                   //
+// tW = totalWeight                          |  1 - ----  |  * sF                            //
+// sF = swapFee                               \      tW  /                                   //
+**********************************************************************************************/
+pub fn calc_single_in_given_pool_out(
+    token_balance_in: Balance,
+    token_weight_in: Weight,
+    pool_supply: Balance,
+    total_weight: Weight,
+    pool_amount_out: Balance,
+    swap_fee: Balance,
+) -> Balance {
+    let normalized_weight = bdiv(token_weight_in, total_weight);
+    let new_pool_supply = badd(pool_supply, pool_amount_out);
+    let pool_ratio = bdiv(new_pool_supply, pool_supply);
+
+    // uint newBalTi = poolRatio^(1/weightTi) * balTi;
+    let boo = bdiv(BONE, normalized_weight);
+    let token_in_ratio = bpow(pool_ratio, boo);
+    let new_token_balance_in = bmul(token_in_ratio, token_balance_in);
+    let token_amount_in_after_fee = bsub(new_token_balance_in, token_balance_in);
+
+    // Do reverse order of fees charged in joinswap_ExternAmountIn, this way
+    //     ``` pAo == joinswap_ExternAmountIn(Ti, joinswap_PoolAmountOut(pAo, Ti)) ```
+    // uint tAi = tAiAfterFee / (1 - (1-weightTi) * swapFee) ;
+    let zar = bmul(bsub(BONE, normalized_weight), swap_fee);
+    bdiv(token_amount_in_after_fee, bsub(BONE, zar))
+}
+
+/**********************************************************************************************
+// calcSingleOutGivenPoolIn                                                                  //
+// tAo = tokenAmountOut            /      /                                             \\   //
+// bO = tokenBalanceOut           /      //       pS - pAi       \     /    1    \      \\  //
+// pAi = poolAmountIn            | bO - || ----------------------- | ^ | --------- | * b0 || //
+// ps = poolSupply                \      \\          pS           /     \(wO / tW)/      //  //
+// wI = tokenWeightIn      tAo =   \      \                                             //   //
+// tW = totalWeight                    /     /      wO \       \                             //
+// sF = swapFee                    *  | 1 - |  1 - ---- | * sF  |                            //
+//                                     \     \      tW /       /                             //
+**********************************************************************************************/
+pub fn calc_single_out_given_pool_in(
+    token_balance_out: Balance,
+    token_weight_out: Weight,
+    pool_supply: Balance,
+    total_weight: Weight,
+    pool_amount_in: Balance,
+    swap_fee: Balance,
+) -> Balance {
+    let normalized_weight = bdiv(token_weight_out, total_weight);
+    let new_pool_supply = bsub(pool_supply, pool_amount_in);
+    let pool_ratio = bdiv(new_pool_supply, pool_supply);
+
+    // newBalTo = poolRatio^(1/weightTo) * balTo;
+    let token_out_ratio = bpow(pool_ratio, bdiv(BONE, normalized_weight));
+    let new_token_balance_out = bmul(token_out_ratio, token_balance_out);
+
+    let token_amount_out_before_swap_fee = bsub(token_balance_out, new_token_balance_out);
+
+    // charge swap fee on the output token side
+    // uint tAo = tAoBeforeSwapFee * (1 - (1-weightTo) * swapFee)
+    let zaz = bmul(bsub(BONE, normalized_weight), swap_fee);
+    bmul(token_amount_out_before_swap_fee, bsub(BONE, zaz))
+}
+
+/**********************************************************************************************
+// calcPoolInGivenSingleOut                                                                  //
+// pAi = poolAmountIn               // /               tAo             \\     / wO \     \   //
+// bO = tokenBalanceOut            // | bO - -------------------------- |\   | ---- |     \  //
+// tAo = tokenAmountOut      pS - ||   \     1 - ((1 - (tO / tW)) * sF)/  | ^ \ tW /  * pS | //
+// ps = poolSupply                 \\ -----------------------------------/                /  //
+// wO = tokenWeightOut  pAi =       \\               bO                 /                /   //
+// tW = totalWeight                                                                          //
+// sF = swapFee                                                                              //
+**********************************************************************************************/
+pub fn calc_pool_in_given_single_out(
+    token_balance_out: Balance,
+    token_weight_out: Weight,
+    pool_supply: Balance,
+    total_weight: Weight,
+    token_amount_out: Balance,
+    swap_fee: Balance,
+) -> Balance {
+    // charge swap fee on the output token side
+    let normalized_weight = bdiv(token_weight_out, total_weight);
+    // uint tAoBeforeSwapFee = tAo / (1 - (1-weightTo) * swapFee) ;
+    let zoo = bsub(BONE, normalized_weight);
+    let zar = bmul(zoo, swap_fee);
+    let token_amount_out_before_swap_fee = bdiv(token_amount_out, bsub(BONE, zar));
+
+    let new_token_balance_out = bsub(token_balance_out, token_amount_out_before_swap_fee);
+    let token_out_ratio = bdiv(new_token_balance_out, token_balance_out);
+
+    // uint newPoolSupply = (ratioTo ^ weightTo) * poolSupply;
+    let pool_ratio = bpow(token_out_ratio, normalized_weight);
+    let new_pool_supply = bmul(pool_ratio, pool_supply);
+    bsub(pool_supply, new_pool_supply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bmul_bdiv() {
+        assert_eq!(bmul(2 * BONE, 3 * BONE), 6 * BONE);
+        assert_eq!(bdiv(BONE, 3 * BONE), 333_333_333_333_333_333_333_333);
+        assert_eq!(bdiv(2 * BONE, 3 * BONE), 666_666_666_666_666_666_666_667);
+        assert_eq!(bmul(BONE / 2, 1), 1);
+    }
+
+    #[test]
+    fn test_bpow() {
+        assert_eq!(bpowi(2 * BONE - 1, 0), BONE);
+        assert_eq!(bpow(BONE / 2, 3 * BONE), BONE / 8);
+        // 0.81 ^ 1.5 = 0.729
+        let result = bpow(81 * BONE / 100, 3 * BONE / 2);
+        assert!(bsub_sign(result, 729 * BONE / 1000).0 < BPOW_PRECISION);
+    }
+}
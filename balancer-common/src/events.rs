@@ -0,0 +1,129 @@
+//! Events logged by the pool and the smart pool as JSON, one per log line:
+//! `{"version": "1.0.0", "event": "LOG_SWAP", "data": {...}}`.
+//! Events are only logged once the token transfers of the operation succeeded.
+
+use near_sdk::json_types::U128;
+use near_sdk::{env, AccountId};
+use serde::{Deserialize, Serialize};
+
+/// Version of the event schema, bumped on any incompatible change.
+pub const EVENT_VERSION: &str = "1.0.0";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", content = "data")]
+pub enum Event {
+    #[serde(rename = "LOG_SWAP")]
+    Swap {
+        caller: AccountId,
+        token_in: AccountId,
+        token_out: AccountId,
+        token_amount_in: U128,
+        token_amount_out: U128,
+    },
+    #[serde(rename = "LOG_JOIN")]
+    Join {
+        caller: AccountId,
+        token_in: AccountId,
+        token_amount_in: U128,
+    },
+    #[serde(rename = "LOG_EXIT")]
+    Exit {
+        caller: AccountId,
+        token_out: AccountId,
+        token_amount_out: U128,
+    },
+    #[serde(rename = "LOG_BIND")]
+    Bind {
+        caller: AccountId,
+        token: AccountId,
+        balance: U128,
+        denorm: U128,
+    },
+    #[serde(rename = "LOG_UNBIND")]
+    Unbind { caller: AccountId, token: AccountId },
+    #[serde(rename = "LOG_FEE_CHANGE")]
+    FeeChange { caller: AccountId, swap_fee: U128 },
+    #[serde(rename = "LOG_PROTOCOL_FEE_CHANGE")]
+    ProtocolFeeChange {
+        caller: AccountId,
+        protocol_fee: U128,
+    },
+    #[serde(rename = "LOG_PAUSE")]
+    Pause { caller: AccountId },
+    #[serde(rename = "LOG_UNPAUSE")]
+    Unpause { caller: AccountId },
+    #[serde(rename = "LOG_FORCE_UNLOCK")]
+    ForceUnlock { caller: AccountId },
+    #[serde(rename = "LOG_GUARDIAN_CHANGE")]
+    GuardianChange {
+        caller: AccountId,
+        guardian: AccountId,
+    },
+    #[serde(rename = "LOG_CONTROLLER_CHANGE")]
+    ControllerChange {
+        caller: AccountId,
+        controller: AccountId,
+    },
+}
+
+/// Single log line with the schema version.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EventLog {
+    pub version: String,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+impl EventLog {
+    /// Parses a log line of the pool, returns `None` if it's not an event.
+    pub fn from_log(log: &str) -> Option<Self> {
+        serde_json::from_str(log).ok()
+    }
+}
+
+impl Event {
+    pub fn emit(self) {
+        let log = EventLog {
+            version: EVENT_VERSION.to_string(),
+            event: self,
+        };
+        let log = serde_json::to_string(&log).unwrap();
+        #[cfg(all(feature = "test-utils", not(target_arch = "wasm32")))]
+        LOGS.with(|logs| logs.borrow_mut().push(log.clone()));
+        env::log(log.as_bytes());
+    }
+}
+
+#[cfg(all(feature = "test-utils", not(target_arch = "wasm32")))]
+thread_local! {
+    /// Events emitted by the current test, as the mocked blockchain doesn't expose its logs.
+    static LOGS: std::cell::RefCell<Vec<String>> = std::cell::RefCell::new(vec![]);
+}
+
+/// Returns and clears the event logs emitted so far by the current test.
+#[cfg(all(feature = "test-utils", not(target_arch = "wasm32")))]
+pub fn take_logs() -> Vec<String> {
+    LOGS.with(|logs| logs.borrow_mut().drain(..).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_format() {
+        let log = EventLog {
+            version: EVENT_VERSION.to_string(),
+            event: Event::FeeChange {
+                caller: "alice".to_string(),
+                swap_fee: 1_000.into(),
+            },
+        };
+        let s = serde_json::to_string(&log).unwrap();
+        assert_eq!(
+            s,
+            r#"{"version":"1.0.0","event":"LOG_FEE_CHANGE","data":{"caller":"alice","swap_fee":"1000"}}"#
+        );
+        assert_eq!(EventLog::from_log(&s), Some(log));
+    }
+}
@@ -0,0 +1,6 @@
+//! Fixed-point math and events of Balancer pools, shared by the pool and the contracts built on
+//! top of it.
+
+pub mod bconst;
+pub mod bmath;
+pub mod events;
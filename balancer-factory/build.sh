@@ -0,0 +1,9 @@
+#!/bin/bash
+set -e
+
+# Pool wasm is embedded into the factory, so it's rebuilt first.
+(cd ../balancer-pool && ./build.sh)
+
+RUSTFLAGS='-C link-arg=-s' cargo +stable build --target wasm32-unknown-unknown --release
+mkdir -p ./res
+cp target/wasm32-unknown-unknown/release/balancer_factory.wasm ./res/
@@ -0,0 +1,430 @@
+use borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::{UnorderedSet, Vector};
+use near_sdk::json_types::U128;
+use near_sdk::{env, ext_contract, near_bindgen, AccountId, Balance, Promise, PromiseResult};
+use serde_json::json;
+
+use near_lib::token::ext_nep21;
+
+const POOL_WASM: &[u8] = include_bytes!("../../balancer-pool/res/balancer_pool.wasm");
+
+const NO_DEPOSIT: Balance = 0;
+
+pub mod gas {
+    pub const BASE_GAS: u64 = 10_000_000_000_000;
+
+    pub const POOL_NEW: u64 = BASE_GAS;
+
+    pub const POOL_SET_CONTROLLER: u64 = BASE_GAS;
+
+    pub const POOL_SET_PROTOCOL_FEE: u64 = BASE_GAS;
+
+    pub const POOL_COLLECT_PROTOCOL_FEE: u64 = BASE_GAS;
+
+    pub const POOL_PAUSE: u64 = BASE_GAS;
+
+    pub const POOL_SET_GUARDIAN: u64 = BASE_GAS;
+
+    pub const POOL_STORAGE_DEPOSIT: u64 = BASE_GAS;
+
+    pub const ON_NEW_POOL_CALLBACK: u64 = BASE_GAS;
+
+    pub const NEP21_GET_BALANCE: u64 = BASE_GAS;
+
+    pub const NEP21_TRANSFER: u64 = BASE_GAS;
+
+    pub const ON_COLLECT_CALLBACK: u64 = NEP21_TRANSFER + BASE_GAS;
+}
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct BFactory {
+    blabs: AccountId,
+    is_bpool: UnorderedSet<AccountId>,
+    pools: Vector<AccountId>,
+}
+
+impl Default for BFactory {
+    fn default() -> Self {
+        panic!("BFactory should be initialized before usage")
+    }
+}
+
+#[near_bindgen]
+impl BFactory {
+    #[init]
+    pub fn new() -> Self {
+        assert!(!env::state_exists(), "Already initialized");
+        Self {
+            blabs: env::predecessor_account_id(),
+            is_bpool: UnorderedSet::new(b"i".to_vec()),
+            pools: Vector::new(b"p".to_vec()),
+        }
+    }
+
+    pub fn isBPool(&self, b: AccountId) -> bool {
+        self.is_bpool.contains(&b)
+    }
+
+    pub fn getNumPools(&self) -> u64 {
+        self.pools.len()
+    }
+
+    /// Returns up to `limit` pools starting from `from_index`, in order of creation.
+    pub fn getPools(&self, from_index: u64, limit: u64) -> Vec<AccountId> {
+        (from_index..std::cmp::min(from_index + limit, self.pools.len()))
+            .map(|index| self.pools.get(index).unwrap())
+            .collect()
+    }
+
+    /// Creates `name` sub-account with a new pool, controlled by the caller. Pool shares are
+    /// named `share_name` and `share_symbol` if given, see the pool's `new`.
+    /// Attached deposit covers the storage of the pool account.
+    #[payable]
+    pub fn newBPool(
+        &mut self,
+        name: String,
+        share_name: Option<String>,
+        share_symbol: Option<String>,
+    ) -> Promise {
+        let pool_id = format!("{}.{}", name, env::current_account_id());
+        assert!(
+            env::is_valid_account_id(pool_id.as_bytes()),
+            "ERR_INVALID_POOL_ID"
+        );
+        assert!(!self.isBPool(pool_id.clone()), "ERR_IS_BPOOL");
+        let controller = env::predecessor_account_id();
+        Promise::new(pool_id.clone())
+            .create_account()
+            .transfer(env::attached_deposit())
+            .deploy_contract(POOL_WASM.to_vec())
+            .function_call(
+                b"new".to_vec(),
+                json!({ "name": share_name, "symbol": share_symbol })
+                    .to_string()
+                    .into_bytes(),
+                NO_DEPOSIT,
+                gas::POOL_NEW,
+            )
+            .function_call(
+                b"setController".to_vec(),
+                json!({ "controller": controller }).to_string().into_bytes(),
+                NO_DEPOSIT,
+                gas::POOL_SET_CONTROLLER,
+            )
+            .then(ext_self::on_new_pool(
+                pool_id,
+                controller,
+                env::attached_deposit().into(),
+                &env::current_account_id(),
+                NO_DEPOSIT,
+                gas::ON_NEW_POOL_CALLBACK,
+            ))
+    }
+
+    pub fn getBLabs(&self) -> AccountId {
+        self.blabs.clone()
+    }
+
+    pub fn setBLabs(&mut self, b: AccountId) {
+        assert_eq!(env::predecessor_account_id(), self.blabs, "ERR_NOT_BLABS");
+        env::log(format!("LOG_BLABS: {} -> {}", self.blabs, b).as_bytes());
+        self.blabs = b;
+    }
+
+    /// Sets the fraction of swap fees of `pool`, that is minted as pool shares for the factory.
+    pub fn setProtocolFee(&mut self, pool: AccountId, protocolFee: U128) -> Promise {
+        assert_eq!(env::predecessor_account_id(), self.blabs, "ERR_NOT_BLABS");
+        assert!(self.isBPool(pool.clone()), "ERR_NOT_BPOOL");
+        ext_bpool::setProtocolFee(protocolFee, &pool, NO_DEPOSIT, gas::POOL_SET_PROTOCOL_FEE)
+    }
+
+    /// Stops swaps and joins of `pool`, as long as the factory is its guardian.
+    pub fn pause(&mut self, pool: AccountId) -> Promise {
+        assert_eq!(env::predecessor_account_id(), self.blabs, "ERR_NOT_BLABS");
+        assert!(self.isBPool(pool.clone()), "ERR_NOT_BPOOL");
+        ext_bpool::pause(&pool, NO_DEPOSIT, gas::POOL_PAUSE)
+    }
+
+    pub fn unpause(&mut self, pool: AccountId) -> Promise {
+        assert_eq!(env::predecessor_account_id(), self.blabs, "ERR_NOT_BLABS");
+        assert!(self.isBPool(pool.clone()), "ERR_NOT_BPOOL");
+        ext_bpool::unpause(&pool, NO_DEPOSIT, gas::POOL_PAUSE)
+    }
+
+    /// Hands the factory's guardian role of `pool` to `guardian`.
+    pub fn setGuardian(&mut self, pool: AccountId, guardian: AccountId) -> Promise {
+        assert_eq!(env::predecessor_account_id(), self.blabs, "ERR_NOT_BLABS");
+        assert!(self.isBPool(pool.clone()), "ERR_NOT_BPOOL");
+        ext_bpool::setGuardian(guardian, &pool, NO_DEPOSIT, gas::POOL_SET_GUARDIAN)
+    }
+
+    /// Collects protocol fee shares accrued by `pool` and sends all shares of `pool` held by the
+    /// factory to blabs. Attached deposit registers blabs with `pool` if needed, the pool refunds
+    /// any excess to the factory.
+    #[payable]
+    pub fn collect(&mut self, pool: AccountId) -> Promise {
+        assert_eq!(env::predecessor_account_id(), self.blabs, "ERR_NOT_BLABS");
+        assert!(self.isBPool(pool.clone()), "ERR_NOT_BPOOL");
+        ext_bpool::collectProtocolFee(&pool, NO_DEPOSIT, gas::POOL_COLLECT_PROTOCOL_FEE)
+            .then(ext_bpool::storage_deposit(
+                Some(self.blabs.clone()),
+                Some(true),
+                &pool,
+                env::attached_deposit(),
+                gas::POOL_STORAGE_DEPOSIT,
+            ))
+            .then(ext_nep21::get_balance(
+                env::current_account_id(),
+                &pool,
+                NO_DEPOSIT,
+                gas::NEP21_GET_BALANCE,
+            ))
+            .then(ext_self::on_collect(
+                pool,
+                &env::current_account_id(),
+                NO_DEPOSIT,
+                gas::ON_COLLECT_CALLBACK,
+            ))
+    }
+
+    /// Callback after the pool account was created. Registers the pool on success,
+    /// otherwise returns the deposit to the caller.
+    pub fn on_new_pool(&mut self, pool: AccountId, controller: AccountId, deposit: U128) -> bool {
+        assert_eq!(
+            env::predecessor_account_id(),
+            env::current_account_id(),
+            "ERR_NOT_SELF"
+        );
+        if !is_promise_success() {
+            if deposit.0 > 0 {
+                Promise::new(controller).transfer(deposit.into());
+            }
+            return false;
+        }
+        env::log(format!("LOG_NEW_POOL: {} {}", controller, pool).as_bytes());
+        self.is_bpool.insert(&pool);
+        self.pools.push(&pool);
+        true
+    }
+
+    /// Callback with the factory's balance of `pool` shares, that are transferred to blabs.
+    pub fn on_collect(&mut self, pool: AccountId) -> U128 {
+        assert_eq!(
+            env::predecessor_account_id(),
+            env::current_account_id(),
+            "ERR_NOT_SELF"
+        );
+        assert_eq!(env::promise_results_count(), 1, "ERR_PROMISE_RESULTS");
+        let balance: U128 = match env::promise_result(0) {
+            PromiseResult::Successful(value) => {
+                serde_json::from_slice(&value).expect("ERR_WRONG_BALANCE")
+            }
+            _ => panic!("ERR_GET_BALANCE_FAILED"),
+        };
+        if balance.0 > 0 {
+            ext_nep21::transfer(
+                self.blabs.clone(),
+                balance,
+                &pool,
+                NO_DEPOSIT,
+                gas::NEP21_TRANSFER,
+            );
+        }
+        balance
+    }
+}
+
+#[ext_contract(ext_bpool)]
+pub trait ExtBPool {
+    fn setProtocolFee(&mut self, protocolFee: U128);
+
+    fn collectProtocolFee(&mut self) -> U128;
+
+    fn pause(&mut self);
+
+    fn unpause(&mut self);
+
+    fn setGuardian(&mut self, guardian: AccountId);
+
+    fn storage_deposit(&mut self, account_id: Option<AccountId>, registration_only: Option<bool>);
+}
+
+#[ext_contract(ext_self)]
+pub trait ExtSelf {
+    fn on_new_pool(&mut self, pool: AccountId, controller: AccountId, deposit: U128) -> bool;
+
+    fn on_collect(&mut self, pool: AccountId) -> U128;
+}
+
+fn is_promise_success() -> bool {
+    assert_eq!(env::promise_results_count(), 1, "ERR_PROMISE_RESULTS");
+    match env::promise_result(0) {
+        PromiseResult::Successful(_) => true,
+        _ => false,
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::{testing_env, MockedBlockchain, VMContext};
+
+    fn factory_account() -> AccountId {
+        "factory".to_string()
+    }
+    fn blabs_account() -> AccountId {
+        "blabs".to_string()
+    }
+    fn alice_account() -> AccountId {
+        "alice".to_string()
+    }
+
+    fn get_context(predecessor_account_id: AccountId, attached_deposit: Balance) -> VMContext {
+        VMContext {
+            current_account_id: factory_account(),
+            signer_account_id: predecessor_account_id.clone(),
+            signer_account_pk: vec![0, 1, 2],
+            predecessor_account_id,
+            input: vec![],
+            block_index: 1,
+            block_timestamp: 0,
+            epoch_height: 1,
+            account_balance: 10u128.pow(26),
+            account_locked_balance: 0,
+            storage_usage: 10u64.pow(6),
+            attached_deposit,
+            prepaid_gas: 10u64.pow(15),
+            random_seed: vec![0, 1, 2],
+            is_view: false,
+            output_data_receivers: vec![],
+        }
+    }
+
+    /// Switches to the factory calling itself back with given outcome of the pool creation.
+    fn testing_env_with_promise_result(success: bool) {
+        let storage = env::take_blockchain_interface()
+            .unwrap()
+            .as_mut_mocked_blockchain()
+            .unwrap()
+            .take_storage();
+        let promise_result = if success {
+            PromiseResult::Successful(vec![])
+        } else {
+            PromiseResult::Failed
+        };
+        env::set_blockchain_interface(Box::new(MockedBlockchain::new(
+            get_context(factory_account(), 0),
+            Default::default(),
+            Default::default(),
+            vec![promise_result],
+            storage,
+            Default::default(),
+        )));
+    }
+
+    #[test]
+    fn test_new_pools() {
+        testing_env!(get_context(blabs_account(), 0));
+        let mut factory = BFactory::new();
+        assert_eq!(factory.getBLabs(), blabs_account());
+        for name in &["pool1", "pool2", "pool3"] {
+            testing_env!(get_context(alice_account(), 10u128.pow(25)));
+            factory.newBPool(name.to_string(), None, None);
+            testing_env_with_promise_result(true);
+            assert!(factory.on_new_pool(
+                format!("{}.factory", name),
+                alice_account(),
+                10u128.pow(25).into()
+            ));
+        }
+        assert!(factory.isBPool("pool2.factory".to_string()));
+        assert_eq!(factory.getNumPools(), 3);
+        assert_eq!(
+            factory.getPools(1, 5),
+            vec!["pool2.factory".to_string(), "pool3.factory".to_string()]
+        );
+        assert!(factory.getPools(3, 5).is_empty());
+    }
+
+    #[test]
+    fn test_new_pool_share_metadata() {
+        testing_env!(get_context(blabs_account(), 0));
+        let mut factory = BFactory::new();
+        testing_env!(get_context(alice_account(), 10u128.pow(25)));
+        factory.newBPool(
+            "pool".to_string(),
+            Some("My Pool".to_string()),
+            Some("MP".to_string()),
+        );
+        let mut blockchain = env::take_blockchain_interface().unwrap();
+        // Deposits don't fit into JSON values as numbers.
+        let receipts = serde_json::to_string(
+            blockchain
+                .as_mut_mocked_blockchain()
+                .unwrap()
+                .created_receipts(),
+        )
+        .unwrap();
+        let receipts: serde_json::Value = serde_json::from_str(&receipts).unwrap();
+        let new_call = receipts[0]["actions"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|action| action["FunctionCall"]["method_name"] == "new")
+            .unwrap();
+        let args: serde_json::Value =
+            serde_json::from_str(new_call["FunctionCall"]["args"].as_str().unwrap()).unwrap();
+        assert_eq!(args, json!({ "name": "My Pool", "symbol": "MP" }));
+    }
+
+    #[test]
+    fn test_new_pool_failed() {
+        testing_env!(get_context(blabs_account(), 0));
+        let mut factory = BFactory::new();
+        testing_env!(get_context(alice_account(), 10u128.pow(25)));
+        factory.newBPool("pool".to_string(), None, None);
+        testing_env_with_promise_result(false);
+        assert!(!factory.on_new_pool(
+            "pool.factory".to_string(),
+            alice_account(),
+            10u128.pow(25).into()
+        ));
+        assert!(!factory.isBPool("pool.factory".to_string()));
+        assert_eq!(factory.getNumPools(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_BLABS")]
+    fn test_set_blabs() {
+        testing_env!(get_context(blabs_account(), 0));
+        let mut factory = BFactory::new();
+        factory.setBLabs(alice_account());
+        assert_eq!(factory.getBLabs(), alice_account());
+        factory.setBLabs(blabs_account());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_BLABS")]
+    fn test_pause_not_blabs() {
+        testing_env!(get_context(blabs_account(), 0));
+        let mut factory = BFactory::new();
+        testing_env!(get_context(alice_account(), 10u128.pow(25)));
+        factory.newBPool("pool".to_string(), None, None);
+        testing_env_with_promise_result(true);
+        factory.on_new_pool("pool.factory".to_string(), alice_account(), 0.into());
+        testing_env!(get_context(blabs_account(), 0));
+        factory.pause("pool.factory".to_string());
+        testing_env!(get_context(alice_account(), 0));
+        factory.pause("pool.factory".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_BPOOL")]
+    fn test_set_protocol_fee_not_bpool() {
+        testing_env!(get_context(blabs_account(), 0));
+        let mut factory = BFactory::new();
+        factory.setProtocolFee("pool.factory".to_string(), 0.into());
+    }
+}
@@ -1,15 +1,23 @@
-use near_sdk::Balance;
+use near_sdk::{Balance, BlockHeight};
 
-pub type Weight = u128;
+pub use balancer_common::bconst::*;
 
-pub const BONE: Balance = 1_000_000_000_000_000_000_000_000;
+pub const POOL_NAME: &str = "Balancer Pool Token";
 
 pub const MIN_BOUND_TOKENS: usize = 2;
 pub const MAX_BOUND_TOKENS: usize = 8;
 
 pub const MIN_FEE: Balance = BONE / 1_000_000;
 pub const MAX_FEE: Balance = BONE / 10;
-pub const EXIT_FEE: Balance = 0;
+/// Part of the average price change kept each block by the dynamic fee, see
+/// `BPool::setDynamicFee`.
+pub const VOLATILITY_DECAY: Balance = BONE / 1_000 * 999;
+/// Max fraction of swap fees that goes to the protocol, see `BPool::setProtocolFee`.
+pub const MAX_PROTOCOL_FEE: Balance = BONE / 2;
+
+/// Bounds of the amplification coefficient of stable-swap pools.
+pub const MIN_AMP: u64 = 1;
+pub const MAX_AMP: u64 = 1_000_000;
 
 pub const MIN_WEIGHT: Weight = BONE;
 pub const MAX_WEIGHT: Weight = BONE * 50;
@@ -17,23 +25,39 @@ pub const MAX_TOTAL_WEIGHT: Weight = BONE * 50;
 pub const MIN_BALANCE: Balance = BONE / 1_000_000_000_000;
 
 pub const INIT_POOL_SUPPLY: Balance = BONE * 100;
-pub const MIN_BPOW_BASE: Balance = 1;
-pub const MAX_BPOW_BASE: Balance = 2 * BONE - 1;
-pub const BPOW_PRECISION: Balance = BONE / 10_000_000_000;
+pub const NO_DEPOSIT: Balance = 0;
+/// Deposit required by NEP-141 `ft_transfer`.
+pub const ONE_YOCTO: Balance = 1;
 
-pub const MAX_IN_RATIO: Balance = BONE / 2;
-pub const MAX_OUT_RATIO: Balance = BONE / 3 + 1;
+/// Blocks after which the lock of an unresolved operation can be released, see
+/// `BPool::forceUnlock`.
+pub const LOCK_TIMEOUT: BlockHeight = 1_000;
 
-pub const NO_DEPOSIT: Balance = 0;
+/// Min time between kept price observations, in seconds.
+pub const OBSERVATION_PERIOD: u64 = 300;
+/// Number of kept price observations, that bounds the oracle window to a day. With one sum per
+/// token these take up to 40 KB of storage.
+pub const MAX_OBSERVATIONS: u64 = 288;
 
 pub mod gas {
     pub const BASE_GAS: u64 = 20_000_000_000_000;
 
     pub const NEP21_TRANSFER: u64 = BASE_GAS;
 
-    pub const ON_PULL_CALLBACK: u64 = BASE_GAS;
+    /// Settles the op, including the oracle update, and sends its pushes, which gas is added.
+    pub const ON_PULL_CALLBACK: u64 = 2 * BASE_GAS;
 
     pub const NEP21_TRANSFER_FROM: u64 = BASE_GAS;
 
     pub const ON_PUSH_CALLBACK: u64 = BASE_GAS;
+
+    pub const ON_UNBIND_CALLBACK: u64 = BASE_GAS;
+
+    pub const NEP21_GET_BALANCE: u64 = BASE_GAS;
+
+    pub const ON_GULP_CALLBACK: u64 = BASE_GAS;
+
+    pub const ON_WITHDRAW_CALLBACK: u64 = BASE_GAS;
+
+    pub const ON_CLAIM_CALLBACK: u64 = BASE_GAS;
 }
@@ -0,0 +1,53 @@
+//! Swap fee that follows recent price movement, see `BPool::setDynamicFee`.
+//! Relative spot price change of every swap is added to an average that decays each block,
+//! and the average is charged on top of the base swap fee.
+
+use borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::{env, Balance, BlockHeight};
+
+use crate::bconst::{BONE, MAX_FEE, VOLATILITY_DECAY};
+use crate::bmath::{badd, bmul, bpowi, bsub};
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct DynamicFee {
+    /// Average relative spot price change per block as of `block`.
+    volatility: Balance,
+    block: BlockHeight,
+}
+
+impl DynamicFee {
+    pub fn new() -> Self {
+        Self {
+            volatility: 0,
+            block: env::block_index(),
+        }
+    }
+
+    /// Average price change decayed to the current block.
+    pub fn volatility(&self) -> Balance {
+        let elapsed = env::block_index().saturating_sub(self.block);
+        bmul(self.volatility, bpowi(VOLATILITY_DECAY, elapsed as Balance))
+    }
+
+    /// Effective swap fee for `base_fee` set by the controller, which is at least `MIN_FEE`.
+    pub fn swap_fee(&self, base_fee: Balance) -> Balance {
+        std::cmp::min(badd(base_fee, self.volatility()), MAX_FEE)
+    }
+
+    /// Adds relative spot price `change` of a swap in the current block.
+    pub fn update(&mut self, change: Balance) {
+        self.volatility = badd(
+            self.volatility(),
+            bmul(bsub(BONE, VOLATILITY_DECAY), change),
+        );
+        self.block = env::block_index();
+    }
+
+    /// Removes the price `change` of the last update, for a swap that was reverted before any
+    /// other update.
+    pub fn revert(&mut self, change: Balance) {
+        self.volatility = self
+            .volatility
+            .saturating_sub(bmul(bsub(BONE, VOLATILITY_DECAY), change));
+    }
+}
@@ -1,14 +1,35 @@
+use std::collections::BTreeMap;
+
 use borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::UnorderedMap;
+use near_sdk::collections::{UnorderedMap, UnorderedSet};
 use near_sdk::json_types::U128;
-use near_sdk::{env, ext_contract, near_bindgen, AccountId, Balance, Promise};
+use near_sdk::{
+    env, ext_contract, near_bindgen, AccountId, Balance, BlockHeight, Promise, PromiseOrValue,
+    PromiseResult,
+};
+use serde::Deserialize;
 
 mod bconst;
-mod bmath;
+mod dynamic_fee;
+mod oracle;
+mod stable_math;
 
+use balancer_common::bmath;
+pub use balancer_common::events;
 use bconst::*;
-use bmath::calc_spot_price;
-use near_lib::token::{ext_nep21, FungibleToken, Token};
+use bmath::{
+    badd, bdiv, bmul, bsub, calc_in_given_out, calc_out_given_in, calc_pool_in_given_single_out,
+    calc_pool_out_given_single_in, calc_single_in_given_pool_out, calc_single_out_given_pool_in,
+    calc_spot_price, U256,
+};
+use dynamic_fee::DynamicFee;
+use events::Event;
+use near_lib::token::{
+    ext_nep141, ext_nep21, FungibleToken, FungibleTokenCore, FungibleTokenMetadata,
+    FungibleTokenMetadataProvider, FungibleTokenReceiver, FungibleTokenResolver, LegacyToken,
+    StorageBalance, StorageBalanceBounds, StorageManagement, Token,
+};
+use oracle::Oracle;
 
 #[derive(BorshDeserialize, BorshSerialize)]
 pub struct Record {
@@ -18,6 +39,75 @@ pub struct Record {
     balance: Balance,
 }
 
+/// Kind of a pending operation, that determines its events and checks.
+#[derive(BorshDeserialize, BorshSerialize, Clone, Copy, PartialEq)]
+pub enum OpKind {
+    Swap,
+    Join,
+    Exit,
+    /// Balance change of a token by the controller, see `rebind`.
+    Bind,
+}
+
+impl Default for OpKind {
+    fn default() -> Self {
+        OpKind::Bind
+    }
+}
+
+/// Operation of an account that waits for its cross-contract token transfers to resolve.
+/// Tokens are pulled first, then shares are minted and tokens are pushed out. Nothing is
+/// committed to the pool records until the corresponding transfers succeeded.
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+pub struct PendingOp {
+    kind: OpKind,
+    /// Tokens pulled from the account.
+    amounts_in: Vec<(AccountId, Balance)>,
+    /// Tokens pushed to the account once all pulls succeeded.
+    amounts_out: Vec<(AccountId, Balance)>,
+    /// Pool shares minted to the account once all pulls succeeded.
+    pool_amount_out: Balance,
+    /// Pool shares held in escrow from the account and burned once all pushes succeeded.
+    pool_amount_in: Balance,
+    /// Pool shares minted as the protocol fee of the op, burned if the op is reverted.
+    protocol_fee: Balance,
+    /// Relative spot price change of a swap added to the dynamic fee, removed if the swap is
+    /// reverted.
+    price_change: Balance,
+    /// Weight of the token of a `Bind` op, set once its transfer succeeded.
+    denorm: Weight,
+    /// Swap fee charged by the op and the token it's charged in, computed when the op starts.
+    swap_fee: Option<(AccountId, Balance)>,
+    /// Whether the pulled tokens were credited and the op waits for its pushes.
+    settled: bool,
+}
+
+/// Linear change of weights from `start_block` to `end_block`, applied by `pokeWeights`.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct GradualUpdate {
+    start_block: BlockHeight,
+    end_block: BlockHeight,
+    /// Weight of each token at the start and at the end.
+    weights: Vec<(AccountId, Weight, Weight)>,
+}
+
+/// Action passed as `msg` of `ft_transfer_call` to the pool, executed with the received tokens.
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferAction {
+    /// Swaps all received tokens to `token_out`, same as `swapExactAmountIn`.
+    Swap {
+        token_out: AccountId,
+        min_amount_out: U128,
+        max_price: U128,
+    },
+    /// Joins the pool with all received tokens, same as `joinswapExternAmountIn`.
+    JoinSingle { min_pool_amount_out: U128 },
+    /// Keeps received tokens as the sender's deposit, that its swaps and joins use instead of
+    /// transfers, see `withdraw`. The sender has to be registered with `storage_deposit`.
+    Deposit,
+}
+
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize)]
 pub struct BPool {
@@ -30,6 +120,69 @@ pub struct BPool {
     tokens: Vec<AccountId>,
     total_weight: Weight,
     token: Token,
+    pending: UnorderedMap<AccountId, PendingOp>,
+    /// Taken by mutating methods and held until their token transfers resolve.
+    mutex: bool,
+    /// Block when the mutex was last taken, see `forceUnlock`.
+    lock_block: BlockHeight,
+    /// Number of times the mutex was taken, that tells callbacks whether the pool changed.
+    lock_nonce: u64,
+    /// Tokens deposited by accounts, that are not part of the pool's balances.
+    deposits: UnorderedMap<AccountId, BTreeMap<AccountId, Balance>>,
+    /// Total deposited amount of each token.
+    total_deposits: UnorderedMap<AccountId, Balance>,
+    /// Tokens owed to accounts by failed operations, sent to them by `claim`.
+    claims: UnorderedMap<AccountId, BTreeMap<AccountId, Balance>>,
+    /// Total claimable amount of each token.
+    total_claims: UnorderedMap<AccountId, Balance>,
+    /// Accounts that keep tokens sent out by their operations as deposits.
+    deposit_outputs: UnorderedSet<AccountId>,
+    /// Bound tokens that called `ft_on_transfer`, which are sent with NEP-141 `ft_transfer`
+    /// instead of NEP-21 `transfer`.
+    nep141_tokens: UnorderedSet<AccountId>,
+    /// Name and symbol of pool shares, derived from bound tokens if not set.
+    name: Option<String>,
+    symbol: Option<String>,
+    /// Weights shifting over blocks, see `updateWeightsGradually`.
+    gradual_update: Option<GradualUpdate>,
+    /// Accounts allowed to join if `whitelist_enabled`.
+    whitelist: UnorderedSet<AccountId>,
+    whitelist_enabled: bool,
+    /// Max total supply of pool shares.
+    cap: Balance,
+    /// Accumulated spot prices, see `consult`.
+    oracle: Oracle,
+    /// Fraction of swap fees minted as pool shares for the factory.
+    protocol_fee: Balance,
+    /// Protocol fee shares held by the pool until collected by the factory.
+    accrued_protocol_fee: Balance,
+    /// Protocol fee shares sent to the factory so far.
+    collected_protocol_fee: Balance,
+    /// Raises `swap_fee` with recent price movement if set, see `setDynamicFee`.
+    dynamic_fee: Option<DynamicFee>,
+    /// Amplification coefficient of the stable-swap invariant, that replaces weighted math if
+    /// set, see `setAmplification`.
+    amp: Option<u64>,
+    /// Decimals of tokens that differ from `POOL_DECIMALS`, which stable-swap math scales
+    /// balances by, see `setDecimals`.
+    decimals: UnorderedMap<AccountId, u8>,
+    /// Account that can pause swaps and joins in an emergency, set by the controller.
+    guardian: AccountId,
+    paused: bool,
+}
+
+/// Pool state before NEP-145 storage management, read by `BPool::migrate`.
+#[derive(BorshDeserialize, BorshSerialize)]
+struct LegacyBPool {
+    controller: AccountId,
+    factory: AccountId,
+    swap_fee: Balance,
+    finalized: bool,
+    public_swap: bool,
+    records: UnorderedMap<AccountId, Record>,
+    tokens: Vec<AccountId>,
+    total_weight: Weight,
+    token: LegacyToken,
 }
 
 impl Default for BPool {
@@ -41,18 +194,35 @@ impl Default for BPool {
 #[near_bindgen]
 impl BPool {
     #[init]
-    pub fn new() -> Self {
-        Self {
-            controller: env::predecessor_account_id(),
-            factory: env::predecessor_account_id(),
-            swap_fee: MIN_FEE,
-            public_swap: false,
-            finalized: false,
-            records: UnorderedMap::new(b"r".to_vec()),
-            tokens: Vec::new(),
-            total_weight: 0,
-            token: Token::new(env::signer_account_id(), 0u128),
-        }
+    pub fn new(name: Option<String>, symbol: Option<String>) -> Self {
+        let mut token = Token::new(env::signer_account_id(), 0u128);
+        // The factory receives protocol fee shares and the creator the initial supply.
+        token.register_account(&env::predecessor_account_id());
+        let pool = Self::with_state(token, UnorderedMap::new(b"r".to_vec()), name, symbol);
+        pool.ft_metadata().assert_valid();
+        pool
+    }
+
+    /// Upgrades the state of a pool deployed before NEP-145 storage management, registering
+    /// its share holders. Called by the pool itself after deploying the new code.
+    #[init]
+    pub fn migrate() -> Self {
+        assert_eq!(
+            env::predecessor_account_id(),
+            env::current_account_id(),
+            "ERR_NOT_SELF"
+        );
+        let old: LegacyBPool = env::state_read().expect("ERR_NO_STATE");
+        let mut pool = Self::with_state(Token::from_legacy(old.token), old.records, None, None);
+        pool.controller = old.controller;
+        pool.factory = old.factory.clone();
+        pool.guardian = old.factory;
+        pool.swap_fee = old.swap_fee;
+        pool.finalized = old.finalized;
+        pool.public_swap = old.public_swap;
+        pool.tokens = old.tokens;
+        pool.total_weight = old.total_weight;
+        pool
     }
 
     // Getters
@@ -77,46 +247,114 @@ impl BPool {
     }
 
     pub fn getCurrentTokens(&self) -> Vec<AccountId> {
+        self.assert_unlocked();
         self.tokens.clone()
     }
 
     pub fn getFinalTokens(&self) -> Vec<AccountId> {
+        self.assert_unlocked();
         assert!(self.finalized, "ERR_NOT_FINALIZED");
         self.tokens.clone()
     }
 
     pub fn getDenormalizedWeight(&self, token: AccountId) -> U128 {
+        self.assert_unlocked();
         assert!(self.isBound(token.clone()), "ERR_NOT_BOUND");
         self.records.get(&token).unwrap().denorm.into()
     }
 
     pub fn getTotalDenormalizedWeight(&self) -> U128 {
+        self.assert_unlocked();
         self.total_weight.into()
     }
 
     pub fn getNormalizedWeight(&self, token: AccountId) -> U128 {
+        self.assert_unlocked();
         assert!(self.isBound(token.clone()), "ERR_NOT_BOUND");
         let denorm = self.records.get(&token).unwrap().denorm;
-        // TODO: this division is special?
-        (denorm / self.total_weight).into()
+        bdiv(denorm, self.total_weight).into()
     }
 
     pub fn getBalance(&self, token: AccountId) -> U128 {
+        self.assert_unlocked();
         assert!(self.isBound(token.clone()), "ERR_NOT_BOUND");
         self.records.get(&token).unwrap().balance.into()
     }
 
+    /// Base swap fee set by the controller.
     pub fn getSwapFee(&self) -> U128 {
         self.swap_fee.into()
     }
 
+    /// Swap fee charged now, including the dynamic part if enabled.
+    pub fn getEffectiveSwapFee(&self) -> U128 {
+        self.assert_unlocked();
+        self.swap_fee().into()
+    }
+
+    pub fn isDynamicFee(&self) -> bool {
+        self.dynamic_fee.is_some()
+    }
+
+    pub fn getAmplification(&self) -> Option<u64> {
+        self.amp
+    }
+
+    pub fn getDecimals(&self, token: AccountId) -> u8 {
+        assert!(self.isBound(token.clone()), "ERR_NOT_BOUND");
+        self.decimals.get(&token).unwrap_or(POOL_DECIMALS)
+    }
+
     pub fn getController(&self) -> AccountId {
+        self.assert_unlocked();
         self.controller.clone()
     }
 
+    pub fn getGuardian(&self) -> AccountId {
+        self.guardian.clone()
+    }
+
+    pub fn isPaused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn getCap(&self) -> U128 {
+        self.cap.into()
+    }
+
+    pub fn getProtocolFee(&self) -> U128 {
+        self.protocol_fee.into()
+    }
+
+    pub fn getAccruedProtocolFee(&self) -> U128 {
+        self.accrued_protocol_fee.into()
+    }
+
+    pub fn getCollectedProtocolFee(&self) -> U128 {
+        self.collected_protocol_fee.into()
+    }
+
+    pub fn isWhitelistEnabled(&self) -> bool {
+        self.whitelist_enabled
+    }
+
+    /// Whether `account_id` can join, always true unless the whitelist is enabled.
+    pub fn canProvideLiquidity(&self, account_id: AccountId) -> bool {
+        !self.whitelist_enabled || self.whitelist.contains(&account_id)
+    }
+
+    /// Returns up to `limit` whitelisted accounts starting from `from_index`.
+    pub fn getWhitelist(&self, from_index: u64, limit: u64) -> Vec<AccountId> {
+        let accounts = self.whitelist.as_vector();
+        (from_index..std::cmp::min(from_index + limit, accounts.len()))
+            .map(|index| accounts.get(index).unwrap())
+            .collect()
+    }
+
     // Setters.
 
     pub fn setSwapFee(&mut self, swapFee: U128) {
+        self.lock();
         let swap_fee = swapFee.into();
         assert!(!self.finalized, "ERR_IS_FINALIZED");
         assert_eq!(
@@ -127,18 +365,175 @@ impl BPool {
         assert!(swap_fee >= MIN_FEE, "ERR_MIN_FEE");
         assert!(swap_fee <= MAX_FEE, "ERR_MIN_FEE");
         self.swap_fee = swap_fee;
+        Event::FeeChange {
+            caller: env::predecessor_account_id(),
+            swap_fee: swapFee,
+        }
+        .emit();
+        self.unlock();
+    }
+
+    /// Adds the average relative spot price change per block of recent swaps to the swap fee,
+    /// clamped between `MIN_FEE` and `MAX_FEE`. The average decays by `VOLATILITY_DECAY` each
+    /// block.
+    pub fn setDynamicFee(&mut self, enabled: bool) {
+        self.lock();
+        assert!(!self.finalized, "ERR_IS_FINALIZED");
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.controller,
+            "ERR_NOT_CONTROLLER"
+        );
+        self.dynamic_fee = if enabled {
+            Some(DynamicFee::new())
+        } else {
+            None
+        };
+        self.unlock();
+    }
+
+    /// Turns the pool into a stable-swap pool for pegged tokens with amplification `amp`, or back
+    /// into a weighted pool if not set. Weights are ignored by stable-swap math and can't be
+    /// changed while it's set.
+    pub fn setAmplification(&mut self, amp: Option<u64>) {
+        self.lock();
+        assert!(!self.finalized, "ERR_IS_FINALIZED");
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.controller,
+            "ERR_NOT_CONTROLLER"
+        );
+        assert!(
+            self.gradual_update.is_none(),
+            "ERR_GRADUAL_UPDATE_IN_PROGRESS"
+        );
+        if let Some(amp) = amp {
+            assert!(amp >= MIN_AMP, "ERR_MIN_AMP");
+            assert!(amp <= MAX_AMP, "ERR_MAX_AMP");
+        }
+        self.update_oracle();
+        self.amp = amp;
+        self.unlock();
+    }
+
+    /// Sets `decimals` of bound `token`, by which stable-swap math scales its balance to compare
+    /// it with other pegged tokens. Tokens have `POOL_DECIMALS` by default.
+    pub fn setDecimals(&mut self, token: AccountId, decimals: u8) {
+        self.lock();
+        assert!(!self.finalized, "ERR_IS_FINALIZED");
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.controller,
+            "ERR_NOT_CONTROLLER"
+        );
+        assert!(self.isBound(token.clone()), "ERR_NOT_BOUND");
+        assert!(decimals <= POOL_DECIMALS, "ERR_MAX_DECIMALS");
+        self.update_oracle();
+        if decimals == POOL_DECIMALS {
+            self.decimals.remove(&token);
+        } else {
+            self.decimals.insert(&token, &decimals);
+        }
+        self.unlock();
     }
 
     pub fn setController(&mut self, controller: AccountId) {
+        self.lock();
         assert_eq!(
             env::predecessor_account_id(),
             self.controller,
             "ERR_NOT_CONTROLLER"
         );
+        Event::ControllerChange {
+            caller: env::predecessor_account_id(),
+            controller: controller.clone(),
+        }
+        .emit();
         self.controller = controller;
+        self.unlock();
+    }
+
+    /// Hands the guardian role to `guardian`. Only called by the current guardian, e.g. the
+    /// factory of pools created by it, also after finalization.
+    pub fn setGuardian(&mut self, guardian: AccountId) {
+        self.lock();
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.guardian,
+            "ERR_NOT_GUARDIAN"
+        );
+        Event::GuardianChange {
+            caller: env::predecessor_account_id(),
+            guardian: guardian.clone(),
+        }
+        .emit();
+        self.guardian = guardian;
+        self.unlock();
+    }
+
+    /// Stops all swaps and joins, also of finalized pools. Exits stay open.
+    /// Doesn't wait for pending operations, which complete as usual.
+    pub fn pause(&mut self) {
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.guardian,
+            "ERR_NOT_GUARDIAN"
+        );
+        self.paused = true;
+        Event::Pause {
+            caller: env::predecessor_account_id(),
+        }
+        .emit();
+    }
+
+    pub fn unpause(&mut self) {
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.guardian,
+            "ERR_NOT_GUARDIAN"
+        );
+        self.paused = false;
+        Event::Unpause {
+            caller: env::predecessor_account_id(),
+        }
+        .emit();
+    }
+
+    /// Releases the lock of operations that didn't resolve within `LOCK_TIMEOUT` blocks, e.g.
+    /// because their callback ran out of gas. Called by the guardian. Pending operations are
+    /// dropped: escrowed shares of settled ones are burned as if their pushes succeeded, tokens
+    /// pulled by the others become claimable by their accounts and escrowed shares are returned.
+    pub fn forceUnlock(&mut self) {
+        let caller = env::predecessor_account_id();
+        assert_eq!(caller, self.guardian, "ERR_NOT_GUARDIAN");
+        assert!(self.mutex, "ERR_NOT_LOCKED");
+        assert!(
+            env::block_index() >= self.lock_block + LOCK_TIMEOUT,
+            "ERR_LOCK_NOT_EXPIRED"
+        );
+        let ops: Vec<(AccountId, PendingOp)> = self.pending.iter().collect();
+        for (account_id, op) in ops {
+            if op.settled {
+                self.burn_pool_share(op.pool_amount_in);
+                continue;
+            }
+            for (token, amount) in op.amounts_in.iter() {
+                self.add_claim(&account_id, token, *amount);
+                if op.kind == OpKind::Bind && self.records.get(token).unwrap().denorm == 0 {
+                    self.remove_token(token);
+                }
+            }
+            if op.pool_amount_in > 0 {
+                self.push_pool_share(account_id, op.pool_amount_in);
+            }
+        }
+        self.pending.clear();
+        Event::ForceUnlock { caller }.emit();
+        self.unlock();
     }
 
     pub fn setPublicSwap(&mut self, public: bool) {
+        self.lock();
         assert!(!self.finalized, "ERR_IS_FINALIZED");
         assert_eq!(
             env::predecessor_account_id(),
@@ -146,9 +541,94 @@ impl BPool {
             "ERR_NOT_CONTROLLER"
         );
         self.public_swap = public;
+        self.unlock();
+    }
+
+    /// Limits the total supply of pool shares, which joins can't exceed.
+    pub fn setCap(&mut self, cap: U128) {
+        self.lock();
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.controller,
+            "ERR_NOT_CONTROLLER"
+        );
+        self.cap = cap.into();
+        self.unlock();
+    }
+
+    /// Sets the fraction of swap fees, that is minted as pool shares for the factory. Swap fees
+    /// of single-token joins and exits are included.
+    pub fn setProtocolFee(&mut self, protocolFee: U128) {
+        self.lock();
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.factory,
+            "ERR_NOT_FACTORY"
+        );
+        assert!(protocolFee.0 <= MAX_PROTOCOL_FEE, "ERR_MAX_PROTOCOL_FEE");
+        self.protocol_fee = protocolFee.into();
+        Event::ProtocolFeeChange {
+            caller: env::predecessor_account_id(),
+            protocol_fee: protocolFee,
+        }
+        .emit();
+        self.unlock();
+    }
+
+    /// Sends accrued protocol fee shares to the factory, returns their amount.
+    pub fn collectProtocolFee(&mut self) -> U128 {
+        self.lock();
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.factory,
+            "ERR_NOT_FACTORY"
+        );
+        let amount = self.accrued_protocol_fee;
+        if amount > 0 {
+            self.push_pool_share(self.factory.clone(), amount);
+            self.accrued_protocol_fee = 0;
+            self.collected_protocol_fee = badd(self.collected_protocol_fee, amount);
+        }
+        self.unlock();
+        amount.into()
+    }
+
+    /// Restricts joins to whitelisted accounts. Exits are always open.
+    pub fn setWhitelistEnabled(&mut self, enabled: bool) {
+        self.lock();
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.controller,
+            "ERR_NOT_CONTROLLER"
+        );
+        self.whitelist_enabled = enabled;
+        self.unlock();
+    }
+
+    pub fn addToWhitelist(&mut self, account_id: AccountId) {
+        self.lock();
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.controller,
+            "ERR_NOT_CONTROLLER"
+        );
+        self.whitelist.insert(&account_id);
+        self.unlock();
+    }
+
+    pub fn removeFromWhitelist(&mut self, account_id: AccountId) {
+        self.lock();
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.controller,
+            "ERR_NOT_CONTROLLER"
+        );
+        assert!(self.whitelist.remove(&account_id), "ERR_NOT_WHITELISTED");
+        self.unlock();
     }
 
     pub fn finalize(&mut self) {
+        self.lock();
         assert_eq!(
             env::predecessor_account_id(),
             self.controller,
@@ -156,12 +636,17 @@ impl BPool {
         );
         assert!(!self.finalized, "ERR_IS_FINALIZED");
         assert!(self.tokens.len() >= MIN_BOUND_TOKENS, "ERR_MIN_TOKENS");
+        assert!(
+            self.token.is_registered(&self.controller),
+            "ERR_NOT_REGISTERED"
+        );
 
         self.finalized = true;
         self.public_swap = true;
 
         self.mint_pool_share(INIT_POOL_SUPPLY);
         self.push_pool_share(env::predecessor_account_id(), INIT_POOL_SUPPLY);
+        self.unlock();
     }
 
     pub fn bind(&mut self, token: AccountId, balance: U128, denorm: U128) {
@@ -184,10 +669,86 @@ impl BPool {
             },
         );
         self.tokens.push(token.clone());
-        self.rebind(token, balance.into(), denorm.into());
+        self.lock();
+        self.internal_rebind(token, balance.into(), denorm.into());
+    }
+
+    /// Sets the balance and weight of bound `token`. Returns whether the tokens were moved, which
+    /// resolves once they are pushed to the controller if the balance decreased.
+    pub fn rebind(
+        &mut self,
+        token: AccountId,
+        balance: Balance,
+        denorm: Weight,
+    ) -> PromiseOrValue<bool> {
+        self.lock();
+        self.assert_can_rebind(&token);
+        self.internal_rebind(token, balance, denorm)
+    }
+
+    /// Adds `balanceIn` to and removes `balanceOut` from the balance of bound `token` and sets
+    /// its weight to `denorm` if given. Unlike `rebind`, it keeps the balance changes of swaps
+    /// since the controller read the balance.
+    pub fn rebindDelta(
+        &mut self,
+        token: AccountId,
+        balanceIn: U128,
+        balanceOut: U128,
+        denorm: Option<U128>,
+    ) -> PromiseOrValue<bool> {
+        self.lock();
+        self.assert_can_rebind(&token);
+        let record = self.records.get(&token).unwrap();
+        let balance = bsub(badd(record.balance, balanceIn.into()), balanceOut.into());
+        let denorm = denorm.map(|denorm| denorm.into()).unwrap_or(record.denorm);
+        self.internal_rebind(token, balance, denorm)
     }
 
-    pub fn rebind(&mut self, token: AccountId, balance: Balance, denorm: Weight) {
+    /// Moves `ratio` of every balance between the pool and the controller's deposits, keeping
+    /// the weights and prices, for a controller that issues its own shares. Adds the amounts,
+    /// at most `limitAmounts`, from the deposits if `join`, otherwise removes them, at least
+    /// `limitAmounts`, to the deposits. Limits follow the order of `getCurrentTokens`.
+    /// Returns the amount moved of each token.
+    pub fn rebindProportional(
+        &mut self,
+        ratio: U128,
+        join: bool,
+        limitAmounts: Vec<U128>,
+    ) -> Vec<(AccountId, U128)> {
+        self.lock();
+        let controller = env::predecessor_account_id();
+        assert_eq!(controller, self.controller, "ERR_NOT_CONTROLLER");
+        assert!(!self.finalized, "ERR_IS_FINALIZED");
+        assert_eq!(limitAmounts.len(), self.tokens.len(), "ERR_LIMITS_LENGTH");
+
+        self.update_oracle();
+        let mut amounts = vec![];
+        for (token, limit) in self.tokens.clone().into_iter().zip(limitAmounts) {
+            let mut record = self.records.get(&token).unwrap();
+            let amount = bmul(ratio.into(), record.balance);
+            assert_ne!(amount, 0, "ERR_MATH_APPROX");
+            if join {
+                assert!(amount <= limit.0, "ERR_LIMIT_IN");
+                self.withdraw_deposit(&controller, &token, amount);
+                record.balance = badd(record.balance, amount);
+            } else {
+                assert!(amount >= limit.0, "ERR_LIMIT_OUT");
+                record.balance = bsub(record.balance, amount);
+                assert!(record.balance >= MIN_BALANCE, "ERR_MIN_BALANCE");
+                self.deposit(&controller, &token, amount);
+            }
+            self.records.insert(&token, &record);
+            self.bind_event(controller.clone(), token.clone()).emit();
+            amounts.push((token, amount.into()));
+        }
+        self.unlock();
+        amounts
+    }
+
+    /// Returns the whole balance of `token` to the controller and removes the token once the
+    /// transfer succeeded.
+    pub fn unbind(&mut self, token: AccountId) -> Promise {
+        self.lock();
         assert_eq!(
             env::predecessor_account_id(),
             self.controller,
@@ -196,150 +757,718 @@ impl BPool {
         assert!(self.isBound(token.clone()), "ERR_NOT_BOUND");
         assert!(!self.finalized, "ERR_IS_FINALIZED");
 
-        assert!(denorm >= MIN_WEIGHT, "ERR_MIN_WEIGHT");
-        assert!(denorm <= MAX_WEIGHT, "ERR_MAX_WEIGHT");
-        assert!(balance >= MIN_BALANCE, "ERR_MIN_BALANCE");
-
+        self.update_oracle();
         let mut record = self.records.get(&token).unwrap();
-        let old_weight = record.denorm;
-        record.denorm = denorm;
-        if denorm > old_weight {
-            self.total_weight = self.total_weight + (denorm - old_weight);
+        let amount = record.balance;
+        // Balance is restored by the callback if the transfer fails.
+        record.balance = 0;
+        self.records.insert(&token, &record);
+        self.push_underlying(token.clone(), env::predecessor_account_id(), amount)
+            .then(ext_self::on_unbind(
+                token,
+                amount.into(),
+                &env::current_account_id(),
+                NO_DEPOSIT,
+                gas::ON_UNBIND_CALLBACK,
+            ))
+    }
+
+    /// Shifts weights of bound tokens to `newWeights` linearly from `startBlock` to `endBlock`,
+    /// as `pokeWeights` is called. Starts from the current block if `startBlock` has passed.
+    pub fn updateWeightsGradually(
+        &mut self,
+        newWeights: Vec<U128>,
+        startBlock: BlockHeight,
+        endBlock: BlockHeight,
+    ) {
+        self.lock();
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.controller,
+            "ERR_NOT_CONTROLLER"
+        );
+        assert!(!self.finalized, "ERR_IS_FINALIZED");
+        assert!(self.amp.is_none(), "ERR_IS_STABLE");
+        assert_eq!(newWeights.len(), self.tokens.len(), "ERR_WEIGHTS_MISMATCH");
+        let start_block = std::cmp::max(env::block_index(), startBlock);
+        assert!(endBlock > start_block, "ERR_GRADUAL_UPDATE_TIME_TRAVEL");
+
+        let mut total_weight = 0;
+        let mut weights = vec![];
+        for (token, new_weight) in self.tokens.iter().zip(newWeights.into_iter()) {
+            let new_weight = new_weight.into();
+            assert!(new_weight >= MIN_WEIGHT, "ERR_MIN_WEIGHT");
+            assert!(new_weight <= MAX_WEIGHT, "ERR_MAX_WEIGHT");
+            total_weight = badd(total_weight, new_weight);
+            weights.push((
+                token.clone(),
+                self.records.get(token).unwrap().denorm,
+                new_weight,
+            ));
+        }
+        assert!(total_weight <= MAX_TOTAL_WEIGHT, "ERR_MAX_TOTAL_WEIGHT");
+
+        self.gradual_update = Some(GradualUpdate {
+            start_block,
+            end_block: endBlock,
+            weights,
+        });
+        self.unlock();
+    }
+
+    /// Sets weights of the gradual update for the current block. Anyone can call it.
+    pub fn pokeWeights(&mut self) {
+        self.lock();
+        assert!(!self.finalized, "ERR_IS_FINALIZED");
+        assert!(self.amp.is_none(), "ERR_IS_STABLE");
+        let update = self
+            .gradual_update
+            .take()
+            .expect("ERR_NO_UPDATE_IN_PROGRESS");
+        let block = env::block_index();
+        assert!(block >= update.start_block, "ERR_CANT_POKE_YET");
+        self.update_oracle();
+
+        let progress = if block >= update.end_block {
+            BONE
         } else {
-            self.total_weight = self.total_weight - (old_weight - denorm);
+            bdiv(
+                (block - update.start_block).into(),
+                (update.end_block - update.start_block).into(),
+            )
+        };
+        for (token, start_weight, end_weight) in update.weights.iter() {
+            // Tokens unbound since the update started are skipped.
+            if let Some(mut record) = self.records.get(token) {
+                let weight = if end_weight >= start_weight {
+                    badd(*start_weight, bmul(end_weight - start_weight, progress))
+                } else {
+                    bsub(*start_weight, bmul(start_weight - end_weight, progress))
+                };
+                self.total_weight = badd(bsub(self.total_weight, record.denorm), weight);
+                record.denorm = weight;
+                self.records.insert(token, &record);
+            }
         }
+        assert!(
+            self.total_weight <= MAX_TOTAL_WEIGHT,
+            "ERR_MAX_TOTAL_WEIGHT"
+        );
+        if block < update.end_block {
+            self.gradual_update = Some(update);
+        }
+        self.unlock();
+    }
 
-        let old_balance = record.balance;
-        record.balance = balance;
-        if balance > old_balance {
-            self.pull_underlying(
+    /// Absorb any tokens that have been sent to this contract into the pool.
+    /// Doesn't take the lock, so the balance is only updated if no operation started meanwhile.
+    pub fn gulp(&mut self, token: AccountId) {
+        self.assert_unlocked();
+        assert!(self.isBound(token.clone()), "ERR_NOT_BOUND");
+        let get_balance = if self.nep141_tokens.contains(&token) {
+            ext_nep141::ft_balance_of(
+                env::current_account_id(),
                 &token,
-                &env::predecessor_account_id(),
-                balance - old_balance,
-            );
+                NO_DEPOSIT,
+                gas::NEP21_GET_BALANCE,
+            )
         } else {
-            let token_balance_withdrawn = old_balance - balance;
-            let token_exit_fee = token_balance_withdrawn * EXIT_FEE;
-            self.push_underlying(
-                token.clone(),
-                env::predecessor_account_id(),
-                token_balance_withdrawn - token_exit_fee,
-            );
-            self.push_underlying(token.clone(), self.factory.clone(), token_exit_fee);
+            ext_nep21::get_balance(
+                env::current_account_id(),
+                &token,
+                NO_DEPOSIT,
+                gas::NEP21_GET_BALANCE,
+            )
+        };
+        get_balance.then(ext_self::on_gulp(
+            token,
+            self.lock_nonce,
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            gas::ON_GULP_CALLBACK,
+        ));
+    }
+
+    /// Returns the amount of `token` deposited by `account_id`.
+    pub fn getDeposit(&self, account_id: AccountId, token: AccountId) -> U128 {
+        self.deposits
+            .get(&account_id)
+            .and_then(|deposits| deposits.get(&token).cloned())
+            .unwrap_or(0)
+            .into()
+    }
+
+    pub fn isDepositOutputs(&self, account_id: AccountId) -> bool {
+        self.deposit_outputs.contains(&account_id)
+    }
+
+    /// Keeps tokens sent out by the caller's operations as its deposits if `enabled`, which
+    /// requires the caller to be registered with `storage_deposit`.
+    pub fn setDepositOutputs(&mut self, enabled: bool) {
+        let account_id = env::predecessor_account_id();
+        if enabled {
+            assert!(self.token.is_registered(&account_id), "ERR_NOT_REGISTERED");
+            self.deposit_outputs.insert(&account_id);
+        } else {
+            self.deposit_outputs.remove(&account_id);
         }
-        // TODO: move this into the on_pull or else clause.
-        self.records.insert(&token, &record);
     }
 
-    pub fn unbind(&mut self, token: AccountId) {}
+    /// Sends `amount` of deposited `token` back to the caller. Returns whether it was sent.
+    pub fn withdraw(&mut self, token: AccountId, amount: U128) -> Promise {
+        self.lock();
+        assert!(amount.0 > 0, "ERR_ZERO_AMOUNT");
+        let account_id = env::predecessor_account_id();
+        self.withdraw_deposit(&account_id, &token, amount.into());
+        self.push_underlying(token.clone(), account_id.clone(), amount.into())
+            .then(ext_self::on_withdraw(
+                account_id,
+                token,
+                amount,
+                &env::current_account_id(),
+                NO_DEPOSIT,
+                gas::ON_WITHDRAW_CALLBACK,
+            ))
+    }
 
-    /// Absorb any tokens that have been sent to this contract into the pool.
-    pub fn gulp(&mut self, token: AccountId) {
-        // TODO: call ext_nep21.balance(env::current_account_id(), token.clone()).then()
+    /// Returns the amount of `token` owed to `account_id` by its failed operations.
+    pub fn getClaim(&self, account_id: AccountId, token: AccountId) -> U128 {
+        self.claims
+            .get(&account_id)
+            .and_then(|claims| claims.get(&token).cloned())
+            .unwrap_or(0)
+            .into()
+    }
+
+    /// Sends the whole claimable amount of `token` to the caller.
+    pub fn claim(&mut self, token: AccountId) {
+        self.lock();
+        let account_id = env::predecessor_account_id();
+        let amount = self.withdraw_claim(&account_id, &token);
+        assert!(amount > 0, "ERR_NO_CLAIM");
+        self.push_underlying(token.clone(), account_id.clone(), amount)
+            .then(ext_self::on_claim(
+                account_id,
+                token,
+                amount.into(),
+                &env::current_account_id(),
+                NO_DEPOSIT,
+                gas::ON_CLAIM_CALLBACK,
+            ));
     }
 
-    pub fn getSpotPrice(&self, tokenIn: AccountId, tokenOut: AccountId) -> Balance {
+    pub fn getSpotPrice(&self, tokenIn: AccountId, tokenOut: AccountId) -> U128 {
+        self.assert_unlocked();
         assert!(self.isBound(tokenIn.clone()), "ERR_NOT_BOUND");
         assert!(self.isBound(tokenOut.clone()), "ERR_NOT_BOUND");
         let in_record = self.records.get(&tokenIn).unwrap();
         let out_record = self.records.get(&tokenOut).unwrap();
-        calc_spot_price(
-            in_record.balance,
-            in_record.denorm,
-            out_record.balance,
-            out_record.denorm,
-            self.swap_fee,
-        )
+        self.spot_price(&in_record, &out_record, self.swap_fee())
+            .into()
     }
 
-    pub fn getSpotPriceSansFee(&self, tokenIn: AccountId, tokenOut: AccountId) -> Balance {
+    pub fn getSpotPriceSansFee(&self, tokenIn: AccountId, tokenOut: AccountId) -> U128 {
+        self.assert_unlocked();
         assert!(self.isBound(tokenIn.clone()), "ERR_NOT_BOUND");
         assert!(self.isBound(tokenOut.clone()), "ERR_NOT_BOUND");
         let in_record = self.records.get(&tokenIn).unwrap();
         let out_record = self.records.get(&tokenOut).unwrap();
-        calc_spot_price(
-            in_record.balance,
-            in_record.denorm,
-            out_record.balance,
-            out_record.denorm,
-            0,
-        )
+        self.spot_price(&in_record, &out_record, 0).into()
+    }
+
+    /// Spot price of `tokenIn` in `tokenOut` without fee, from time-weighted averages of their
+    /// prices in the oracle's reference token over at least the last `window` seconds. It's the
+    /// average price of the pair itself if either token is the reference.
+    pub fn consult(&self, tokenIn: AccountId, tokenOut: AccountId, window: u64) -> U128 {
+        self.assert_unlocked();
+        assert!(self.isBound(tokenIn.clone()), "ERR_NOT_BOUND");
+        assert!(self.isBound(tokenOut.clone()), "ERR_NOT_BOUND");
+        let reference = self
+            .oracle
+            .reference()
+            .expect("ERR_MISSING_HISTORICAL_OBSERVATION")
+            .clone();
+        let average_price = |token: &AccountId| {
+            let price = if *token == reference {
+                BONE
+            } else {
+                self.getSpotPriceSansFee(reference.clone(), token.clone())
+                    .into()
+            };
+            self.oracle.consult(token, price, window)
+        };
+        bdiv(average_price(&tokenOut), average_price(&tokenIn)).into()
+    }
+
+    pub fn swapExactAmountIn(
+        &mut self,
+        tokenIn: AccountId,
+        tokenAmountIn: U128,
+        tokenOut: AccountId,
+        minAmountOut: U128,
+        maxPrice: U128,
+    ) -> (U128, U128) {
+        self.lock();
+        let token_amount_in = tokenAmountIn.into();
+        let (token_amount_out, spot_price_after) = self.swap_exact_amount_in(
+            &tokenIn,
+            token_amount_in,
+            &tokenOut,
+            minAmountOut.into(),
+            maxPrice.into(),
+        );
+
+        self.start_op(
+            env::predecessor_account_id(),
+            PendingOp {
+                kind: OpKind::Swap,
+                amounts_in: vec![(tokenIn, token_amount_in)],
+                amounts_out: vec![(tokenOut, token_amount_out)],
+                ..Default::default()
+            },
+        );
+
+        (token_amount_out.into(), spot_price_after.into())
+    }
+
+    pub fn swapExactAmountOut(
+        &mut self,
+        tokenIn: AccountId,
+        maxAmountIn: U128,
+        tokenOut: AccountId,
+        tokenAmountOut: U128,
+        maxPrice: U128,
+    ) -> (U128, U128) {
+        self.lock();
+        assert!(self.isBound(tokenIn.clone()), "ERR_NOT_BOUND");
+        assert!(self.isBound(tokenOut.clone()), "ERR_NOT_BOUND");
+        assert!(self.public_swap, "ERR_SWAP_NOT_PUBLIC");
+        let max_amount_in = maxAmountIn.into();
+        let token_amount_out = tokenAmountOut.into();
+        let max_price = maxPrice.into();
+
+        let mut in_record = self.records.get(&tokenIn).unwrap();
+        let mut out_record = self.records.get(&tokenOut).unwrap();
+
+        assert!(
+            token_amount_out <= bmul(out_record.balance, MAX_OUT_RATIO),
+            "ERR_MAX_OUT_RATIO"
+        );
+
+        let spot_price_before = self.spot_price(&in_record, &out_record, self.swap_fee());
+        assert!(spot_price_before <= max_price, "ERR_BAD_LIMIT_PRICE");
+
+        let token_amount_in = self.in_given_out(&in_record, &out_record, token_amount_out);
+        assert!(token_amount_in <= max_amount_in, "ERR_LIMIT_IN");
+
+        in_record.balance = badd(in_record.balance, token_amount_in);
+        out_record.balance = bsub(out_record.balance, token_amount_out);
+
+        let spot_price_after = self.spot_price(&in_record, &out_record, self.swap_fee());
+        assert!(spot_price_after >= spot_price_before, "ERR_MATH_APPROX");
+        assert!(spot_price_after <= max_price, "ERR_LIMIT_PRICE");
+        assert!(
+            spot_price_before <= bdiv(token_amount_in, token_amount_out),
+            "ERR_MATH_APPROX"
+        );
+
+        self.start_op(
+            env::predecessor_account_id(),
+            PendingOp {
+                kind: OpKind::Swap,
+                amounts_in: vec![(tokenIn, token_amount_in)],
+                amounts_out: vec![(tokenOut, token_amount_out)],
+                ..Default::default()
+            },
+        );
+
+        (token_amount_in.into(), spot_price_after.into())
     }
 
     pub fn joinPool(&mut self, poolAmountOut: Balance, maxAmountsIn: Vec<Balance>) {
+        self.lock();
         assert!(self.finalized, "ERR_NOT_FINALIZED");
         let pool_total = self.token.get_total_supply();
-        let ratio = poolAmountOut / pool_total;
+        let ratio = bdiv(poolAmountOut, pool_total);
         assert_ne!(ratio, 0, "ERR_MAX_APPROX");
 
+        let mut amounts_in = vec![];
         for i in 0..self.tokens.len() {
-            let mut record = self.records.get(&self.tokens[i]).unwrap();
-            let token_amount_in = ratio * record.balance;
+            let record = self.records.get(&self.tokens[i]).unwrap();
+            let token_amount_in = bmul(ratio, record.balance);
             assert_ne!(token_amount_in, 0, "ERR_MATH_APPROX");
             assert!(token_amount_in <= maxAmountsIn[i], "ERR_LIMIT_IN");
-            record.balance += token_amount_in;
-            self.pull_underlying(
-                &self.tokens[i].clone(),
-                &env::predecessor_account_id(),
-                token_amount_in,
-            );
-            // TODO: join all promises and only save records / mint shares on success.
+            amounts_in.push((self.tokens[i].clone(), token_amount_in));
         }
-        self.mint_pool_share(poolAmountOut);
-        self.push_pool_share(env::predecessor_account_id(), poolAmountOut);
+        self.start_op(
+            env::predecessor_account_id(),
+            PendingOp {
+                kind: OpKind::Join,
+                amounts_in,
+                pool_amount_out: poolAmountOut,
+                ..Default::default()
+            },
+        );
     }
 
     pub fn exitPool(&mut self, poolAmountIn: Balance, minAmountsOut: Vec<Balance>) {
+        self.lock();
         assert!(self.finalized, "ERR_NOT_FINALIZED");
 
         let pool_total = self.token.get_total_supply();
-        let exit_fee = poolAmountIn * EXIT_FEE;
-        let p_ai_after_exit_fee = poolAmountIn - exit_fee;
-        let ratio = p_ai_after_exit_fee / pool_total;
+        let ratio = bdiv(poolAmountIn, pool_total);
         assert_ne!(ratio, 0, "ERR_MATH_APPROX");
 
-        self.pull_pool_share(env::predecessor_account_id(), poolAmountIn);
-        self.push_pool_share(self.factory.clone(), exit_fee);
-        self.burn_pool_share(p_ai_after_exit_fee);
-
+        let mut amounts_out = vec![];
         for i in 0..self.tokens.len() {
-            let mut record = self.records.get(&self.tokens[i]).unwrap();
-            let token_amount_out = ratio * record.balance;
+            let record = self.records.get(&self.tokens[i]).unwrap();
+            let token_amount_out = bmul(ratio, record.balance);
             assert_ne!(token_amount_out, 0, "ERR_MATH_APPROX");
             assert!(token_amount_out >= minAmountsOut[i], "ERR_LIMIT_OUT");
-            record.balance += token_amount_out;
-            self.push_underlying(
-                self.tokens[i].clone(),
-                env::predecessor_account_id(),
-                token_amount_out,
-            );
+            amounts_out.push((self.tokens[i].clone(), token_amount_out));
+        }
+        self.start_op(
+            env::predecessor_account_id(),
+            PendingOp {
+                kind: OpKind::Exit,
+                amounts_out,
+                pool_amount_in: poolAmountIn,
+                ..Default::default()
+            },
+        );
+    }
+
+    pub fn joinswapExternAmountIn(
+        &mut self,
+        tokenIn: AccountId,
+        tokenAmountIn: U128,
+        minPoolAmountOut: U128,
+    ) -> U128 {
+        self.lock();
+        let token_amount_in = tokenAmountIn.into();
+        let pool_amount_out =
+            self.joinswap_extern_amount_in(&tokenIn, token_amount_in, minPoolAmountOut.into());
+
+        self.start_op(
+            env::predecessor_account_id(),
+            PendingOp {
+                kind: OpKind::Join,
+                amounts_in: vec![(tokenIn, token_amount_in)],
+                pool_amount_out,
+                ..Default::default()
+            },
+        );
+
+        pool_amount_out.into()
+    }
+
+    pub fn joinswapPoolAmountOut(
+        &mut self,
+        tokenIn: AccountId,
+        poolAmountOut: U128,
+        maxAmountIn: U128,
+    ) -> U128 {
+        self.lock();
+        assert!(self.finalized, "ERR_NOT_FINALIZED");
+        assert!(self.isBound(tokenIn.clone()), "ERR_NOT_BOUND");
+        let pool_amount_out = poolAmountOut.into();
+        let max_amount_in = maxAmountIn.into();
+
+        let in_record = self.records.get(&tokenIn).unwrap();
+
+        let token_amount_in = self.single_in_given_pool_out(&in_record, pool_amount_out);
+        assert_ne!(token_amount_in, 0, "ERR_MATH_APPROX");
+        assert!(token_amount_in <= max_amount_in, "ERR_LIMIT_IN");
+        assert!(
+            token_amount_in <= bmul(in_record.balance, MAX_IN_RATIO),
+            "ERR_MAX_IN_RATIO"
+        );
+
+        self.start_op(
+            env::predecessor_account_id(),
+            PendingOp {
+                kind: OpKind::Join,
+                amounts_in: vec![(tokenIn, token_amount_in)],
+                pool_amount_out,
+                ..Default::default()
+            },
+        );
+
+        token_amount_in.into()
+    }
+
+    pub fn exitswapPoolAmountIn(
+        &mut self,
+        tokenOut: AccountId,
+        poolAmountIn: U128,
+        minAmountOut: U128,
+    ) -> U128 {
+        self.lock();
+        assert!(self.finalized, "ERR_NOT_FINALIZED");
+        assert!(self.isBound(tokenOut.clone()), "ERR_NOT_BOUND");
+        let pool_amount_in = poolAmountIn.into();
+        let min_amount_out = minAmountOut.into();
+
+        let out_record = self.records.get(&tokenOut).unwrap();
+
+        let token_amount_out = self.single_out_given_pool_in(&out_record, pool_amount_in);
+        assert!(token_amount_out >= min_amount_out, "ERR_LIMIT_OUT");
+        assert!(
+            token_amount_out <= bmul(out_record.balance, MAX_OUT_RATIO),
+            "ERR_MAX_OUT_RATIO"
+        );
+
+        self.start_op(
+            env::predecessor_account_id(),
+            PendingOp {
+                kind: OpKind::Exit,
+                amounts_out: vec![(tokenOut, token_amount_out)],
+                pool_amount_in,
+                ..Default::default()
+            },
+        );
+
+        token_amount_out.into()
+    }
+
+    pub fn exitswapExternAmountOut(
+        &mut self,
+        tokenOut: AccountId,
+        tokenAmountOut: U128,
+        maxPoolAmountIn: U128,
+    ) -> U128 {
+        self.lock();
+        assert!(self.finalized, "ERR_NOT_FINALIZED");
+        assert!(self.isBound(tokenOut.clone()), "ERR_NOT_BOUND");
+        let token_amount_out = tokenAmountOut.into();
+        let max_pool_amount_in = maxPoolAmountIn.into();
+
+        let out_record = self.records.get(&tokenOut).unwrap();
+        assert!(
+            token_amount_out <= bmul(out_record.balance, MAX_OUT_RATIO),
+            "ERR_MAX_OUT_RATIO"
+        );
+
+        let pool_amount_in = self.pool_in_given_single_out(&out_record, token_amount_out);
+        assert_ne!(pool_amount_in, 0, "ERR_MATH_APPROX");
+        assert!(pool_amount_in <= max_pool_amount_in, "ERR_LIMIT_IN");
+
+        self.start_op(
+            env::predecessor_account_id(),
+            PendingOp {
+                kind: OpKind::Exit,
+                amounts_out: vec![(tokenOut, token_amount_out)],
+                pool_amount_in,
+                ..Default::default()
+            },
+        );
+
+        pool_amount_in.into()
+    }
+
+    /// Callback after all tokens of the pending operation of `account_id` were pulled.
+    /// Commits the operation if every transfer succeeded, otherwise what was pulled becomes
+    /// claimable by the account.
+    pub fn on_pull(&mut self, account_id: AccountId) -> bool {
+        assert_eq!(
+            env::predecessor_account_id(),
+            env::current_account_id(),
+            "ERR_NOT_SELF"
+        );
+        let op = self.pending.get(&account_id).expect("ERR_NO_PENDING_OP");
+        let results = promise_results();
+        if results.iter().all(|success| *success) {
+            self.settle_op(account_id, op);
+            return true;
+        }
+        for ((token, amount), success) in op.amounts_in.into_iter().zip(results) {
+            if success {
+                self.add_claim(&account_id, &token, amount);
+            }
+            // Token which `bind` failed isn't kept without a balance.
+            if op.kind == OpKind::Bind && self.records.get(&token).unwrap().denorm == 0 {
+                self.remove_token(&token);
+            }
+        }
+        if op.pool_amount_in > 0 {
+            self.push_pool_share(account_id.clone(), op.pool_amount_in);
         }
+        self.pending.remove(&account_id);
+        self.unlock();
+        false
     }
 
-    pub fn on_pull(&mut self) -> bool {
-        true
+    /// Callback after all tokens of the pending operation of `account_id` were pushed.
+    /// Tokens that failed to transfer are returned to the pool, together with the shares or
+    /// input tokens that were paid for them. Input tokens become claimable by the account.
+    pub fn on_push(&mut self, account_id: AccountId) -> bool {
+        assert_eq!(
+            env::predecessor_account_id(),
+            env::current_account_id(),
+            "ERR_NOT_SELF"
+        );
+        let op = self.pending.remove(&account_id).expect("ERR_NO_PENDING_OP");
+        self.update_oracle();
+        let results = promise_results();
+        if op.kind == OpKind::Bind && results.iter().all(|success| *success) {
+            self.set_weight(&op.amounts_out[0].0, op.denorm);
+        }
+        let num_out = op.amounts_out.len();
+        // Taken before balances are restored, which changes them for stable pools.
+        let shares: Vec<Balance> = op
+            .amounts_out
+            .iter()
+            .map(|(token, _)| self.token_share(&self.records.get(token).unwrap()))
+            .collect();
+        let mut all_success = true;
+        let mut pool_amount_refund = 0;
+        for (((token, amount), success), share) in op.amounts_out.iter().zip(results).zip(shares) {
+            if success {
+                self.out_event(account_id.clone(), &op, token.clone(), *amount)
+                    .emit();
+                continue;
+            }
+            all_success = false;
+            let mut record = self.records.get(token).unwrap();
+            record.balance = badd(record.balance, *amount);
+            self.records.insert(token, &record);
+            // Shares that paid for this token: all of them for single-asset exit,
+            // otherwise the token's share of the pool.
+            pool_amount_refund = if num_out == 1 {
+                op.pool_amount_in
+            } else {
+                badd(pool_amount_refund, bmul(op.pool_amount_in, share))
+            };
+        }
+        if !all_success {
+            if op.protocol_fee > 0 {
+                self.burn_pool_share(op.protocol_fee);
+                self.accrued_protocol_fee = bsub(self.accrued_protocol_fee, op.protocol_fee);
+            }
+            if let Some(dynamic_fee) = self.dynamic_fee.as_mut() {
+                dynamic_fee.revert(op.price_change);
+            }
+            for (token, amount) in op.amounts_in.into_iter() {
+                let mut record = self.records.get(&token).unwrap();
+                record.balance = bsub(record.balance, amount);
+                self.records.insert(&token, &record);
+                self.add_claim(&account_id, &token, amount);
+            }
+        }
+        if op.pool_amount_in > 0 {
+            let pool_amount_refund = std::cmp::min(pool_amount_refund, op.pool_amount_in);
+            if pool_amount_refund > 0 {
+                self.push_pool_share(account_id, pool_amount_refund);
+            }
+            self.burn_pool_share(op.pool_amount_in - pool_amount_refund);
+        }
+        self.unlock();
+        all_success
+    }
+
+    /// Callback with the balance of the pool in `token` contract, that becomes the pool's balance
+    /// unless the lock was taken since `lock_nonce`.
+    pub fn on_gulp(&mut self, token: AccountId, lock_nonce: u64) -> U128 {
+        assert_eq!(
+            env::predecessor_account_id(),
+            env::current_account_id(),
+            "ERR_NOT_SELF"
+        );
+        assert_eq!(env::promise_results_count(), 1, "ERR_PROMISE_RESULTS");
+        let mut record = self.records.get(&token).expect("ERR_NOT_BOUND");
+        if self.mutex || self.lock_nonce != lock_nonce {
+            return record.balance.into();
+        }
+        self.update_oracle();
+        // Balance stays the same if the token didn't report it.
+        if let PromiseResult::Successful(value) = env::promise_result(0) {
+            if let Ok(balance) = serde_json::from_slice::<U128>(&value) {
+                let total_deposit = self.total_deposits.get(&token).unwrap_or(0);
+                let total_claim = self.total_claims.get(&token).unwrap_or(0);
+                record.balance = balance.0.saturating_sub(badd(total_deposit, total_claim));
+                self.records.insert(&token, &record);
+            }
+        }
+        record.balance.into()
     }
 
-    pub fn on_push(&mut self) -> bool {
-        true
+    /// Callback after deposited `token` was sent to `account_id`, deposit is restored on failure.
+    pub fn on_withdraw(&mut self, account_id: AccountId, token: AccountId, amount: U128) -> bool {
+        assert_eq!(
+            env::predecessor_account_id(),
+            env::current_account_id(),
+            "ERR_NOT_SELF"
+        );
+        let success = promise_results().iter().all(|success| *success);
+        if !success {
+            self.deposit(&account_id, &token, amount.into());
+        }
+        self.unlock();
+        success
+    }
+
+    /// Callback after claimed `token` was sent to `account_id`, claim is restored on failure.
+    pub fn on_claim(&mut self, account_id: AccountId, token: AccountId, amount: U128) -> bool {
+        assert_eq!(
+            env::predecessor_account_id(),
+            env::current_account_id(),
+            "ERR_NOT_SELF"
+        );
+        let success = promise_results().iter().all(|success| *success);
+        if !success {
+            self.add_claim(&account_id, &token, amount.into());
+        }
+        self.unlock();
+        success
+    }
+
+    /// Callback after the balance of `token` was returned to the controller in `unbind`.
+    /// Removes the token from the pool on success, otherwise restores its balance.
+    /// Returns the amount returned to the controller, 0 if the transfer failed.
+    pub fn on_unbind(&mut self, token: AccountId, amount: U128) -> U128 {
+        assert_eq!(
+            env::predecessor_account_id(),
+            env::current_account_id(),
+            "ERR_NOT_SELF"
+        );
+        self.update_oracle();
+        let mut record = self.records.get(&token).expect("ERR_NOT_BOUND");
+        if !promise_results().iter().all(|success| *success) {
+            record.balance = badd(record.balance, amount.into());
+            self.records.insert(&token, &record);
+            self.unlock();
+            return 0.into();
+        }
+        self.remove_token(&token);
+        Event::Unbind {
+            caller: self.controller.clone(),
+            token,
+        }
+        .emit();
+        self.unlock();
+        amount
     }
 }
 
 #[near_bindgen]
 impl FungibleToken for BPool {
+    #[payable]
     fn inc_allowance(&mut self, escrow_account_id: String, amount: U128) {
         self.token.inc_allowance(escrow_account_id, amount.into());
     }
 
+    #[payable]
     fn dec_allowance(&mut self, escrow_account_id: String, amount: U128) {
         self.token.dec_allowance(escrow_account_id, amount.into());
     }
 
+    #[payable]
     fn transfer_from(&mut self, owner_id: String, new_owner_id: String, amount: U128) {
         self.token
             .transfer_from(owner_id, new_owner_id, amount.into());
     }
 
+    #[payable]
     fn transfer(&mut self, new_owner_id: String, amount: U128) {
         self.token.transfer(new_owner_id, amount.into());
     }
@@ -357,130 +1486,2858 @@ impl FungibleToken for BPool {
     }
 }
 
-#[ext_contract(ext_self)]
-pub trait ExtSelf {
-    fn on_pull(&mut self) -> bool;
+#[near_bindgen]
+impl FungibleTokenCore for BPool {
+    #[payable]
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>) {
+        self.token.ft_transfer(receiver_id, amount.into(), memo);
+    }
 
-    fn on_push(&mut self) -> bool;
-}
+    #[payable]
+    fn ft_transfer_call(
+        &mut self,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+        msg: String,
+    ) -> Promise {
+        self.token
+            .ft_transfer_call(receiver_id, amount.into(), memo, msg)
+    }
 
-impl BPool {
-    fn pull_underlying(&mut self, token: &AccountId, from: &AccountId, amount: Balance) -> Promise {
-        ext_nep21::transfer_from(
-            from.clone(),
-            env::current_account_id(),
-            amount.into(),
-            token,
-            NO_DEPOSIT,
-            gas::NEP21_TRANSFER_FROM,
-        )
-        .then(ext_self::on_pull(
-            &env::current_account_id(),
-            NO_DEPOSIT,
-            gas::ON_PULL_CALLBACK,
-        ))
+    fn ft_total_supply(&self) -> U128 {
+        self.token.get_total_supply().into()
     }
 
-    fn push_underlying(&mut self, token: AccountId, to: AccountId, amount: Balance) -> Promise {
-        ext_nep21::transfer(
-            to.clone(),
-            amount.into(),
-            &token,
-            NO_DEPOSIT,
-            gas::NEP21_TRANSFER,
-        )
-        .then(ext_self::on_push(
-            &env::current_account_id(),
-            NO_DEPOSIT,
-            gas::ON_PUSH_CALLBACK,
-        ))
+    fn ft_balance_of(&self, account_id: AccountId) -> U128 {
+        self.token.get_balance(account_id).into()
     }
+}
 
-    fn mint_pool_share(&mut self, amount: Balance) {
-        self.token.mint(env::current_account_id(), amount)
+#[near_bindgen]
+impl FungibleTokenResolver for BPool {
+    fn ft_resolve_transfer(
+        &mut self,
+        sender_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+    ) -> U128 {
+        self.token
+            .ft_resolve_transfer(sender_id, receiver_id, amount.into())
+            .into()
     }
+}
 
-    fn burn_pool_share(&mut self, amount: Balance) {
-        self.token.burn(env::current_account_id(), amount)
+#[near_bindgen]
+impl StorageManagement for BPool {
+    #[payable]
+    fn storage_deposit(
+        &mut self,
+        account_id: Option<AccountId>,
+        registration_only: Option<bool>,
+    ) -> StorageBalance {
+        // Storage balance is fixed, so any excess is refunded regardless of `registration_only`.
+        let _ = registration_only;
+        self.token.storage_deposit(account_id)
     }
 
-    fn pull_pool_share(&mut self, from: AccountId, amount: Balance) {
+    #[payable]
+    fn storage_withdraw(&mut self, amount: Option<U128>) -> StorageBalance {
         self.token
-            .transfer_from(from, env::current_account_id(), amount)
+            .storage_withdraw(amount.map(|amount| amount.into()))
     }
 
-    fn push_pool_share(&mut self, to: AccountId, amount: Balance) {
-        self.token
-            .transfer_from(env::current_account_id(), to, amount)
+    /// Accounts with deposits, claims or a pending operation can't unregister, as the storage
+    /// deposit covers them.
+    #[payable]
+    fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+        let account_id = env::predecessor_account_id();
+        assert!(self.deposits.get(&account_id).is_none(), "ERR_HAS_DEPOSITS");
+        assert!(self.claims.get(&account_id).is_none(), "ERR_HAS_CLAIMS");
+        assert!(self.pending.get(&account_id).is_none(), "ERR_PENDING_OP");
+        self.deposit_outputs.remove(&account_id);
+        self.token.storage_unregister(force)
+    }
+
+    fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+        self.token.storage_balance_bounds()
+    }
+
+    fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance> {
+        self.token.storage_balance_of(account_id)
     }
 }
 
-#[cfg(not(target_arch = "wasm32"))]
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use near_sdk::{testing_env, MockedBlockchain, VMContext};
+#[near_bindgen]
+impl FungibleTokenMetadataProvider for BPool {
+    /// Symbol defaults to "BPT-" followed by the bound tokens, e.g. "BPT-WETH-DAI".
+    fn ft_metadata(&self) -> FungibleTokenMetadata {
+        let symbol = self.symbol.clone().unwrap_or_else(|| {
+            let tokens: Vec<String> = self
+                .tokens
+                .iter()
+                .map(|token| token.split('.').next().unwrap().to_uppercase())
+                .collect();
+            format!("BPT-{}", tokens.join("-"))
+        });
+        FungibleTokenMetadata::new(
+            self.name.clone().unwrap_or_else(|| POOL_NAME.to_string()),
+            symbol,
+            POOL_DECIMALS,
+        )
+    }
+}
 
-    fn pool_account() -> AccountId {
-        "pool".to_string()
+#[near_bindgen]
+impl FungibleTokenReceiver for BPool {
+    /// Executes `TransferAction` from `msg` with `amount` of tokens received from `sender_id`.
+    /// If the action fails, the token contract refunds all of them.
+    fn ft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        let token_in = env::predecessor_account_id();
+        let token_amount_in = amount.into();
+        let action: TransferAction = serde_json::from_str(&msg).expect("ERR_WRONG_MSG");
+        self.lock();
+        // Actions fail for tokens that aren't bound.
+        self.nep141_tokens.insert(&token_in);
+        match action {
+            TransferAction::Swap {
+                token_out,
+                min_amount_out,
+                max_price,
+            } => {
+                let (token_amount_out, _) = self.swap_exact_amount_in(
+                    &token_in,
+                    token_amount_in,
+                    &token_out,
+                    min_amount_out.into(),
+                    max_price.into(),
+                );
+                self.receive_op(
+                    sender_id,
+                    PendingOp {
+                        kind: OpKind::Swap,
+                        amounts_in: vec![(token_in, token_amount_in)],
+                        amounts_out: vec![(token_out, token_amount_out)],
+                        ..Default::default()
+                    },
+                );
+            }
+            TransferAction::JoinSingle {
+                min_pool_amount_out,
+            } => {
+                let pool_amount_out = self.joinswap_extern_amount_in(
+                    &token_in,
+                    token_amount_in,
+                    min_pool_amount_out.into(),
+                );
+                self.receive_op(
+                    sender_id,
+                    PendingOp {
+                        kind: OpKind::Join,
+                        amounts_in: vec![(token_in, token_amount_in)],
+                        pool_amount_out,
+                        ..Default::default()
+                    },
+                );
+            }
+            TransferAction::Deposit => {
+                assert!(self.isBound(token_in.clone()), "ERR_NOT_BOUND");
+                assert!(self.token.is_registered(&sender_id), "ERR_NOT_REGISTERED");
+                self.deposit(&sender_id, &token_in, token_amount_in);
+                self.unlock();
+            }
+        }
+        // All received tokens are used.
+        PromiseOrValue::Value(0.into())
     }
-    fn factory_account() -> AccountId {
-        "factory".to_string()
+}
+
+#[ext_contract(ext_self)]
+pub trait ExtSelf {
+    fn on_pull(&mut self, account_id: AccountId) -> bool;
+
+    fn on_push(&mut self, account_id: AccountId) -> bool;
+
+    fn on_unbind(&mut self, token: AccountId, amount: U128) -> U128;
+
+    fn on_gulp(&mut self, token: AccountId, lock_nonce: u64) -> U128;
+
+    fn on_withdraw(&mut self, account_id: AccountId, token: AccountId, amount: U128) -> bool;
+
+    fn on_claim(&mut self, account_id: AccountId, token: AccountId, amount: U128) -> bool;
+}
+
+/// Returns whether each of the promises this callback depends on succeeded.
+fn promise_results() -> Vec<bool> {
+    (0..env::promise_results_count())
+        .map(|i| match env::promise_result(i) {
+            PromiseResult::Successful(_) => true,
+            _ => false,
+        })
+        .collect()
+}
+
+impl BPool {
+    /// Pool with given share `token` and token `records`, created by the caller.
+    fn with_state(
+        token: Token,
+        records: UnorderedMap<AccountId, Record>,
+        name: Option<String>,
+        symbol: Option<String>,
+    ) -> Self {
+        let mut pool = Self {
+            controller: env::predecessor_account_id(),
+            factory: env::predecessor_account_id(),
+            swap_fee: MIN_FEE,
+            public_swap: false,
+            finalized: false,
+            records,
+            tokens: Vec::new(),
+            total_weight: 0,
+            token,
+            pending: UnorderedMap::new(b"p".to_vec()),
+            mutex: false,
+            lock_block: 0,
+            lock_nonce: 0,
+            deposits: UnorderedMap::new(b"d".to_vec()),
+            total_deposits: UnorderedMap::new(b"t".to_vec()),
+            claims: UnorderedMap::new(b"c".to_vec()),
+            total_claims: UnorderedMap::new(b"k".to_vec()),
+            deposit_outputs: UnorderedSet::new(b"e".to_vec()),
+            nep141_tokens: UnorderedSet::new(b"n".to_vec()),
+            name,
+            symbol,
+            gradual_update: None,
+            whitelist: UnorderedSet::new(b"w".to_vec()),
+            whitelist_enabled: false,
+            cap: Balance::max_value(),
+            oracle: Oracle::new(b"o".to_vec()),
+            protocol_fee: 0,
+            accrued_protocol_fee: 0,
+            collected_protocol_fee: 0,
+            dynamic_fee: None,
+            amp: None,
+            decimals: UnorderedMap::new(b"m".to_vec()),
+            guardian: env::predecessor_account_id(),
+            paused: false,
+        };
+        pool.measure_account_storage_usage();
+        pool
     }
-    fn token1_account() -> AccountId {
-        "token1".to_string()
+
+    /// Adds storage of the largest deposits, claims and pending operation of an account to the
+    /// storage that registered accounts pay for. Accounts only hold bound tokens in them.
+    fn measure_account_storage_usage(&mut self) {
+        let initial_storage = env::storage_usage();
+        let account_id = "a".repeat(64);
+        let amounts: Vec<(AccountId, Balance)> = (0..MAX_BOUND_TOKENS)
+            .map(|i| (format!("{:a>64}", i), Balance::max_value()))
+            .collect();
+        let balances: BTreeMap<AccountId, Balance> = amounts.iter().cloned().collect();
+        self.deposits.insert(&account_id, &balances);
+        self.claims.insert(&account_id, &balances);
+        self.deposit_outputs.insert(&account_id);
+        self.pending.insert(
+            &account_id,
+            &PendingOp {
+                amounts_in: amounts.clone(),
+                amounts_out: amounts.clone(),
+                swap_fee: amounts.into_iter().next(),
+                ..Default::default()
+            },
+        );
+        self.token.account_storage_usage += env::storage_usage() - initial_storage;
+        self.deposits.remove(&account_id);
+        self.claims.remove(&account_id);
+        self.deposit_outputs.remove(&account_id);
+        self.pending.remove(&account_id);
     }
-    fn token2_account() -> AccountId {
-        "token2".to_string()
+
+    fn assert_can_rebind(&self, token: &AccountId) {
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.controller,
+            "ERR_NOT_CONTROLLER"
+        );
+        assert!(self.isBound(token.clone()), "ERR_NOT_BOUND");
+        assert!(!self.finalized, "ERR_IS_FINALIZED");
     }
 
-    pub fn get_context(
-        predecessor_account_id: AccountId,
-        account_balance: u128,
-        account_locked_balance: u128,
-        is_view: bool,
-    ) -> VMContext {
-        VMContext {
-            current_account_id: pool_account(),
-            signer_account_id: predecessor_account_id.clone(),
-            signer_account_pk: vec![0, 1, 2],
-            predecessor_account_id,
-            input: vec![],
-            block_index: 1,
-            block_timestamp: 0,
-            epoch_height: 1,
-            account_balance,
-            account_locked_balance,
-            storage_usage: 10u64.pow(6),
-            attached_deposit: 0,
-            prepaid_gas: 10u64.pow(15),
-            random_seed: vec![0, 1, 2],
-            is_view,
-            output_data_receivers: vec![],
+    /// Sets `balance` and `denorm` of bound `token` under the lock, moving the difference in
+    /// balance from or to the controller.
+    fn internal_rebind(
+        &mut self,
+        token: AccountId,
+        balance: Balance,
+        denorm: Weight,
+    ) -> PromiseOrValue<bool> {
+        assert!(denorm >= MIN_WEIGHT, "ERR_MIN_WEIGHT");
+        assert!(denorm <= MAX_WEIGHT, "ERR_MAX_WEIGHT");
+        assert!(balance >= MIN_BALANCE, "ERR_MIN_BALANCE");
+
+        self.update_oracle();
+        let record = self.records.get(&token).unwrap();
+        let old_weight = record.denorm;
+        assert!(
+            denorm == old_weight || self.gradual_update.is_none(),
+            "ERR_GRADUAL_UPDATE_IN_PROGRESS"
+        );
+        // Weights of stable pools are only set when tokens are bound.
+        assert!(
+            denorm == old_weight || old_weight == 0 || self.amp.is_none(),
+            "ERR_IS_STABLE"
+        );
+
+        // Balance and weight are updated once the transfer goes through.
+        let old_balance = record.balance;
+        let op = if balance > old_balance {
+            PendingOp {
+                kind: OpKind::Bind,
+                amounts_in: vec![(token, balance - old_balance)],
+                denorm,
+                ..Default::default()
+            }
+        } else if balance < old_balance {
+            PendingOp {
+                kind: OpKind::Bind,
+                amounts_out: vec![(token, old_balance - balance)],
+                denorm,
+                ..Default::default()
+            }
+        } else {
+            self.set_weight(&token, denorm);
+            self.bind_event(env::predecessor_account_id(), token).emit();
+            self.unlock();
+            return PromiseOrValue::Value(true);
+        };
+        match self.start_op(env::predecessor_account_id(), op) {
+            Some(promise) => promise.into(),
+            None => PromiseOrValue::Value(true),
         }
     }
 
-    fn to_yocto(amount: Balance) -> Balance {
-        amount * 10u128.pow(24)
+    /// Sets the weight of bound `token` to `denorm`, adjusting the total weight.
+    fn set_weight(&mut self, token: &AccountId, denorm: Weight) {
+        let mut record = self.records.get(token).unwrap();
+        self.total_weight = bsub(badd(self.total_weight, denorm), record.denorm);
+        record.denorm = denorm;
+        self.records.insert(token, &record);
     }
 
-    #[test]
-    fn test_setup_pool() {
-        let context = get_context(factory_account(), to_yocto(10), 0, false);
-        testing_env!(context.clone());
-        let mut pool = BPool::new();
-        assert_eq!(pool.getController(), factory_account());
-        pool.bind(
-            token1_account(),
-            to_yocto(50_000).into(),
-            to_yocto(10).into(),
+    /// Removes `token` from the pool, moving the last token in its place.
+    fn remove_token(&mut self, token: &AccountId) {
+        let record = self.records.get(token).unwrap();
+        let index = record.index as usize;
+        let last = self.tokens.len() - 1;
+        self.tokens.swap(index, last);
+        if index != last {
+            let mut moved_record = self.records.get(&self.tokens[index]).unwrap();
+            moved_record.index = index as u64;
+            self.records.insert(&self.tokens[index], &moved_record);
+        }
+        self.tokens.pop();
+        self.total_weight = bsub(self.total_weight, record.denorm);
+        self.records.remove(token);
+        self.nep141_tokens.remove(token);
+        self.decimals.remove(token);
+    }
+
+    /// Balancer's `_lock_`: fails if another operation is in progress.
+    fn lock(&mut self) {
+        assert!(!self.mutex, "ERR_REENTRY");
+        self.mutex = true;
+        self.lock_block = env::block_index();
+        self.lock_nonce += 1;
+    }
+
+    fn unlock(&mut self) {
+        self.mutex = false;
+    }
+
+    /// Balancer's `_viewlock_`: pool state is not consistent while an operation is in progress.
+    fn assert_unlocked(&self) {
+        assert!(!self.mutex, "ERR_REENTRY");
+    }
+
+    /// Computes the amount of `token_out` for exact `token_amount_in` of `token_in` and the spot
+    /// price after the swap, see `swapExactAmountIn`.
+    fn swap_exact_amount_in(
+        &self,
+        token_in: &AccountId,
+        token_amount_in: Balance,
+        token_out: &AccountId,
+        min_amount_out: Balance,
+        max_price: Balance,
+    ) -> (Balance, Balance) {
+        assert!(self.isBound(token_in.clone()), "ERR_NOT_BOUND");
+        assert!(self.isBound(token_out.clone()), "ERR_NOT_BOUND");
+        assert!(self.public_swap, "ERR_SWAP_NOT_PUBLIC");
+
+        let mut in_record = self.records.get(token_in).unwrap();
+        let mut out_record = self.records.get(token_out).unwrap();
+
+        assert!(
+            token_amount_in <= bmul(in_record.balance, MAX_IN_RATIO),
+            "ERR_MAX_IN_RATIO"
         );
-        pool.bind(
-            token2_account(),
-            to_yocto(1_000_000).into(),
+
+        let spot_price_before = self.spot_price(&in_record, &out_record, self.swap_fee());
+        assert!(spot_price_before <= max_price, "ERR_BAD_LIMIT_PRICE");
+
+        let token_amount_out = self.out_given_in(&in_record, &out_record, token_amount_in);
+        assert!(token_amount_out >= min_amount_out, "ERR_LIMIT_OUT");
+
+        in_record.balance = badd(in_record.balance, token_amount_in);
+        out_record.balance = bsub(out_record.balance, token_amount_out);
+
+        let spot_price_after = self.spot_price(&in_record, &out_record, self.swap_fee());
+        assert!(spot_price_after >= spot_price_before, "ERR_MATH_APPROX");
+        assert!(spot_price_after <= max_price, "ERR_LIMIT_PRICE");
+        assert!(
+            spot_price_before <= bdiv(token_amount_in, token_amount_out),
+            "ERR_MATH_APPROX"
+        );
+
+        (token_amount_out, spot_price_after)
+    }
+
+    /// Computes the pool shares for exact `token_amount_in` of `token_in`,
+    /// see `joinswapExternAmountIn`.
+    fn joinswap_extern_amount_in(
+        &self,
+        token_in: &AccountId,
+        token_amount_in: Balance,
+        min_pool_amount_out: Balance,
+    ) -> Balance {
+        assert!(self.finalized, "ERR_NOT_FINALIZED");
+        assert!(self.isBound(token_in.clone()), "ERR_NOT_BOUND");
+
+        let in_record = self.records.get(token_in).unwrap();
+        assert!(
+            token_amount_in <= bmul(in_record.balance, MAX_IN_RATIO),
+            "ERR_MAX_IN_RATIO"
+        );
+
+        let pool_amount_out = self.pool_out_given_single_in(&in_record, token_amount_in);
+        assert!(pool_amount_out >= min_pool_amount_out, "ERR_LIMIT_OUT");
+
+        pool_amount_out
+    }
+
+    /// Checks that `account_id` has no pending operation and may receive shares from `op`.
+    fn assert_can_start(&self, account_id: &AccountId, op: &PendingOp) {
+        assert!(self.pending.get(account_id).is_none(), "ERR_PENDING_OP");
+        if op.kind == OpKind::Swap || op.kind == OpKind::Join {
+            assert!(!self.paused, "ERR_PAUSED");
+        }
+        if op.pool_amount_out > 0 {
+            assert!(self.token.is_registered(account_id), "ERR_NOT_REGISTERED");
+            assert!(
+                !self.whitelist_enabled || self.whitelist.contains(account_id),
+                "ERR_NOT_ON_WHITELIST"
+            );
+            self.assert_below_cap(op.pool_amount_out);
+        }
+    }
+
+    fn assert_below_cap(&self, pool_amount_out: Balance) {
+        assert!(
+            badd(self.token.get_total_supply(), pool_amount_out) <= self.cap,
+            "ERR_CAP_LIMIT_REACHED"
+        );
+    }
+
+    /// Settles `op` of `account_id`, which incoming tokens were already transferred to the pool.
+    fn receive_op(&mut self, account_id: AccountId, mut op: PendingOp) {
+        self.assert_can_start(&account_id, &op);
+        op.swap_fee = self.op_swap_fee(&op);
+        self.settle_op(account_id, op);
+    }
+
+    /// Registers `op` as pending for `account_id` and pulls its incoming tokens.
+    /// Operations that don't pull anything are settled right away.
+    /// Accounts with deposits that cover all incoming tokens settle against their deposits
+    /// without any transfers.
+    /// Returns the callback that resolves the operation, if it's not settled yet.
+    fn start_op(&mut self, account_id: AccountId, mut op: PendingOp) -> Option<Promise> {
+        self.assert_can_start(&account_id, &op);
+        op.swap_fee = self.op_swap_fee(&op);
+        if op.pool_amount_in > 0 {
+            self.pull_pool_share(account_id.clone(), op.pool_amount_in);
+        }
+        if op.amounts_in.is_empty() {
+            return self.settle_op(account_id, op);
+        }
+        if self.has_deposits(&account_id, &op.amounts_in) {
+            for (token, amount) in op.amounts_in.iter() {
+                self.withdraw_deposit(&account_id, token, *amount);
+            }
+            return self.settle_op(account_id, op);
+        }
+        let mut promise: Option<Promise> = None;
+        for (token, amount) in op.amounts_in.iter() {
+            let pull = self.pull_underlying(token, &account_id, *amount);
+            promise = Some(match promise {
+                Some(promise) => promise.and(pull),
+                None => pull,
+            });
+        }
+        // Callback pushes out the outgoing tokens if all pulls succeeded.
+        let num_out = op.amounts_out.len() as u64;
+        let settle_gas = if num_out > 0 {
+            gas::NEP21_TRANSFER * num_out + gas::ON_PUSH_CALLBACK
+        } else {
+            0
+        };
+        self.pending.insert(&account_id, &op);
+        Some(promise.unwrap().then(ext_self::on_pull(
+            account_id,
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            gas::ON_PULL_CALLBACK + settle_gas,
+        )))
+    }
+
+    /// Credits pulled tokens and mints shares of the `op`, then pushes its outgoing tokens.
+    /// Outgoing tokens are added to the deposits of `account_id` instead if it opted in with
+    /// `setDepositOutputs`.
+    /// Returns the callback after the push, if there is anything to push.
+    fn settle_op(&mut self, account_id: AccountId, mut op: PendingOp) -> Option<Promise> {
+        self.update_oracle();
+        let is_swap = op.kind == OpKind::Swap;
+        let price_change = if is_swap { self.price_change(&op) } else { 0 };
+        let swap_fee = op.swap_fee.clone();
+        let is_push = !op.amounts_out.is_empty() && !self.deposit_outputs.contains(&account_id);
+        // Weight of a bind that pushes tokens out is set once the push succeeded.
+        if op.kind == OpKind::Bind && !is_push {
+            let (token, _) = op
+                .amounts_in
+                .iter()
+                .chain(op.amounts_out.iter())
+                .next()
+                .unwrap();
+            self.set_weight(token, op.denorm);
+        }
+        for (token, amount) in op.amounts_in.iter() {
+            let mut record = self.records.get(token).unwrap();
+            record.balance = badd(record.balance, *amount);
+            self.records.insert(token, &record);
+            if op.kind == OpKind::Join {
+                Event::Join {
+                    caller: account_id.clone(),
+                    token_in: token.clone(),
+                    token_amount_in: (*amount).into(),
+                }
+                .emit();
+            } else if op.kind == OpKind::Bind {
+                self.bind_event(account_id.clone(), token.clone()).emit();
+            }
+        }
+        if op.pool_amount_out > 0 {
+            self.mint_pool_share(op.pool_amount_out);
+            self.push_pool_share(account_id.clone(), op.pool_amount_out);
+        }
+        if let Some((token, fee)) = swap_fee {
+            op.protocol_fee = self.mint_protocol_fee(&token, fee);
+        }
+        if is_swap {
+            if let Some(dynamic_fee) = self.dynamic_fee.as_mut() {
+                dynamic_fee.update(price_change);
+                op.price_change = price_change;
+            }
+        }
+        if !is_push {
+            for (token, amount) in op.amounts_out.iter() {
+                let mut record = self.records.get(token).unwrap();
+                record.balance = bsub(record.balance, *amount);
+                self.records.insert(token, &record);
+                self.deposit(&account_id, token, *amount);
+                self.out_event(account_id.clone(), &op, token.clone(), *amount)
+                    .emit();
+            }
+            self.pending.remove(&account_id);
+            if op.pool_amount_in > 0 {
+                self.burn_pool_share(op.pool_amount_in);
+            }
+            self.unlock();
+            return None;
+        }
+        let mut promise: Option<Promise> = None;
+        for (token, amount) in op.amounts_out.iter() {
+            let mut record = self.records.get(token).unwrap();
+            record.balance = bsub(record.balance, *amount);
+            self.records.insert(token, &record);
+            let push = self.push_underlying(token.clone(), account_id.clone(), *amount);
+            promise = Some(match promise {
+                Some(promise) => promise.and(push),
+                None => push,
+            });
+        }
+        op.settled = true;
+        self.pending.insert(&account_id, &op);
+        Some(promise.unwrap().then(ext_self::on_push(
+            account_id,
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            gas::ON_PUSH_CALLBACK,
+        )))
+    }
+
+    /// Swap fee set by the controller, raised by the dynamic fee if enabled.
+    fn swap_fee(&self) -> Balance {
+        match &self.dynamic_fee {
+            Some(dynamic_fee) => dynamic_fee.swap_fee(self.swap_fee),
+            None => self.swap_fee,
+        }
+    }
+
+    /// Relative change of the spot price of swap `op`, before its balances are updated.
+    fn price_change(&self, op: &PendingOp) -> Balance {
+        let (token_in, token_amount_in) = &op.amounts_in[0];
+        let (token_out, token_amount_out) = &op.amounts_out[0];
+        let mut in_record = self.records.get(token_in).unwrap();
+        let mut out_record = self.records.get(token_out).unwrap();
+        let spot_price_before = self.spot_price(&in_record, &out_record, 0);
+        in_record.balance = badd(in_record.balance, *token_amount_in);
+        out_record.balance = bsub(out_record.balance, *token_amount_out);
+        let spot_price_after = self.spot_price(&in_record, &out_record, 0);
+        bdiv(spot_price_after, spot_price_before).saturating_sub(BONE)
+    }
+
+    /// Swap fee charged by `op` and the token it's charged in, before its balances are updated.
+    /// Single-token joins and exits are charged on the part beyond the token's share of the
+    /// pool, which is effectively swapped. `joinPool` and `exitPool` move all tokens and
+    /// aren't charged.
+    fn op_swap_fee(&self, op: &PendingOp) -> Option<(AccountId, Balance)> {
+        let ((token, amount), is_exit) = match op.kind {
+            OpKind::Swap => {
+                let (token_in, token_amount_in) = &op.amounts_in[0];
+                return Some((token_in.clone(), bmul(*token_amount_in, self.swap_fee())));
+            }
+            OpKind::Join if op.amounts_in.len() == 1 => (&op.amounts_in[0], false),
+            OpKind::Exit if op.amounts_out.len() == 1 => (&op.amounts_out[0], true),
+            _ => return None,
+        };
+        let share = self.token_share(&self.records.get(token).unwrap());
+        let fee_rate = bmul(bsub(BONE, share), self.swap_fee());
+        // Exits pay out the amount after the fee.
+        let fee = if is_exit {
+            bsub(bdiv(*amount, bsub(BONE, fee_rate)), *amount)
+        } else {
+            bmul(*amount, fee_rate)
+        };
+        Some((token.clone(), fee))
+    }
+
+    /// Part of the pool's value held in the `record` token: its normalized weight, or its part
+    /// of the scaled balances for stable pools.
+    fn token_share(&self, record: &Record) -> Balance {
+        match self.amp {
+            None => bdiv(record.denorm, self.total_weight),
+            Some(_) => {
+                let sum = self
+                    .balances_of(&[])
+                    .iter()
+                    .fold(0, |sum, balance| badd(sum, *balance));
+                bdiv(self.scale(record, record.balance), sum)
+            }
+        }
+    }
+
+    /// Factor that scales amounts of the `record` token to `POOL_DECIMALS`.
+    fn precision_multiplier(&self, record: &Record) -> Balance {
+        let decimals = self
+            .decimals
+            .get(&self.tokens[record.index as usize])
+            .unwrap_or(POOL_DECIMALS);
+        10u128.pow((POOL_DECIMALS - decimals) as u32)
+    }
+
+    /// `amount` of the `record` token scaled to `POOL_DECIMALS`.
+    fn scale(&self, record: &Record, amount: Balance) -> Balance {
+        amount
+            .checked_mul(self.precision_multiplier(record))
+            .expect("ERR_MUL_OVERFLOW")
+    }
+
+    /// Scaled `amount` of the `record` token back in its decimals, rounded up if `round_up`.
+    fn unscale(&self, record: &Record, amount: Balance, round_up: bool) -> Balance {
+        let multiplier = self.precision_multiplier(record);
+        if round_up {
+            (amount + multiplier - 1) / multiplier
+        } else {
+            amount / multiplier
+        }
+    }
+
+    /// Scaled balances of bound tokens in order, with balances of given `records` instead of the
+    /// stored ones.
+    fn balances_of(&self, records: &[&Record]) -> Vec<Balance> {
+        let mut balances: Vec<Balance> = self
+            .tokens
+            .iter()
+            .map(|token| {
+                let record = self.records.get(token).unwrap();
+                self.scale(&record, record.balance)
+            })
+            .collect();
+        for record in records {
+            balances[record.index as usize] = self.scale(record, record.balance);
+        }
+        balances
+    }
+
+    /// Spot price of `out_record` token in `in_record` token, by the math of the pool type.
+    fn spot_price(&self, in_record: &Record, out_record: &Record, swap_fee: Balance) -> Balance {
+        match self.amp {
+            None => calc_spot_price(
+                in_record.balance,
+                in_record.denorm,
+                out_record.balance,
+                out_record.denorm,
+                swap_fee,
+            ),
+            Some(amp) => {
+                let price = stable_math::calc_spot_price(
+                    &self.balances_of(&[in_record, out_record]),
+                    amp,
+                    in_record.index as usize,
+                    out_record.index as usize,
+                    swap_fee,
+                );
+                // Price of whole units in whole units.
+                (U256::from(price) * U256::from(self.precision_multiplier(out_record))
+                    / U256::from(self.precision_multiplier(in_record)))
+                .as_u128()
+            }
+        }
+    }
+
+    fn out_given_in(
+        &self,
+        in_record: &Record,
+        out_record: &Record,
+        token_amount_in: Balance,
+    ) -> Balance {
+        match self.amp {
+            None => calc_out_given_in(
+                in_record.balance,
+                in_record.denorm,
+                out_record.balance,
+                out_record.denorm,
+                token_amount_in,
+                self.swap_fee(),
+            ),
+            Some(amp) => {
+                let amount_out = stable_math::calc_out_given_in(
+                    &self.balances_of(&[in_record, out_record]),
+                    amp,
+                    in_record.index as usize,
+                    out_record.index as usize,
+                    self.scale(in_record, token_amount_in),
+                    self.swap_fee(),
+                );
+                self.unscale(out_record, amount_out, false)
+            }
+        }
+    }
+
+    fn in_given_out(
+        &self,
+        in_record: &Record,
+        out_record: &Record,
+        token_amount_out: Balance,
+    ) -> Balance {
+        match self.amp {
+            None => calc_in_given_out(
+                in_record.balance,
+                in_record.denorm,
+                out_record.balance,
+                out_record.denorm,
+                token_amount_out,
+                self.swap_fee(),
+            ),
+            Some(amp) => {
+                let amount_in = stable_math::calc_in_given_out(
+                    &self.balances_of(&[in_record, out_record]),
+                    amp,
+                    in_record.index as usize,
+                    out_record.index as usize,
+                    self.scale(out_record, token_amount_out),
+                    self.swap_fee(),
+                );
+                self.unscale(in_record, amount_in, true)
+            }
+        }
+    }
+
+    fn pool_out_given_single_in(&self, in_record: &Record, token_amount_in: Balance) -> Balance {
+        match self.amp {
+            None => calc_pool_out_given_single_in(
+                in_record.balance,
+                in_record.denorm,
+                self.token.get_total_supply(),
+                self.total_weight,
+                token_amount_in,
+                self.swap_fee(),
+            ),
+            Some(amp) => stable_math::calc_pool_out_given_single_in(
+                &self.balances_of(&[in_record]),
+                amp,
+                in_record.index as usize,
+                self.token.get_total_supply(),
+                self.scale(in_record, token_amount_in),
+                self.swap_fee(),
+            ),
+        }
+    }
+
+    fn single_in_given_pool_out(&self, in_record: &Record, pool_amount_out: Balance) -> Balance {
+        match self.amp {
+            None => calc_single_in_given_pool_out(
+                in_record.balance,
+                in_record.denorm,
+                self.token.get_total_supply(),
+                self.total_weight,
+                pool_amount_out,
+                self.swap_fee(),
+            ),
+            Some(amp) => {
+                let amount_in = stable_math::calc_single_in_given_pool_out(
+                    &self.balances_of(&[in_record]),
+                    amp,
+                    in_record.index as usize,
+                    self.token.get_total_supply(),
+                    pool_amount_out,
+                    self.swap_fee(),
+                );
+                self.unscale(in_record, amount_in, true)
+            }
+        }
+    }
+
+    fn single_out_given_pool_in(&self, out_record: &Record, pool_amount_in: Balance) -> Balance {
+        match self.amp {
+            None => calc_single_out_given_pool_in(
+                out_record.balance,
+                out_record.denorm,
+                self.token.get_total_supply(),
+                self.total_weight,
+                pool_amount_in,
+                self.swap_fee(),
+            ),
+            Some(amp) => {
+                let amount_out = stable_math::calc_single_out_given_pool_in(
+                    &self.balances_of(&[out_record]),
+                    amp,
+                    out_record.index as usize,
+                    self.token.get_total_supply(),
+                    pool_amount_in,
+                    self.swap_fee(),
+                );
+                self.unscale(out_record, amount_out, false)
+            }
+        }
+    }
+
+    fn pool_in_given_single_out(&self, out_record: &Record, token_amount_out: Balance) -> Balance {
+        match self.amp {
+            None => calc_pool_in_given_single_out(
+                out_record.balance,
+                out_record.denorm,
+                self.token.get_total_supply(),
+                self.total_weight,
+                token_amount_out,
+                self.swap_fee(),
+            ),
+            Some(amp) => stable_math::calc_pool_in_given_single_out(
+                &self.balances_of(&[out_record]),
+                amp,
+                out_record.index as usize,
+                self.token.get_total_supply(),
+                self.scale(out_record, token_amount_out),
+                self.swap_fee(),
+            ),
+        }
+    }
+
+    /// Accumulates spot prices of tokens with balances in the first of them, before they
+    /// change.
+    fn update_oracle(&mut self) {
+        let (tokens, records): (Vec<AccountId>, Vec<Record>) = self
+            .tokens
+            .iter()
+            .map(|token| (token.clone(), self.records.get(token).unwrap()))
+            .filter(|(_, record)| record.balance > 0)
+            .unzip();
+        let prices = records
+            .iter()
+            .enumerate()
+            .map(|(i, record)| match i {
+                0 => BONE,
+                _ => self.spot_price(&records[0], record, 0),
+            })
+            .collect();
+        self.oracle.update(tokens, prices);
+    }
+
+    /// Event with the current balance and weight of `token`, after it was (re)bound.
+    fn bind_event(&self, caller: AccountId, token: AccountId) -> Event {
+        let record = self.records.get(&token).unwrap();
+        Event::Bind {
+            caller,
+            token,
+            balance: record.balance.into(),
+            denorm: record.denorm.into(),
+        }
+    }
+
+    /// Event for `amount` of `token` sent out to `account_id` by `op`.
+    fn out_event(
+        &self,
+        account_id: AccountId,
+        op: &PendingOp,
+        token: AccountId,
+        amount: Balance,
+    ) -> Event {
+        match op.kind {
+            OpKind::Swap => {
+                let (token_in, amount_in) = &op.amounts_in[0];
+                Event::Swap {
+                    caller: account_id,
+                    token_in: token_in.clone(),
+                    token_out: token,
+                    token_amount_in: (*amount_in).into(),
+                    token_amount_out: amount.into(),
+                }
+            }
+            OpKind::Exit => Event::Exit {
+                caller: account_id,
+                token_out: token,
+                token_amount_out: amount.into(),
+            },
+            OpKind::Bind => self.bind_event(account_id, token),
+            OpKind::Join => panic!("ERR_JOIN_HAS_NO_OUTPUTS"),
+        }
+    }
+
+    /// Whether `account_id` has deposits and they cover all of `amounts`.
+    fn has_deposits(&self, account_id: &AccountId, amounts: &[(AccountId, Balance)]) -> bool {
+        match self.deposits.get(account_id) {
+            Some(deposits) => amounts
+                .iter()
+                .all(|(token, amount)| deposits.get(token).cloned().unwrap_or(0) >= *amount),
+            None => false,
+        }
+    }
+
+    fn deposit(&mut self, account_id: &AccountId, token: &AccountId, amount: Balance) {
+        let mut deposits = self.deposits.get(account_id).unwrap_or_default();
+        let deposit = deposits.entry(token.clone()).or_insert(0);
+        *deposit = badd(*deposit, amount);
+        self.deposits.insert(account_id, &deposits);
+        let total_deposit = self.total_deposits.get(token).unwrap_or(0);
+        self.total_deposits
+            .insert(token, &badd(total_deposit, amount));
+    }
+
+    fn withdraw_deposit(&mut self, account_id: &AccountId, token: &AccountId, amount: Balance) {
+        let mut deposits = self.deposits.get(account_id).unwrap_or_default();
+        let deposit = *deposits.get(token).expect("ERR_INSUFFICIENT_DEPOSIT");
+        assert!(deposit >= amount, "ERR_INSUFFICIENT_DEPOSIT");
+        if deposit == amount {
+            deposits.remove(token);
+        } else {
+            deposits.insert(token.clone(), deposit - amount);
+        }
+        if deposits.is_empty() {
+            self.deposits.remove(account_id);
+        } else {
+            self.deposits.insert(account_id, &deposits);
+        }
+        let total_deposit = self.total_deposits.get(token).unwrap_or(0);
+        self.total_deposits
+            .insert(token, &bsub(total_deposit, amount));
+    }
+
+    fn add_claim(&mut self, account_id: &AccountId, token: &AccountId, amount: Balance) {
+        let mut claims = self.claims.get(account_id).unwrap_or_default();
+        let claim = claims.entry(token.clone()).or_insert(0);
+        *claim = badd(*claim, amount);
+        self.claims.insert(account_id, &claims);
+        let total_claim = self.total_claims.get(token).unwrap_or(0);
+        self.total_claims.insert(token, &badd(total_claim, amount));
+    }
+
+    /// Removes the claim of `account_id` in `token` and returns its amount.
+    fn withdraw_claim(&mut self, account_id: &AccountId, token: &AccountId) -> Balance {
+        let mut claims = self.claims.get(account_id).unwrap_or_default();
+        let amount = claims.remove(token).unwrap_or(0);
+        if claims.is_empty() {
+            self.claims.remove(account_id);
+        } else {
+            self.claims.insert(account_id, &claims);
+        }
+        let total_claim = self.total_claims.get(token).unwrap_or(0);
+        self.total_claims.insert(token, &bsub(total_claim, amount));
+        amount
+    }
+
+    fn pull_underlying(&mut self, token: &AccountId, from: &AccountId, amount: Balance) -> Promise {
+        ext_nep21::transfer_from(
+            from.clone(),
+            env::current_account_id(),
+            amount.into(),
+            token,
+            NO_DEPOSIT,
+            gas::NEP21_TRANSFER_FROM,
+        )
+    }
+
+    fn push_underlying(&mut self, token: AccountId, to: AccountId, amount: Balance) -> Promise {
+        if self.nep141_tokens.contains(&token) {
+            ext_nep141::ft_transfer(
+                to,
+                amount.into(),
+                None,
+                &token,
+                ONE_YOCTO,
+                gas::NEP21_TRANSFER,
+            )
+        } else {
+            ext_nep21::transfer(to, amount.into(), &token, NO_DEPOSIT, gas::NEP21_TRANSFER)
+        }
+    }
+
+    fn mint_pool_share(&mut self, amount: Balance) {
+        self.assert_below_cap(amount);
+        self.token.mint(env::current_account_id(), amount)
+    }
+
+    /// Mints shares worth the protocol's part of `swap_fee` charged in `token`, which balance
+    /// already includes it. Returns the minted amount.
+    fn mint_protocol_fee(&mut self, token: &AccountId, swap_fee: Balance) -> Balance {
+        let supply = self.token.get_total_supply();
+        if self.protocol_fee == 0 || supply == 0 {
+            return 0;
+        }
+        let fee = bmul(swap_fee, self.protocol_fee);
+        // Pool value in `token`, of which the fee becomes the share of new shares.
+        let record = self.records.get(token).unwrap();
+        let (fee, pool_value) = match self.amp {
+            None => (
+                fee,
+                bdiv(record.balance, bdiv(record.denorm, self.total_weight)),
+            ),
+            // Pegged tokens are worth the invariant together.
+            Some(amp) => (
+                self.scale(&record, fee),
+                stable_math::calc_d(&self.balances_of(&[]), amp),
+            ),
+        };
+        let amount = bdiv(bmul(supply, fee), bsub(pool_value, fee));
+        if amount > 0 {
+            // Not limited by the cap, which only applies to joins.
+            self.token.mint(env::current_account_id(), amount);
+            self.accrued_protocol_fee = badd(self.accrued_protocol_fee, amount);
+        }
+        amount
+    }
+
+    fn burn_pool_share(&mut self, amount: Balance) {
+        self.token.burn(env::current_account_id(), amount)
+    }
+
+    fn pull_pool_share(&mut self, from: AccountId, amount: Balance) {
+        self.token
+            .internal_transfer(from, env::current_account_id(), amount)
+    }
+
+    fn push_pool_share(&mut self, to: AccountId, amount: Balance) {
+        self.token
+            .internal_transfer(env::current_account_id(), to, amount)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_lib::token::Account;
+    use near_sdk::{testing_env, MockedBlockchain, VMContext};
+
+    fn pool_account() -> AccountId {
+        "pool".to_string()
+    }
+    fn factory_account() -> AccountId {
+        "factory".to_string()
+    }
+    fn token1_account() -> AccountId {
+        "token1".to_string()
+    }
+    fn token2_account() -> AccountId {
+        "token2".to_string()
+    }
+    fn alice_account() -> AccountId {
+        "alice".to_string()
+    }
+    fn bob_account() -> AccountId {
+        "bob".to_string()
+    }
+
+    pub fn get_context(
+        predecessor_account_id: AccountId,
+        account_balance: u128,
+        account_locked_balance: u128,
+        is_view: bool,
+    ) -> VMContext {
+        VMContext {
+            current_account_id: pool_account(),
+            signer_account_id: predecessor_account_id.clone(),
+            signer_account_pk: vec![0, 1, 2],
+            predecessor_account_id,
+            input: vec![],
+            block_index: 1,
+            block_timestamp: 0,
+            epoch_height: 1,
+            account_balance,
+            account_locked_balance,
+            storage_usage: 10u64.pow(6),
+            attached_deposit: 0,
+            prepaid_gas: 10u64.pow(15),
+            random_seed: vec![0, 1, 2],
+            is_view,
+            output_data_receivers: vec![],
+        }
+    }
+
+    fn to_yocto(amount: Balance) -> Balance {
+        amount * 10u128.pow(24)
+    }
+
+    /// Switches to the pool calling itself back with given promise results.
+    fn testing_env_with_promise_results(promise_results: Vec<PromiseResult>) {
+        let storage = env::take_blockchain_interface()
+            .unwrap()
+            .as_mut_mocked_blockchain()
+            .unwrap()
+            .take_storage();
+        env::set_blockchain_interface(Box::new(MockedBlockchain::new(
+            get_context(pool_account(), to_yocto(10), 0, false),
+            Default::default(),
+            Default::default(),
+            promise_results,
+            storage,
+            Default::default(),
+        )));
+    }
+
+    /// Switches to the pool calling itself back with given transfer outcomes.
+    fn testing_env_with_transfer_results(results: Vec<bool>) {
+        testing_env_with_promise_results(
+            results
+                .into_iter()
+                .map(|success| {
+                    if success {
+                        PromiseResult::Successful(vec![])
+                    } else {
+                        PromiseResult::Failed
+                    }
+                })
+                .collect(),
+        );
+    }
+
+    fn resolve_pull(pool: &mut BPool, account_id: AccountId, results: Vec<bool>) -> bool {
+        testing_env_with_transfer_results(results);
+        let result = pool.on_pull(account_id.clone());
+        testing_env!(get_context(account_id, to_yocto(10), 0, false));
+        result
+    }
+
+    fn resolve_push(pool: &mut BPool, account_id: AccountId, results: Vec<bool>) -> bool {
+        testing_env_with_transfer_results(results);
+        let result = pool.on_push(account_id.clone());
+        testing_env!(get_context(account_id, to_yocto(10), 0, false));
+        result
+    }
+
+    /// Pool with 6 token1 at weight 5 and 1200 token2 at weight 10.
+    fn setup_pool(finalize: bool) -> BPool {
+        testing_env!(get_context(factory_account(), to_yocto(10), 0, false));
+        let mut pool = BPool::new(None, None);
+        pool.bind(token1_account(), to_yocto(6).into(), to_yocto(5).into());
+        resolve_pull(&mut pool, factory_account(), vec![true]);
+        pool.bind(
+            token2_account(),
+            to_yocto(1_200).into(),
+            to_yocto(10).into(),
+        );
+        resolve_pull(&mut pool, factory_account(), vec![true]);
+        if finalize {
+            pool.finalize();
+        } else {
+            pool.setPublicSwap(true);
+        }
+        pool
+    }
+
+    #[test]
+    fn test_setup_pool() {
+        let context = get_context(factory_account(), to_yocto(10), 0, false);
+        testing_env!(context.clone());
+        let mut pool = BPool::new(None, None);
+        assert_eq!(pool.getController(), factory_account());
+        pool.bind(
+            token1_account(),
+            to_yocto(50_000).into(),
+            to_yocto(10).into(),
+        );
+        resolve_pull(&mut pool, factory_account(), vec![true]);
+        pool.bind(
+            token2_account(),
+            to_yocto(1_000_000).into(),
+            to_yocto(10).into(),
+        );
+        resolve_pull(&mut pool, factory_account(), vec![true]);
+        pool.finalize();
+        assert_eq!(
+            pool.getNormalizedWeight(token1_account()),
+            (BONE / 2).into()
+        );
+        assert_eq!(
+            pool.getSpotPriceSansFee(token2_account(), token1_account()),
+            (20 * BONE).into()
+        );
+        assert_eq!(
+            pool.getSpotPriceSansFee(token1_account(), token2_account()),
+            (BONE / 20).into()
+        );
+        assert_eq!(
+            pool.getSpotPrice(token2_account(), token1_account()),
+            20_000_020_000_020_000_020_000_020u128.into()
+        );
+        assert_eq!(pool.get_balance(factory_account()), INIT_POOL_SUPPLY.into());
+    }
+
+    #[test]
+    fn test_metadata() {
+        let pool = setup_pool(false);
+        let metadata = pool.ft_metadata();
+        assert_eq!(metadata.name, POOL_NAME);
+        assert_eq!(metadata.symbol, "BPT-TOKEN1-TOKEN2");
+        assert_eq!(metadata.decimals, 24);
+
+        // Another pool needs empty storage.
+        env::take_blockchain_interface();
+        testing_env!(get_context(factory_account(), to_yocto(10), 0, false));
+        let pool = BPool::new(Some("My Pool".to_string()), Some("MP".to_string()));
+        let metadata = pool.ft_metadata();
+        assert_eq!(metadata.name, "My Pool");
+        assert_eq!(metadata.symbol, "MP");
+    }
+
+    #[test]
+    #[should_panic(expected = "Symbol should not be empty")]
+    fn test_empty_symbol() {
+        testing_env!(get_context(factory_account(), to_yocto(10), 0, false));
+        BPool::new(None, Some("".to_string()));
+    }
+
+    #[test]
+    fn test_bind_failed() {
+        testing_env!(get_context(factory_account(), to_yocto(10), 0, false));
+        let mut pool = BPool::new(None, None);
+        pool.bind(token1_account(), to_yocto(6).into(), to_yocto(5).into());
+        assert!(!resolve_pull(&mut pool, factory_account(), vec![false]));
+        assert!(!pool.isBound(token1_account()));
+        assert_eq!(pool.getNumTokens(), 0);
+        assert_eq!(pool.getTotalDenormalizedWeight(), 0.into());
+    }
+
+    #[test]
+    fn test_rebind_failed() {
+        let mut pool = setup_pool(false);
+        pool.rebind(token1_account(), to_yocto(10), to_yocto(8));
+        assert!(!resolve_pull(&mut pool, factory_account(), vec![false]));
+        assert_eq!(
+            pool.getDenormalizedWeight(token1_account()),
+            to_yocto(5).into()
+        );
+        assert_eq!(pool.getTotalDenormalizedWeight(), to_yocto(15).into());
+        pool.rebind(token1_account(), to_yocto(4), to_yocto(8));
+        assert!(!resolve_push(&mut pool, factory_account(), vec![false]));
+        assert_eq!(
+            pool.getDenormalizedWeight(token1_account()),
+            to_yocto(5).into()
+        );
+        pool.rebind(token1_account(), to_yocto(4), to_yocto(8));
+        assert!(resolve_push(&mut pool, factory_account(), vec![true]));
+        assert_eq!(
+            pool.getDenormalizedWeight(token1_account()),
+            to_yocto(8).into()
+        );
+        assert_eq!(pool.getTotalDenormalizedWeight(), to_yocto(18).into());
+    }
+
+    #[test]
+    fn test_rebind_delta() {
+        let mut pool = setup_pool(false);
+        match pool.rebindDelta(token1_account(), 0.into(), to_yocto(2).into(), None) {
+            PromiseOrValue::Promise(_) => {}
+            PromiseOrValue::Value(_) => panic!("Expected the push to resolve the rebind"),
+        }
+        assert!(!resolve_push(&mut pool, factory_account(), vec![false]));
+        assert_eq!(pool.getBalance(token1_account()), to_yocto(6).into());
+        pool.rebindDelta(token1_account(), 0.into(), to_yocto(2).into(), None);
+        assert!(resolve_push(&mut pool, factory_account(), vec![true]));
+        assert_eq!(pool.getBalance(token1_account()), to_yocto(4).into());
+        assert_eq!(
+            pool.getDenormalizedWeight(token1_account()),
+            to_yocto(5).into()
+        );
+        match pool.rebindDelta(
+            token2_account(),
+            0.into(),
+            0.into(),
+            Some(to_yocto(20).into()),
+        ) {
+            PromiseOrValue::Value(success) => assert!(success),
+            PromiseOrValue::Promise(_) => panic!("Expected nothing to transfer"),
+        }
+        assert_eq!(pool.getBalance(token2_account()), to_yocto(1_200).into());
+        assert_eq!(pool.getTotalDenormalizedWeight(), to_yocto(25).into());
+    }
+
+    #[test]
+    fn test_rebind_proportional() {
+        let mut pool = setup_pool(false);
+        for (token, amount) in vec![(token1_account(), 4), (token2_account(), 600)] {
+            testing_env!(get_context(token, to_yocto(10), 0, false));
+            pool.ft_on_transfer(
+                factory_account(),
+                to_yocto(amount).into(),
+                r#""deposit""#.to_string(),
+            );
+        }
+        testing_env!(get_context(factory_account(), to_yocto(10), 0, false));
+        let amounts = pool.rebindProportional(
+            (BONE / 2).into(),
+            true,
+            vec![to_yocto(3).into(), to_yocto(600).into()],
+        );
+        assert_eq!(
+            amounts,
+            vec![
+                (token1_account(), to_yocto(3).into()),
+                (token2_account(), to_yocto(600).into())
+            ]
+        );
+        assert_eq!(pool.getBalance(token1_account()), to_yocto(9).into());
+        assert_eq!(pool.getBalance(token2_account()), to_yocto(1_800).into());
+        assert_eq!(
+            pool.getDeposit(factory_account(), token1_account()),
+            to_yocto(1).into()
+        );
+        assert_eq!(
+            pool.getDeposit(factory_account(), token2_account()),
+            0.into()
+        );
+        assert_eq!(
+            pool.getDenormalizedWeight(token1_account()),
+            to_yocto(5).into()
+        );
+
+        pool.rebindProportional((BONE / 10).into(), false, vec![0.into(), 0.into()]);
+        assert_eq!(
+            pool.getBalance(token1_account()),
+            (to_yocto(81) / 10).into()
+        );
+        assert_eq!(pool.getBalance(token2_account()), to_yocto(1_620).into());
+        assert_eq!(
+            pool.getDeposit(factory_account(), token1_account()),
+            (to_yocto(19) / 10).into()
+        );
+        assert_eq!(
+            pool.getDeposit(factory_account(), token2_account()),
+            to_yocto(180).into()
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_INSUFFICIENT_DEPOSIT")]
+    fn test_rebind_proportional_no_deposit() {
+        let mut pool = setup_pool(false);
+        pool.rebindProportional(
+            (BONE / 2).into(),
+            true,
+            vec![to_yocto(3).into(), to_yocto(600).into()],
+        );
+    }
+
+    fn resolve_unbind(
+        pool: &mut BPool,
+        token: AccountId,
+        amount: Balance,
+        results: Vec<bool>,
+    ) -> bool {
+        testing_env_with_transfer_results(results);
+        let result = pool.on_unbind(token, amount.into());
+        testing_env!(get_context(factory_account(), to_yocto(10), 0, false));
+        result.0 > 0
+    }
+
+    #[test]
+    fn test_unbind() {
+        let mut pool = setup_pool(false);
+        pool.unbind(token1_account());
+        assert!(resolve_unbind(
+            &mut pool,
+            token1_account(),
+            to_yocto(6),
+            vec![true]
+        ));
+        assert!(!pool.isBound(token1_account()));
+        assert_eq!(pool.getCurrentTokens(), vec![token2_account()]);
+        assert_eq!(pool.getTotalDenormalizedWeight(), to_yocto(10).into());
+        assert_eq!(pool.getNormalizedWeight(token2_account()), BONE.into());
+    }
+
+    #[test]
+    fn test_unbind_failed() {
+        let mut pool = setup_pool(false);
+        pool.unbind(token1_account());
+        assert!(!resolve_unbind(
+            &mut pool,
+            token1_account(),
+            to_yocto(6),
+            vec![false]
+        ));
+        assert!(pool.isBound(token1_account()));
+        assert_eq!(pool.getBalance(token1_account()), to_yocto(6).into());
+        assert_eq!(pool.getTotalDenormalizedWeight(), to_yocto(15).into());
+    }
+
+    #[test]
+    fn test_gulp() {
+        let mut pool = setup_pool(false);
+        pool.gulp(token1_account());
+        testing_env_with_promise_results(vec![PromiseResult::Successful(
+            serde_json::to_vec(&U128(to_yocto(7))).unwrap(),
+        )]);
+        assert_eq!(
+            pool.on_gulp(token1_account(), pool.lock_nonce),
+            to_yocto(7).into()
+        );
+        assert_eq!(pool.getBalance(token1_account()), to_yocto(7).into());
+    }
+
+    #[test]
+    fn test_gulp_during_op() {
+        let mut pool = setup_pool(false);
+        pool.gulp(token1_account());
+        let lock_nonce = pool.lock_nonce;
+        swap_token1(&mut pool);
+        testing_env_with_promise_results(vec![PromiseResult::Successful(
+            serde_json::to_vec(&U128(to_yocto(7))).unwrap(),
+        )]);
+        // Balance reported before the swap is outdated.
+        assert_eq!(
+            pool.on_gulp(token1_account(), lock_nonce),
+            to_yocto(7).into()
+        );
+        assert!(resolve_push(&mut pool, factory_account(), vec![true]));
+        assert_eq!(pool.getBalance(token1_account()), to_yocto(7).into());
+    }
+
+    #[test]
+    fn test_ft_transfer_call() {
+        let mut pool = setup_pool(true);
+        let mut context = get_context(alice_account(), to_yocto(10), 0, false);
+        context.attached_deposit = to_yocto(1);
+        testing_env!(context);
+        pool.storage_deposit(None, None);
+        let mut context = get_context(factory_account(), to_yocto(10), 0, false);
+        context.attached_deposit = 1;
+        testing_env!(context);
+        pool.ft_transfer_call(alice_account(), to_yocto(10).into(), None, "".to_string());
+        assert_eq!(pool.ft_balance_of(alice_account()), to_yocto(10).into());
+        testing_env_with_promise_results(vec![PromiseResult::Successful(
+            serde_json::to_vec(&U128(to_yocto(4))).unwrap(),
+        )]);
+        assert_eq!(
+            pool.ft_resolve_transfer(factory_account(), alice_account(), to_yocto(10).into()),
+            to_yocto(6).into()
+        );
+        assert_eq!(pool.ft_balance_of(alice_account()), to_yocto(6).into());
+        assert_eq!(pool.ft_balance_of(factory_account()), to_yocto(94).into());
+        assert_eq!(pool.ft_total_supply(), INIT_POOL_SUPPLY.into());
+    }
+
+    fn ft_on_transfer(pool: &mut BPool, token: AccountId, amount: Balance, msg: &str) -> U128 {
+        testing_env!(get_context(token, to_yocto(10), 0, false));
+        let unused = match pool.ft_on_transfer(alice_account(), amount.into(), msg.to_string()) {
+            PromiseOrValue::Value(unused) => unused,
+            PromiseOrValue::Promise(_) => panic!("Expected value"),
+        };
+        testing_env!(get_context(alice_account(), to_yocto(10), 0, false));
+        unused
+    }
+
+    #[test]
+    fn test_ft_on_transfer_swap() {
+        let mut pool = setup_pool(false);
+        let msg = format!(
+            r#"{{"swap": {{"token_out": "{}", "min_amount_out": "0", "max_price": "{}"}}}}"#,
+            token2_account(),
+            to_yocto(100)
+        );
+        let unused = ft_on_transfer(&mut pool, token1_account(), to_yocto(1), &msg);
+        assert_eq!(unused, 0.into());
+        assert!(resolve_push(&mut pool, alice_account(), vec![true]));
+        assert_eq!(pool.getBalance(token1_account()), to_yocto(7).into());
+        assert_eq!(
+            pool.getBalance(token2_account()).0 / 10u128.pow(18),
+            1_110_984_199
+        );
+    }
+
+    #[test]
+    fn test_ft_on_transfer_join_single() {
+        let mut pool = setup_pool(true);
+        let msg = r#"{"join_single": {"min_pool_amount_out": "0"}}"#;
+        register(&mut pool, alice_account());
+        ft_on_transfer(&mut pool, token1_account(), to_yocto(1), msg);
+        assert_eq!(
+            pool.get_balance(alice_account()).0 / 10u128.pow(15),
+            5_272_656_619
+        );
+        assert_eq!(pool.getBalance(token1_account()), to_yocto(7).into());
+    }
+
+    fn register(pool: &mut BPool, account_id: AccountId) {
+        let mut context = get_context(account_id.clone(), to_yocto(10), 0, false);
+        context.attached_deposit = to_yocto(1);
+        testing_env!(context);
+        pool.storage_deposit(None, None);
+        testing_env!(get_context(account_id, to_yocto(10), 0, false));
+    }
+
+    /// Methods called by the receipts created in the current context.
+    fn created_calls() -> Vec<String> {
+        let mut blockchain = env::take_blockchain_interface().unwrap();
+        // Deposits don't fit into JSON values as numbers.
+        let receipts = serde_json::to_string(
+            blockchain
+                .as_mut_mocked_blockchain()
+                .unwrap()
+                .created_receipts(),
+        )
+        .unwrap();
+        let receipts: serde_json::Value = serde_json::from_str(&receipts).unwrap();
+        env::set_blockchain_interface(blockchain);
+        receipts
+            .as_array()
+            .unwrap()
+            .iter()
+            .flat_map(|receipt| receipt["actions"].as_array().unwrap().clone())
+            .filter_map(|action| {
+                action["FunctionCall"]["method_name"]
+                    .as_str()
+                    .map(|method| method.to_string())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_ft_on_transfer_swap_nep141_out() {
+        let mut pool = setup_pool(false);
+        ft_on_transfer(
+            &mut pool,
+            token2_account(),
+            to_yocto(10),
+            &format!(
+                r#"{{"swap": {{"token_out": "{}", "min_amount_out": "0", "max_price": "{}"}}}}"#,
+                token1_account(),
+                to_yocto(1_000)
+            ),
+        );
+        assert!(resolve_push(&mut pool, alice_account(), vec![true]));
+        // Token2 is known to support NEP-141 now.
+        testing_env!(get_context(token1_account(), to_yocto(10), 0, false));
+        pool.ft_on_transfer(
+            alice_account(),
+            to_yocto(1).into(),
+            format!(
+                r#"{{"swap": {{"token_out": "{}", "min_amount_out": "0", "max_price": "{}"}}}}"#,
+                token2_account(),
+                to_yocto(100)
+            ),
+        );
+        assert_eq!(created_calls(), vec!["ft_transfer", "on_push"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_REGISTERED")]
+    fn test_deposit_not_registered() {
+        let mut pool = setup_pool(false);
+        ft_on_transfer(&mut pool, token1_account(), to_yocto(1), r#""deposit""#);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_HAS_DEPOSITS")]
+    fn test_storage_unregister_with_deposits() {
+        let mut pool = setup_pool(false);
+        register(&mut pool, alice_account());
+        ft_on_transfer(&mut pool, token1_account(), to_yocto(1), r#""deposit""#);
+        let mut context = get_context(alice_account(), to_yocto(10), 0, false);
+        context.attached_deposit = 1;
+        testing_env!(context);
+        pool.storage_unregister(None);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_HAS_CLAIMS")]
+    fn test_storage_unregister_with_claims() {
+        let mut pool = setup_pool(true);
+        register(&mut pool, alice_account());
+        pool.swapExactAmountIn(
+            token1_account(),
+            to_yocto(1).into(),
+            token2_account(),
+            0.into(),
+            to_yocto(100).into(),
+        );
+        assert!(resolve_pull(&mut pool, alice_account(), vec![true]));
+        assert!(!resolve_push(&mut pool, alice_account(), vec![false]));
+        let mut context = get_context(alice_account(), to_yocto(10), 0, false);
+        context.attached_deposit = 1;
+        testing_env!(context);
+        pool.storage_unregister(None);
+    }
+
+    #[test]
+    fn test_deposit_withdraw() {
+        let mut pool = setup_pool(false);
+        register(&mut pool, alice_account());
+        ft_on_transfer(&mut pool, token1_account(), to_yocto(2), r#""deposit""#);
+        assert_eq!(
+            pool.getDeposit(alice_account(), token1_account()),
+            to_yocto(2).into()
+        );
+        assert_eq!(pool.getBalance(token1_account()), to_yocto(6).into());
+
+        pool.withdraw(token1_account(), to_yocto(1).into());
+        testing_env_with_transfer_results(vec![false]);
+        assert!(!pool.on_withdraw(alice_account(), token1_account(), to_yocto(1).into()));
+        assert_eq!(
+            pool.getDeposit(alice_account(), token1_account()),
+            to_yocto(2).into()
+        );
+
+        testing_env!(get_context(alice_account(), to_yocto(10), 0, false));
+        pool.withdraw(token1_account(), to_yocto(2).into());
+        testing_env_with_transfer_results(vec![true]);
+        assert!(pool.on_withdraw(alice_account(), token1_account(), to_yocto(2).into()));
+        assert_eq!(pool.getDeposit(alice_account(), token1_account()), 0.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_ZERO_AMOUNT")]
+    fn test_withdraw_zero() {
+        let mut pool = setup_pool(false);
+        register(&mut pool, alice_account());
+        ft_on_transfer(&mut pool, token1_account(), to_yocto(2), r#""deposit""#);
+        pool.withdraw(token1_account(), 0.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_INSUFFICIENT_DEPOSIT")]
+    fn test_withdraw_unknown_token() {
+        let mut pool = setup_pool(false);
+        register(&mut pool, alice_account());
+        ft_on_transfer(&mut pool, token1_account(), to_yocto(2), r#""deposit""#);
+        pool.withdraw("token3".to_string(), 1.into());
+    }
+
+    #[test]
+    fn test_swap_from_deposit() {
+        let mut pool = setup_pool(false);
+        register(&mut pool, alice_account());
+        pool.setDepositOutputs(true);
+        ft_on_transfer(&mut pool, token1_account(), to_yocto(2), r#""deposit""#);
+        let (amount_out, _) = pool.swapExactAmountIn(
+            token1_account(),
+            to_yocto(1).into(),
+            token2_account(),
+            0.into(),
+            to_yocto(100).into(),
+        );
+        // Settled without any callbacks.
+        assert_eq!(pool.getBalance(token1_account()), to_yocto(7).into());
+        assert_eq!(
+            pool.getBalance(token2_account()),
+            (to_yocto(1_200) - amount_out.0).into()
+        );
+        assert_eq!(
+            pool.getDeposit(alice_account(), token1_account()),
+            to_yocto(1).into()
+        );
+        assert_eq!(
+            pool.getDeposit(alice_account(), token2_account()),
+            amount_out
+        );
+    }
+
+    #[test]
+    fn test_join_exit_from_deposit() {
+        let mut pool = setup_pool(true);
+        register(&mut pool, alice_account());
+        ft_on_transfer(&mut pool, token1_account(), to_yocto(1), r#""deposit""#);
+        let pool_amount_out =
+            pool.joinswapExternAmountIn(token1_account(), to_yocto(1).into(), 0.into());
+        assert_eq!(pool.get_balance(alice_account()), pool_amount_out);
+        assert_eq!(pool.getDeposit(alice_account(), token1_account()), 0.into());
+
+        // Without deposits the tokens are transferred again.
+        pool.exitswapPoolAmountIn(token1_account(), pool_amount_out, 0.into());
+        assert!(resolve_push(&mut pool, alice_account(), vec![true]));
+        assert_eq!(pool.get_balance(alice_account()), 0.into());
+
+        // Exits don't use deposits unless the account opted in.
+        ft_on_transfer(&mut pool, token2_account(), to_yocto(1), r#""deposit""#);
+        pool.joinswapExternAmountIn(token1_account(), to_yocto(1).into(), 0.into());
+        assert!(resolve_pull(&mut pool, alice_account(), vec![true]));
+        let pool_amount_in = pool.get_balance(alice_account()).0 / 2;
+        pool.exitswapPoolAmountIn(token2_account(), pool_amount_in.into(), 0.into());
+        assert!(resolve_push(&mut pool, alice_account(), vec![true]));
+        assert_eq!(
+            pool.getDeposit(alice_account(), token2_account()),
+            to_yocto(1).into()
+        );
+
+        pool.setDepositOutputs(true);
+        let token_amount_out =
+            pool.exitswapPoolAmountIn(token2_account(), pool_amount_in.into(), 0.into());
+        assert_eq!(
+            pool.getDeposit(alice_account(), token2_account()),
+            (to_yocto(1) + token_amount_out.0).into()
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_REGISTERED")]
+    fn test_deposit_outputs_not_registered() {
+        let mut pool = setup_pool(true);
+        testing_env!(get_context(alice_account(), to_yocto(10), 0, false));
+        pool.setDepositOutputs(true);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_WRONG_MSG")]
+    fn test_ft_on_transfer_wrong_msg() {
+        let mut pool = setup_pool(false);
+        ft_on_transfer(&mut pool, token1_account(), to_yocto(1), "{}");
+    }
+
+    #[test]
+    fn test_storage_management() {
+        let mut pool = setup_pool(true);
+        let min_balance = pool.storage_balance_bounds().min;
+        assert!(min_balance.0 > 0);
+        assert_eq!(pool.storage_balance_of(alice_account()), None);
+
+        let mut context = get_context(factory_account(), to_yocto(10), 0, false);
+        context.attached_deposit = to_yocto(1);
+        testing_env!(context);
+        let storage_balance = pool.storage_deposit(Some(alice_account()), None);
+        assert_eq!(storage_balance.total, min_balance);
+        assert_eq!(
+            pool.storage_balance_of(alice_account()),
+            Some(storage_balance)
+        );
+
+        let mut context = get_context(alice_account(), to_yocto(10), 0, false);
+        context.attached_deposit = 1;
+        testing_env!(context);
+        assert!(pool.storage_unregister(None));
+        assert!(!pool.storage_unregister(None));
+        assert_eq!(pool.storage_balance_of(alice_account()), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "positive balance without force")]
+    fn test_storage_unregister_with_balance() {
+        let mut pool = setup_pool(true);
+        let mut context = get_context(factory_account(), to_yocto(10), 0, false);
+        context.attached_deposit = to_yocto(1);
+        testing_env!(context.clone());
+        pool.storage_deposit(None, None);
+        context.attached_deposit = 1;
+        testing_env!(context);
+        pool.storage_unregister(None);
+    }
+
+    #[test]
+    fn test_swap_exact_amount_in() {
+        let context = get_context(factory_account(), to_yocto(10), 0, false);
+        testing_env!(context.clone());
+        let mut pool = BPool::new(None, None);
+        pool.bind(token1_account(), to_yocto(6).into(), to_yocto(5).into());
+        resolve_pull(&mut pool, factory_account(), vec![true]);
+        pool.bind(
+            token2_account(),
+            to_yocto(1_200).into(),
+            to_yocto(10).into(),
+        );
+        resolve_pull(&mut pool, factory_account(), vec![true]);
+        pool.setPublicSwap(true);
+        let (amount_out, _) = pool.swapExactAmountIn(
+            token1_account(),
+            to_yocto(1).into(),
+            token2_account(),
+            to_yocto(40).into(),
+            to_yocto(100).into(),
+        );
+        assert_eq!(amount_out.0 / 10u128.pow(15), 89_015_800_911);
+        assert!(resolve_pull(&mut pool, factory_account(), vec![true]));
+        assert!(resolve_push(&mut pool, factory_account(), vec![true]));
+        assert_eq!(pool.getBalance(token1_account()), to_yocto(7).into());
+        assert_eq!(
+            pool.getBalance(token2_account()),
+            (to_yocto(1_200) - amount_out.0).into()
+        );
+    }
+
+    #[test]
+    fn test_swap_exact_amount_out() {
+        let context = get_context(factory_account(), to_yocto(10), 0, false);
+        testing_env!(context.clone());
+        let mut pool = BPool::new(None, None);
+        pool.bind(token1_account(), to_yocto(6).into(), to_yocto(5).into());
+        resolve_pull(&mut pool, factory_account(), vec![true]);
+        pool.bind(
+            token2_account(),
+            to_yocto(1_200).into(),
+            to_yocto(10).into(),
+        );
+        resolve_pull(&mut pool, factory_account(), vec![true]);
+        pool.setPublicSwap(true);
+        let (amount_in, _) = pool.swapExactAmountOut(
+            token1_account(),
+            to_yocto(2).into(),
+            token2_account(),
+            to_yocto(100).into(),
+            to_yocto(100).into(),
+        );
+        assert_eq!(amount_in.0 / 10u128.pow(12), 1_140_497_008_265);
+        assert!(resolve_pull(&mut pool, factory_account(), vec![true]));
+        assert!(resolve_push(&mut pool, factory_account(), vec![true]));
+        assert_eq!(
+            pool.getBalance(token1_account()),
+            (to_yocto(6) + amount_in.0).into()
+        );
+        assert_eq!(pool.getBalance(token2_account()), to_yocto(1_100).into());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_REENTRY")]
+    fn test_swap_reentry() {
+        let mut pool = setup_pool(false);
+        pool.swapExactAmountIn(
+            token1_account(),
+            to_yocto(1).into(),
+            token2_account(),
+            0.into(),
+            to_yocto(100).into(),
+        );
+        testing_env!(get_context(alice_account(), to_yocto(10), 0, false));
+        pool.swapExactAmountIn(
+            token1_account(),
+            to_yocto(1).into(),
+            token2_account(),
+            0.into(),
+            to_yocto(100).into(),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_REENTRY")]
+    fn test_view_reentry() {
+        let mut pool = setup_pool(false);
+        pool.swapExactAmountIn(
+            token1_account(),
+            to_yocto(1).into(),
+            token2_account(),
+            0.into(),
+            to_yocto(100).into(),
+        );
+        assert!(resolve_pull(&mut pool, factory_account(), vec![true]));
+        pool.getSpotPrice(token1_account(), token2_account());
+    }
+
+    #[test]
+    fn test_swap_push_failed() {
+        let mut pool = setup_pool(false);
+        pool.swapExactAmountIn(
+            token1_account(),
+            to_yocto(1).into(),
+            token2_account(),
+            0.into(),
+            to_yocto(100).into(),
+        );
+        assert!(resolve_pull(&mut pool, factory_account(), vec![true]));
+        assert!(!resolve_push(&mut pool, factory_account(), vec![false]));
+        assert_eq!(pool.getBalance(token1_account()), to_yocto(6).into());
+        assert_eq!(pool.getBalance(token2_account()), to_yocto(1_200).into());
+        assert_eq!(
+            pool.getClaim(factory_account(), token1_account()),
+            to_yocto(1).into()
+        );
+    }
+
+    #[test]
+    fn test_claim() {
+        let mut pool = setup_pool(true);
+        register(&mut pool, alice_account());
+        pool.joinPool(to_yocto(10), vec![to_yocto(1), to_yocto(120)]);
+        assert!(!resolve_pull(&mut pool, alice_account(), vec![true, false]));
+        let amount = pool.getClaim(alice_account(), token1_account());
+        assert_eq!(amount, (to_yocto(6) / 10).into());
+
+        pool.claim(token1_account());
+        testing_env_with_transfer_results(vec![false]);
+        assert!(!pool.on_claim(alice_account(), token1_account(), amount));
+        assert_eq!(pool.getClaim(alice_account(), token1_account()), amount);
+
+        testing_env!(get_context(alice_account(), to_yocto(10), 0, false));
+        pool.claim(token1_account());
+        testing_env_with_transfer_results(vec![true]);
+        assert!(pool.on_claim(alice_account(), token1_account(), amount));
+        assert_eq!(pool.getClaim(alice_account(), token1_account()), 0.into());
+    }
+
+    #[test]
+    fn test_gulp_excludes_claims() {
+        let mut pool = setup_pool(false);
+        pool.swapExactAmountIn(
+            token1_account(),
+            to_yocto(1).into(),
+            token2_account(),
+            0.into(),
+            to_yocto(100).into(),
+        );
+        assert!(resolve_pull(&mut pool, factory_account(), vec![true]));
+        assert!(!resolve_push(&mut pool, factory_account(), vec![false]));
+        pool.gulp(token1_account());
+        testing_env_with_promise_results(vec![PromiseResult::Successful(
+            serde_json::to_vec(&U128(to_yocto(7))).unwrap(),
+        )]);
+        assert_eq!(
+            pool.on_gulp(token1_account(), pool.lock_nonce),
+            to_yocto(6).into()
+        );
+    }
+
+    #[test]
+    fn test_swap_events() {
+        let mut pool = setup_pool(false);
+        events::take_logs();
+        let (amount_out, _) = pool.swapExactAmountIn(
+            token1_account(),
+            to_yocto(1).into(),
+            token2_account(),
+            0.into(),
+            to_yocto(100).into(),
+        );
+        assert!(resolve_pull(&mut pool, factory_account(), vec![true]));
+        // Logged only once the tokens are sent out.
+        assert!(events::take_logs().is_empty());
+        assert!(resolve_push(&mut pool, factory_account(), vec![true]));
+        assert_eq!(
+            events::take_logs(),
+            vec![format!(
+                r#"{{"version":"1.0.0","event":"LOG_SWAP","data":{{"caller":"factory","token_in":"token1","token_out":"token2","token_amount_in":"{}","token_amount_out":"{}"}}}}"#,
+                to_yocto(1),
+                amount_out.0
+            )]
+        );
+
+        swap_token1(&mut pool);
+        assert!(!resolve_push(&mut pool, factory_account(), vec![false]));
+        assert!(events::take_logs().is_empty());
+    }
+
+    #[test]
+    fn test_join_exit_events() {
+        let mut pool = setup_pool(true);
+        events::take_logs();
+        pool.exitPool(to_yocto(10), vec![0, 0]);
+        assert!(!resolve_push(
+            &mut pool,
+            factory_account(),
+            vec![false, true]
+        ));
+        // Only the token that was sent out is logged.
+        assert_eq!(
+            events::take_logs(),
+            vec![format!(
+                r#"{{"version":"1.0.0","event":"LOG_EXIT","data":{{"caller":"factory","token_out":"token2","token_amount_out":"{}"}}}}"#,
+                to_yocto(120)
+            )]
+        );
+
+        pool.joinswapExternAmountIn(token1_account(), to_yocto(1).into(), 0.into());
+        assert!(resolve_pull(&mut pool, factory_account(), vec![true]));
+        assert_eq!(
+            events::take_logs(),
+            vec![format!(
+                r#"{{"version":"1.0.0","event":"LOG_JOIN","data":{{"caller":"factory","token_in":"token1","token_amount_in":"{}"}}}}"#,
+                to_yocto(1)
+            )]
+        );
+    }
+
+    #[test]
+    fn test_bind_events() {
+        testing_env!(get_context(factory_account(), to_yocto(10), 0, false));
+        let mut pool = BPool::new(None, None);
+        pool.bind(token1_account(), to_yocto(6).into(), to_yocto(5).into());
+        assert!(events::take_logs().is_empty());
+        resolve_pull(&mut pool, factory_account(), vec![true]);
+        assert_eq!(
+            events::take_logs(),
+            vec![format!(
+                r#"{{"version":"1.0.0","event":"LOG_BIND","data":{{"caller":"factory","token":"token1","balance":"{}","denorm":"{}"}}}}"#,
+                to_yocto(6),
+                to_yocto(5)
+            )]
+        );
+    }
+
+    fn swap_token1(pool: &mut BPool) {
+        pool.swapExactAmountIn(
+            token1_account(),
+            to_yocto(1).into(),
+            token2_account(),
+            0.into(),
+            to_yocto(100).into(),
+        );
+        assert!(resolve_pull(pool, factory_account(), vec![true]));
+    }
+
+    #[test]
+    fn test_protocol_fee() {
+        let mut pool = setup_pool(true);
+        pool.setProtocolFee((BONE / 2).into());
+        swap_token1(&mut pool);
+        assert!(resolve_push(&mut pool, factory_account(), vec![true]));
+        // Half of the swap fee on 1 token1, out of 21 token1 of pool value.
+        let accrued = pool.getAccruedProtocolFee().0;
+        assert_eq!(accrued / 10u128.pow(12), 2_380_952);
+        assert_eq!(pool.ft_balance_of(pool_account()).0, accrued);
+        assert_eq!(pool.ft_total_supply().0, to_yocto(100) + accrued);
+
+        assert_eq!(pool.collectProtocolFee().0, accrued);
+        assert_eq!(pool.getAccruedProtocolFee().0, 0);
+        assert_eq!(pool.getCollectedProtocolFee().0, accrued);
+        assert_eq!(
+            pool.ft_balance_of(factory_account()).0,
+            to_yocto(100) + accrued
+        );
+    }
+
+    #[test]
+    fn test_protocol_fee_joinswap_exitswap() {
+        let mut pool = setup_pool(true);
+        pool.setProtocolFee((BONE / 2).into());
+        register(&mut pool, alice_account());
+        pool.joinswapExternAmountIn(token1_account(), to_yocto(1).into(), 0.into());
+        assert!(resolve_pull(&mut pool, alice_account(), vec![true]));
+        // Half of the swap fee on the two thirds of 1 token1 beyond its weight.
+        let accrued = pool.getAccruedProtocolFee().0;
+        assert_eq!(accrued / 10u128.pow(12), 1_670_994);
+
+        testing_env!(get_context(factory_account(), to_yocto(10), 0, false));
+        pool.exitswapPoolAmountIn(token2_account(), to_yocto(1).into(), 0.into());
+        // Reverted exits keep no fee.
+        assert!(!resolve_push(&mut pool, factory_account(), vec![false]));
+        assert_eq!(pool.getAccruedProtocolFee().0, accrued);
+        testing_env!(get_context(factory_account(), to_yocto(10), 0, false));
+        pool.exitswapPoolAmountIn(token2_account(), to_yocto(1).into(), 0.into());
+        assert!(resolve_push(&mut pool, factory_account(), vec![true]));
+        // Plus half of the swap fee on the third of the exit beyond the weight of token2.
+        assert_eq!(pool.getAccruedProtocolFee().0 / 10u128.pow(12), 1_837_264);
+    }
+
+    #[test]
+    fn test_protocol_fee_swap_push_failed() {
+        let mut pool = setup_pool(true);
+        pool.setProtocolFee((BONE / 2).into());
+        swap_token1(&mut pool);
+        assert!(!resolve_push(&mut pool, factory_account(), vec![false]));
+        assert_eq!(pool.getAccruedProtocolFee().0, 0);
+        assert_eq!(pool.ft_total_supply().0, to_yocto(100));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_FACTORY")]
+    fn test_set_protocol_fee_not_factory() {
+        let mut pool = setup_pool(true);
+        testing_env!(get_context(alice_account(), to_yocto(10), 0, false));
+        pool.setProtocolFee(0.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_MAX_PROTOCOL_FEE")]
+    fn test_set_protocol_fee_over_max() {
+        let mut pool = setup_pool(true);
+        pool.setProtocolFee((BONE / 2 + 1).into());
+    }
+
+    #[test]
+    fn test_dynamic_fee() {
+        let mut pool = setup_pool(false);
+        pool.setDynamicFee(true);
+        assert!(pool.isDynamicFee());
+        assert_eq!(pool.getSwapFee(), MIN_FEE.into());
+        swap_token1(&mut pool);
+        assert!(resolve_push(&mut pool, factory_account(), vec![true]));
+        // Spot price went up by 26%, a thousandth of which is added to the fee.
+        assert_eq!(pool.getEffectiveSwapFee().0 / MIN_FEE, 261);
+        assert_eq!(pool.getSwapFee(), MIN_FEE.into());
+        testing_env_at_block(factory_account(), 1_001);
+        assert_eq!(pool.getEffectiveSwapFee().0 / MIN_FEE, 96);
+        pool.setDynamicFee(false);
+        assert_eq!(pool.getEffectiveSwapFee(), MIN_FEE.into());
+    }
+
+    #[test]
+    fn test_dynamic_fee_swap_push_failed() {
+        let mut pool = setup_pool(false);
+        pool.setDynamicFee(true);
+        swap_token1(&mut pool);
+        assert!(!resolve_push(&mut pool, factory_account(), vec![false]));
+        assert_eq!(pool.getEffectiveSwapFee(), MIN_FEE.into());
+    }
+
+    #[test]
+    fn test_dynamic_fee_settled_later() {
+        let mut pool = setup_pool(false);
+        pool.setProtocolFee((BONE / 2).into());
+        pool.setDynamicFee(true);
+        pool.finalize();
+        swap_token1(&mut pool);
+        assert!(resolve_push(&mut pool, factory_account(), vec![true]));
+        let accrued = pool.getAccruedProtocolFee().0;
+        pool.swapExactAmountIn(
+            token1_account(),
+            to_yocto(1).into(),
+            token2_account(),
+            0.into(),
+            to_yocto(1_000).into(),
+        );
+        // Pull resolves once the raised fee decayed, the fee charged at the start is kept.
+        let storage = env::take_blockchain_interface()
+            .unwrap()
+            .as_mut_mocked_blockchain()
+            .unwrap()
+            .take_storage();
+        let mut context = get_context(pool_account(), to_yocto(10), 0, false);
+        context.block_index = 1_001;
+        env::set_blockchain_interface(Box::new(MockedBlockchain::new(
+            context,
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])],
+            storage,
+            Default::default(),
+        )));
+        assert!(pool.on_pull(factory_account()));
+        // Raised fee instead of the 96 times decayed one, relative to the minimum fee.
+        assert_eq!((pool.getAccruedProtocolFee().0 - accrued) / accrued, 228);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_IS_FINALIZED")]
+    fn test_dynamic_fee_finalized() {
+        let mut pool = setup_pool(true);
+        pool.setDynamicFee(true);
+    }
+
+    /// Stable-swap pool with 100 of each token.
+    fn setup_stable_pool(finalize: bool) -> BPool {
+        testing_env!(get_context(factory_account(), to_yocto(10), 0, false));
+        let mut pool = BPool::new(None, None);
+        pool.setAmplification(Some(100));
+        for token in vec![token1_account(), token2_account()] {
+            pool.bind(token, to_yocto(100).into(), to_yocto(1).into());
+            resolve_pull(&mut pool, factory_account(), vec![true]);
+        }
+        if finalize {
+            pool.finalize();
+        }
+        pool
+    }
+
+    #[test]
+    fn test_stable_swap() {
+        let mut pool = setup_stable_pool(true);
+        assert_eq!(pool.getAmplification(), Some(100));
+        assert_eq!(
+            pool.getSpotPriceSansFee(token1_account(), token2_account()),
+            BONE.into()
+        );
+        let (amount_out, _) = pool.swapExactAmountIn(
+            token1_account(),
+            to_yocto(10).into(),
+            token2_account(),
+            to_yocto(9).into(),
+            to_yocto(2).into(),
+        );
+        // Weighted math would give out only 9.09 for 10 in.
+        assert_eq!(amount_out.0 / 10u128.pow(21), 9_994);
+        assert!(resolve_pull(&mut pool, factory_account(), vec![true]));
+        assert!(resolve_push(&mut pool, factory_account(), vec![true]));
+        assert_eq!(
+            pool.getBalance(token2_account()),
+            (to_yocto(100) - amount_out.0).into()
+        );
+    }
+
+    #[test]
+    fn test_stable_joinswap_exitswap() {
+        let mut pool = setup_stable_pool(true);
+        let pool_amount_out =
+            pool.joinswapExternAmountIn(token1_account(), to_yocto(10).into(), 0.into());
+        assert!(resolve_pull(&mut pool, factory_account(), vec![true]));
+        // Close to 10 of 200 tokens of pool value.
+        assert_eq!(pool_amount_out.0 / 10u128.pow(22), 499);
+        let token_amount_out =
+            pool.exitswapPoolAmountIn(token2_account(), pool_amount_out, 0.into());
+        assert!(resolve_push(&mut pool, factory_account(), vec![true]));
+        assert_eq!(token_amount_out.0 / 10u128.pow(22), 999);
+    }
+
+    #[test]
+    fn test_stable_decimals() {
+        testing_env!(get_context(factory_account(), to_yocto(10), 0, false));
+        let mut pool = BPool::new(None, None);
+        pool.setAmplification(Some(100));
+        pool.bind(token1_account(), to_yocto(100).into(), to_yocto(1).into());
+        resolve_pull(&mut pool, factory_account(), vec![true]);
+        // 100 of a token with 18 decimals.
+        pool.bind(
+            token2_account(),
+            (100 * 10u128.pow(18)).into(),
+            to_yocto(1).into(),
+        );
+        resolve_pull(&mut pool, factory_account(), vec![true]);
+        pool.setDecimals(token2_account(), 18);
+        pool.finalize();
+        assert_eq!(pool.getDecimals(token1_account()), POOL_DECIMALS);
+        assert_eq!(pool.getDecimals(token2_account()), 18);
+        assert_eq!(
+            pool.getSpotPriceSansFee(token1_account(), token2_account()),
+            (BONE * 10u128.pow(6)).into()
+        );
+        let (amount_out, _) = pool.swapExactAmountIn(
+            token1_account(),
+            to_yocto(10).into(),
+            token2_account(),
+            (9 * 10u128.pow(18)).into(),
+            (2 * BONE * 10u128.pow(6)).into(),
+        );
+        // Same as for equal decimals.
+        assert_eq!(amount_out.0 / 10u128.pow(15), 9_994);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_MAX_DECIMALS")]
+    fn test_set_decimals_over_max() {
+        let mut pool = setup_stable_pool(false);
+        pool.setDecimals(token1_account(), POOL_DECIMALS + 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_IS_STABLE")]
+    fn test_stable_rebind_weight() {
+        let mut pool = setup_stable_pool(false);
+        pool.rebind(token1_account(), to_yocto(100), to_yocto(2));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_IS_STABLE")]
+    fn test_stable_update_weights_gradually() {
+        let mut pool = setup_stable_pool(false);
+        pool.updateWeightsGradually(vec![to_yocto(2).into(), to_yocto(1).into()], 11, 21);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_GRADUAL_UPDATE_IN_PROGRESS")]
+    fn test_set_amplification_during_update() {
+        let mut pool = setup_pool(false);
+        pool.updateWeightsGradually(vec![to_yocto(15).into(), to_yocto(5).into()], 11, 21);
+        pool.setAmplification(Some(100));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_MAX_AMP")]
+    fn test_set_amplification_over_max() {
+        let mut pool = setup_pool(false);
+        pool.setAmplification(Some(MAX_AMP + 1));
+    }
+
+    #[test]
+    fn test_join_exit_pool() {
+        let mut pool = setup_pool(true);
+        register(&mut pool, alice_account());
+        pool.joinPool(to_yocto(10), vec![to_yocto(1), to_yocto(120)]);
+        assert_eq!(pool.get_balance(alice_account()), 0.into());
+        assert!(resolve_pull(&mut pool, alice_account(), vec![true, true]));
+        assert_eq!(pool.get_balance(alice_account()), to_yocto(10).into());
+        assert_eq!(
+            pool.getBalance(token1_account()),
+            (to_yocto(66) / 10).into()
+        );
+        assert_eq!(pool.getBalance(token2_account()), to_yocto(1_320).into());
+
+        pool.exitPool(to_yocto(10), vec![0, 0]);
+        assert_eq!(pool.get_balance(alice_account()), 0.into());
+        assert!(resolve_push(&mut pool, alice_account(), vec![true, true]));
+        assert_eq!(pool.get_total_supply(), INIT_POOL_SUPPLY.into());
+        // Rounding leaves the dust in the pool.
+        assert_eq!(pool.getBalance(token1_account()), (to_yocto(6) + 1).into());
+        assert_eq!(
+            pool.getBalance(token2_account()),
+            (to_yocto(1_200) + 120).into()
+        );
+    }
+
+    #[test]
+    fn test_pause() {
+        let mut pool = setup_pool(true);
+        assert_eq!(pool.getGuardian(), factory_account());
+        pool.setGuardian(alice_account());
+        testing_env!(get_context(alice_account(), to_yocto(10), 0, false));
+        pool.pause();
+        assert!(pool.isPaused());
+        // Exits are still open.
+        testing_env!(get_context(factory_account(), to_yocto(10), 0, false));
+        pool.exitPool(to_yocto(10), vec![0, 0]);
+        assert!(resolve_push(&mut pool, factory_account(), vec![true, true]));
+        testing_env!(get_context(alice_account(), to_yocto(10), 0, false));
+        pool.unpause();
+        assert!(!pool.isPaused());
+        testing_env!(get_context(factory_account(), to_yocto(10), 0, false));
+        swap_token1(&mut pool);
+        assert!(resolve_push(&mut pool, factory_account(), vec![true]));
+    }
+
+    #[test]
+    fn test_force_unlock() {
+        let mut pool = setup_pool(true);
+        let token_amount_out =
+            pool.exitswapPoolAmountIn(token2_account(), to_yocto(1).into(), 0.into());
+        // Callback of the exit never ran.
+        testing_env_at_block(factory_account(), 1 + LOCK_TIMEOUT);
+        pool.forceUnlock();
+        assert!(pool.pending.is_empty());
+        assert_eq!(pool.get_balance(factory_account()), to_yocto(99).into());
+        assert_eq!(pool.get_total_supply(), to_yocto(99).into());
+        assert_eq!(
+            pool.getBalance(token2_account()),
+            (to_yocto(1_200) - token_amount_out.0).into()
+        );
+        swap_token1(&mut pool);
+    }
+
+    #[test]
+    fn test_force_unlock_pull() {
+        let mut pool = setup_pool(true);
+        pool.swapExactAmountIn(
+            token1_account(),
+            to_yocto(1).into(),
+            token2_account(),
+            0.into(),
+            to_yocto(100).into(),
+        );
+        // Callback of the pull never ran.
+        testing_env_at_block(factory_account(), 1 + LOCK_TIMEOUT);
+        pool.forceUnlock();
+        assert!(pool.pending.is_empty());
+        assert_eq!(
+            pool.getClaim(factory_account(), token1_account()),
+            to_yocto(1).into()
+        );
+        assert_eq!(pool.getBalance(token1_account()), to_yocto(6).into());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_GUARDIAN")]
+    fn test_force_unlock_not_guardian() {
+        let mut pool = setup_pool(true);
+        pool.setGuardian(alice_account());
+        pool.exitswapPoolAmountIn(token2_account(), to_yocto(1).into(), 0.into());
+        testing_env_at_block(factory_account(), 1 + LOCK_TIMEOUT);
+        pool.forceUnlock();
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_LOCK_NOT_EXPIRED")]
+    fn test_force_unlock_early() {
+        let mut pool = setup_pool(true);
+        pool.exitswapPoolAmountIn(token2_account(), to_yocto(1).into(), 0.into());
+        testing_env_at_block(factory_account(), LOCK_TIMEOUT);
+        pool.forceUnlock();
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_PAUSED")]
+    fn test_swap_paused() {
+        let mut pool = setup_pool(true);
+        pool.pause();
+        swap_token1(&mut pool);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_PAUSED")]
+    fn test_join_paused() {
+        let mut pool = setup_pool(true);
+        pool.pause();
+        pool.joinswapExternAmountIn(token1_account(), to_yocto(1).into(), 0.into());
+    }
+
+    #[test]
+    fn test_set_guardian_finalized() {
+        let mut pool = setup_pool(true);
+        // Guardian hands over the role, which the new guardian can hand on.
+        pool.setGuardian(bob_account());
+        assert_eq!(pool.getGuardian(), bob_account());
+        testing_env!(get_context(bob_account(), to_yocto(10), 0, false));
+        pool.setGuardian(alice_account());
+        assert_eq!(pool.getGuardian(), alice_account());
+        testing_env!(get_context(alice_account(), to_yocto(10), 0, false));
+        pool.pause();
+        assert!(pool.isPaused());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_GUARDIAN")]
+    fn test_set_guardian_controller() {
+        let mut pool = setup_pool(true);
+        pool.setController(alice_account());
+        pool.setGuardian(bob_account());
+        // Controller can't take the role from the guardian.
+        testing_env!(get_context(alice_account(), to_yocto(10), 0, false));
+        pool.setGuardian(alice_account());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_GUARDIAN")]
+    fn test_set_guardian_not_guardian() {
+        let mut pool = setup_pool(true);
+        testing_env!(get_context(alice_account(), to_yocto(10), 0, false));
+        pool.setGuardian(alice_account());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_GUARDIAN")]
+    fn test_pause_not_guardian() {
+        let mut pool = setup_pool(true);
+        testing_env!(get_context(alice_account(), to_yocto(10), 0, false));
+        pool.pause();
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_REGISTERED")]
+    fn test_join_not_registered() {
+        let mut pool = setup_pool(true);
+        testing_env!(get_context(alice_account(), to_yocto(10), 0, false));
+        pool.joinPool(to_yocto(10), vec![to_yocto(1), to_yocto(120)]);
+    }
+
+    #[test]
+    fn test_migrate() {
+        testing_env!(get_context(pool_account(), to_yocto(10), 0, false));
+        let account_hash = env::sha256(alice_account().as_bytes());
+        let mut account = Account::new(account_hash.clone());
+        account.balance = to_yocto(100);
+        let mut token = LegacyToken {
+            accounts: UnorderedMap::new(b"a".to_vec()),
+            total_supply: to_yocto(100),
+        };
+        token.accounts.insert(&account_hash, &account);
+        env::state_write(&LegacyBPool {
+            controller: alice_account(),
+            factory: factory_account(),
+            swap_fee: MIN_FEE,
+            finalized: true,
+            public_swap: true,
+            records: UnorderedMap::new(b"r".to_vec()),
+            tokens: vec![],
+            total_weight: 0,
+            token,
+        });
+        let pool = BPool::migrate();
+        assert!(pool.isFinalized());
+        assert_eq!(pool.getController(), alice_account());
+        assert_eq!(pool.get_balance(alice_account()), to_yocto(100).into());
+        assert_eq!(
+            pool.storage_balance_of(alice_account()).unwrap().total,
+            0.into()
+        );
+    }
+
+    #[test]
+    fn test_join_pool_failed() {
+        let mut pool = setup_pool(true);
+        register(&mut pool, alice_account());
+        pool.joinPool(to_yocto(10), vec![to_yocto(1), to_yocto(120)]);
+        assert!(!resolve_pull(&mut pool, alice_account(), vec![true, false]));
+        assert_eq!(pool.get_balance(alice_account()), 0.into());
+        assert_eq!(pool.getBalance(token1_account()), to_yocto(6).into());
+        assert_eq!(pool.getBalance(token2_account()), to_yocto(1_200).into());
+        // Pending operation is cleared, so account can try again.
+        pool.joinPool(to_yocto(10), vec![to_yocto(1), to_yocto(120)]);
+    }
+
+    #[test]
+    fn test_exit_pool_failed() {
+        let mut pool = setup_pool(true);
+        pool.exitPool(to_yocto(30), vec![0, 0]);
+        assert!(!resolve_push(
+            &mut pool,
+            factory_account(),
+            vec![true, false]
+        ));
+        // Shares for token2 are returned, the ones for token1 are burned.
+        assert_eq!(
+            pool.get_balance(factory_account()),
+            (to_yocto(90) + 10).into()
+        );
+        assert_eq!(
+            pool.getBalance(token1_account()),
+            (to_yocto(42) / 10).into()
+        );
+        assert_eq!(pool.getBalance(token2_account()), to_yocto(1_200).into());
+    }
+
+    #[test]
+    fn test_joinswap() {
+        let context = get_context(factory_account(), to_yocto(10), 0, false);
+        testing_env!(context.clone());
+        let mut pool = BPool::new(None, None);
+        pool.bind(token1_account(), to_yocto(6).into(), to_yocto(5).into());
+        resolve_pull(&mut pool, factory_account(), vec![true]);
+        pool.bind(
+            token2_account(),
+            to_yocto(1_200).into(),
+            to_yocto(10).into(),
+        );
+        resolve_pull(&mut pool, factory_account(), vec![true]);
+        pool.finalize();
+
+        register(&mut pool, alice_account());
+        let pool_amount_out =
+            pool.joinswapExternAmountIn(token1_account(), to_yocto(1).into(), 0.into());
+        assert_eq!(pool_amount_out.0 / 10u128.pow(15), 5_272_656_619);
+        assert!(resolve_pull(&mut pool, alice_account(), vec![true]));
+        assert_eq!(pool.get_balance(alice_account()), pool_amount_out);
+        assert_eq!(pool.getBalance(token1_account()), to_yocto(7).into());
+
+        let token_amount_in =
+            pool.joinswapPoolAmountOut(token2_account(), to_yocto(1).into(), to_yocto(100).into());
+        assert_eq!(token_amount_in.0 / 10u128.pow(15), 17_139_003_898);
+        assert!(resolve_pull(&mut pool, alice_account(), vec![true]));
+        assert_eq!(
+            pool.get_balance(alice_account()),
+            (pool_amount_out.0 + to_yocto(1)).into()
+        );
+        assert_eq!(
+            pool.getBalance(token2_account()),
+            (to_yocto(1_200) + token_amount_in.0).into()
+        );
+    }
+
+    #[test]
+    fn test_exitswap() {
+        let context = get_context(factory_account(), to_yocto(10), 0, false);
+        testing_env!(context.clone());
+        let mut pool = BPool::new(None, None);
+        pool.bind(token1_account(), to_yocto(6).into(), to_yocto(5).into());
+        resolve_pull(&mut pool, factory_account(), vec![true]);
+        pool.bind(
+            token2_account(),
+            to_yocto(1_200).into(),
             to_yocto(10).into(),
         );
+        resolve_pull(&mut pool, factory_account(), vec![true]);
         pool.finalize();
-        assert_eq!(pool.getSpotPrice(token1_account(), token2_account()), 1);
+
+        let token_amount_out =
+            pool.exitswapPoolAmountIn(token2_account(), to_yocto(1).into(), 0.into());
+        assert_eq!(token_amount_out.0 / 10u128.pow(15), 17_954_918_732);
+        assert!(resolve_push(&mut pool, factory_account(), vec![true]));
+        assert_eq!(pool.get_balance(factory_account()), to_yocto(99).into());
+        assert_eq!(pool.get_total_supply(), to_yocto(99).into());
+        assert_eq!(
+            pool.getBalance(token2_account()),
+            (to_yocto(1_200) - token_amount_out.0).into()
+        );
+
+        let pool_amount_in =
+            pool.exitswapExternAmountOut(token1_account(), to_yocto(1).into(), to_yocto(99).into());
+        assert_eq!(pool_amount_in.0 / 10u128.pow(15), 5_837_437_280);
+        assert!(resolve_push(&mut pool, factory_account(), vec![true]));
+        assert_eq!(
+            pool.get_balance(factory_account()),
+            (to_yocto(99) - pool_amount_in.0).into()
+        );
+        assert_eq!(pool.getBalance(token1_account()), to_yocto(5).into());
+    }
+
+    /// Switches to `account_id` calling the pool at `block_index`.
+    fn testing_env_at_block(account_id: AccountId, block_index: BlockHeight) {
+        let mut context = get_context(account_id, to_yocto(10), 0, false);
+        context.block_index = block_index;
+        testing_env!(context);
+    }
+
+    #[test]
+    fn test_update_weights_gradually() {
+        let mut pool = setup_pool(false);
+        pool.updateWeightsGradually(vec![to_yocto(15).into(), to_yocto(5).into()], 11, 21);
+
+        testing_env_at_block(alice_account(), 16);
+        pool.pokeWeights();
+        assert_eq!(
+            pool.getDenormalizedWeight(token1_account()),
+            to_yocto(10).into()
+        );
+        assert_eq!(
+            pool.getDenormalizedWeight(token2_account()),
+            (to_yocto(15) / 2).into()
+        );
+        assert_eq!(pool.getTotalDenormalizedWeight(), (to_yocto(35) / 2).into());
+
+        testing_env_at_block(alice_account(), 30);
+        pool.pokeWeights();
+        assert_eq!(
+            pool.getDenormalizedWeight(token1_account()),
+            to_yocto(15).into()
+        );
+        assert_eq!(
+            pool.getDenormalizedWeight(token2_account()),
+            to_yocto(5).into()
+        );
+        assert_eq!(pool.getTotalDenormalizedWeight(), to_yocto(20).into());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_CANT_POKE_YET")]
+    fn test_poke_weights_early() {
+        let mut pool = setup_pool(false);
+        pool.updateWeightsGradually(vec![to_yocto(15).into(), to_yocto(5).into()], 11, 21);
+        testing_env_at_block(alice_account(), 5);
+        pool.pokeWeights();
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_GRADUAL_UPDATE_IN_PROGRESS")]
+    fn test_rebind_weight_during_update() {
+        let mut pool = setup_pool(false);
+        pool.updateWeightsGradually(vec![to_yocto(15).into(), to_yocto(5).into()], 11, 21);
+        // Balance alone can still change.
+        pool.rebindDelta(token1_account(), 0.into(), 0.into(), None);
+        pool.rebindDelta(
+            token1_account(),
+            0.into(),
+            0.into(),
+            Some(to_yocto(6).into()),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_GRADUAL_UPDATE_IN_PROGRESS")]
+    fn test_bind_during_update() {
+        let mut pool = setup_pool(false);
+        pool.updateWeightsGradually(vec![to_yocto(15).into(), to_yocto(5).into()], 11, 21);
+        pool.bind(alice_account(), to_yocto(1).into(), to_yocto(5).into());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_MAX_TOTAL_WEIGHT")]
+    fn test_update_weights_over_max_total() {
+        let mut pool = setup_pool(false);
+        pool.updateWeightsGradually(vec![to_yocto(40).into(), to_yocto(40).into()], 11, 21);
+    }
+
+    #[test]
+    fn test_whitelist() {
+        let mut pool = setup_pool(true);
+        pool.setWhitelistEnabled(true);
+        pool.addToWhitelist(alice_account());
+        assert!(pool.canProvideLiquidity(alice_account()));
+        assert!(!pool.canProvideLiquidity(factory_account()));
+        assert_eq!(pool.getWhitelist(0, 10), vec![alice_account()]);
+
+        register(&mut pool, alice_account());
+        pool.joinswapExternAmountIn(token1_account(), to_yocto(1).into(), 0.into());
+        assert!(resolve_pull(&mut pool, alice_account(), vec![true]));
+        assert!(pool.get_balance(alice_account()).0 > 0);
+
+        // Exits stay open for accounts that aren't whitelisted.
+        testing_env!(get_context(factory_account(), to_yocto(10), 0, false));
+        pool.exitswapPoolAmountIn(token2_account(), to_yocto(1).into(), 0.into());
+        assert!(resolve_push(&mut pool, factory_account(), vec![true]));
+        assert_eq!(pool.get_balance(factory_account()), to_yocto(99).into());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_ON_WHITELIST")]
+    fn test_join_not_whitelisted() {
+        let mut pool = setup_pool(true);
+        pool.setWhitelistEnabled(true);
+        pool.addToWhitelist(alice_account());
+        pool.removeFromWhitelist(alice_account());
+        register(&mut pool, alice_account());
+        ft_on_transfer(
+            &mut pool,
+            token1_account(),
+            to_yocto(1),
+            r#"{"join_single": {"min_pool_amount_out": "0"}}"#,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_CAP_LIMIT_REACHED")]
+    fn test_cap() {
+        let mut pool = setup_pool(true);
+        pool.setCap(to_yocto(101).into());
+        assert_eq!(pool.getCap(), to_yocto(101).into());
+
+        register(&mut pool, alice_account());
+        pool.joinPool(to_yocto(1), vec![to_yocto(1), to_yocto(100)]);
+        assert!(resolve_pull(&mut pool, alice_account(), vec![true, true]));
+        assert_eq!(pool.get_total_supply(), to_yocto(101).into());
+        pool.joinPool(to_yocto(1), vec![to_yocto(1), to_yocto(100)]);
+    }
+
+    /// Switches to alice calling the pool at `timestamp` in seconds.
+    fn testing_env_at_time(timestamp: u64) {
+        let mut context = get_context(alice_account(), to_yocto(10), 0, false);
+        context.block_timestamp = timestamp * 1_000_000_000;
+        testing_env!(context);
+    }
+
+    #[test]
+    fn test_consult() {
+        let mut pool = setup_pool(false);
+        register(&mut pool, alice_account());
+        pool.setDepositOutputs(true);
+        ft_on_transfer(&mut pool, token1_account(), to_yocto(2), r#""deposit""#);
+        let mut prices = vec![];
+        for timestamp in &[1_000, 2_000] {
+            testing_env_at_time(*timestamp);
+            pool.swapExactAmountIn(
+                token1_account(),
+                to_yocto(1).into(),
+                token2_account(),
+                0.into(),
+                to_yocto(100).into(),
+            );
+            prices.push(
+                pool.getSpotPriceSansFee(token1_account(), token2_account())
+                    .0,
+            );
+        }
+
+        testing_env_at_time(3_000);
+        assert_eq!(
+            pool.consult(token1_account(), token2_account(), 500),
+            prices[1].into()
+        );
+        assert_eq!(
+            pool.consult(token1_account(), token2_account(), 2_000),
+            ((prices[0] + prices[1]) / 2).into()
+        );
+        // Token1 is the reference token, in which prices are kept.
+        assert_eq!(
+            pool.consult(token2_account(), token1_account(), 500),
+            bdiv(BONE, prices[1]).into()
+        );
+    }
+
+    #[test]
+    fn test_oracle_update_gas() {
+        testing_env!(get_context(factory_account(), to_yocto(10), 0, false));
+        let mut pool = BPool::new(None, None);
+        let tokens: Vec<AccountId> = (0..MAX_BOUND_TOKENS)
+            .map(|i| format!("token{}", i))
+            .collect();
+        for token in tokens.iter() {
+            pool.bind(token.clone(), to_yocto(10).into(), to_yocto(5).into());
+            resolve_pull(&mut pool, factory_account(), vec![true]);
+        }
+        pool.setPublicSwap(true);
+        for timestamp in &[1_000, 2_000] {
+            testing_env_at_time(*timestamp);
+            pool.swapExactAmountIn(
+                tokens[1].clone(),
+                to_yocto(1).into(),
+                tokens[2].clone(),
+                0.into(),
+                to_yocto(100).into(),
+            );
+            // Settles with the gas the pool attaches to its callback. Only host functions and
+            // receipts are metered here, not the contract's own execution.
+            let storage = env::take_blockchain_interface()
+                .unwrap()
+                .as_mut_mocked_blockchain()
+                .unwrap()
+                .take_storage();
+            let mut context = get_context(pool_account(), to_yocto(10), 0, false);
+            context.block_timestamp = timestamp * 1_000_000_000;
+            context.prepaid_gas =
+                gas::ON_PULL_CALLBACK + gas::NEP21_TRANSFER + gas::ON_PUSH_CALLBACK;
+            env::set_blockchain_interface(Box::new(MockedBlockchain::new(
+                context,
+                Default::default(),
+                Default::default(),
+                vec![PromiseResult::Successful(vec![])],
+                storage,
+                Default::default(),
+            )));
+            assert!(pool.on_pull(alice_account()));
+            assert!(resolve_push(&mut pool, alice_account(), vec![true]));
+        }
+        testing_env_at_time(2_500);
+        assert!(pool.consult(tokens[0].clone(), tokens[2].clone(), 1_000).0 > 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_MISSING_HISTORICAL_OBSERVATION")]
+    fn test_consult_window_too_long() {
+        let mut pool = setup_pool(false);
+        register(&mut pool, alice_account());
+        pool.setDepositOutputs(true);
+        ft_on_transfer(&mut pool, token1_account(), to_yocto(1), r#""deposit""#);
+        testing_env_at_time(1_000);
+        pool.swapExactAmountIn(
+            token1_account(),
+            to_yocto(1).into(),
+            token2_account(),
+            0.into(),
+            to_yocto(100).into(),
+        );
+        // History starts with the swap, the first update since token2 got its balance.
+        testing_env_at_time(1_200);
+        pool.consult(token1_account(), token2_account(), 500);
     }
 }
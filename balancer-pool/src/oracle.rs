@@ -0,0 +1,139 @@
+//! Time-weighted average prices of tokens, see `BPool::consult`.
+//! Spot prices of all tokens in a reference token, the first of them, are summed over time
+//! before every change of the pool's balances or weights, and snapshots of these sums are kept
+//! at least `OBSERVATION_PERIOD` apart. Prices of other pairs are derived from these.
+
+use borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::Vector;
+use near_sdk::{env, AccountId, Balance};
+
+use crate::bconst::{MAX_OBSERVATIONS, OBSERVATION_PERIOD};
+
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+pub struct Observation {
+    /// Time of the observation in seconds.
+    timestamp: u64,
+    /// Price of each token of the oracle summed over seconds, wrapping on overflow.
+    price_cumulatives: Vec<Balance>,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Oracle {
+    /// Tokens which prices are summed, the first one is the reference token.
+    tokens: Vec<AccountId>,
+    /// Sums as of the last update.
+    last: Observation,
+    /// Ring buffer of past sums.
+    observations: Vector<Observation>,
+    /// Number of observations recorded so far.
+    num_observations: u64,
+}
+
+/// Current block time in seconds.
+fn now() -> u64 {
+    env::block_timestamp() / 1_000_000_000
+}
+
+impl Oracle {
+    pub fn new(id: Vec<u8>) -> Self {
+        Self {
+            tokens: vec![],
+            last: Observation::default(),
+            observations: Vector::new(id),
+            num_observations: 0,
+        }
+    }
+
+    /// Token all prices are in, `None` before the first update.
+    pub fn reference(&self) -> Option<&AccountId> {
+        self.tokens.first()
+    }
+
+    /// Adds `prices` of `tokens` in the first of them, which are held since the last update,
+    /// up to now. The history is dropped if the tokens changed.
+    pub fn update(&mut self, tokens: Vec<AccountId>, prices: Vec<Balance>) {
+        let timestamp = now();
+        if tokens != self.tokens {
+            self.last = Observation {
+                timestamp,
+                price_cumulatives: vec![0; tokens.len()],
+            };
+            self.tokens = tokens;
+            self.observations.clear();
+            self.num_observations = 0;
+        } else if timestamp > self.last.timestamp {
+            let elapsed = (timestamp - self.last.timestamp) as Balance;
+            for (price_cumulative, price) in self.last.price_cumulatives.iter_mut().zip(prices) {
+                *price_cumulative = price_cumulative.wrapping_add(price.wrapping_mul(elapsed));
+            }
+            self.last.timestamp = timestamp;
+        }
+        let is_due = match self.observation(0) {
+            Some(newest) => self.last.timestamp >= newest.timestamp + OBSERVATION_PERIOD,
+            None => true,
+        };
+        if is_due {
+            self.record();
+        }
+    }
+
+    /// Average price of `token` in the reference token over at least the last `window`
+    /// seconds, given its current `price`.
+    pub fn consult(&self, token: &AccountId, price: Balance, window: u64) -> Balance {
+        assert!(window > 0, "ERR_INVALID_WINDOW");
+        let index = self
+            .tokens
+            .iter()
+            .position(|oracle_token| oracle_token == token)
+            .expect("ERR_MISSING_HISTORICAL_OBSERVATION");
+        let timestamp = now();
+        let target = timestamp
+            .checked_sub(window)
+            .expect("ERR_MISSING_HISTORICAL_OBSERVATION");
+        // Price didn't change within the window.
+        if self.last.timestamp <= target {
+            return price;
+        }
+        let price_cumulative = self.last.price_cumulatives[index]
+            .wrapping_add(price.wrapping_mul((timestamp - self.last.timestamp) as Balance));
+
+        // Finds the newest observation at or before `target`.
+        let (mut low, mut high) = (0, self.observations.len());
+        while low < high {
+            let middle = (low + high) / 2;
+            if self.observation(middle).unwrap().timestamp <= target {
+                high = middle;
+            } else {
+                low = middle + 1;
+            }
+        }
+        let past = self
+            .observation(low)
+            .expect("ERR_MISSING_HISTORICAL_OBSERVATION");
+        price_cumulative.wrapping_sub(past.price_cumulatives[index])
+            / (timestamp - past.timestamp) as Balance
+    }
+
+    fn record(&mut self) {
+        let observation = Observation {
+            timestamp: self.last.timestamp,
+            price_cumulatives: self.last.price_cumulatives.clone(),
+        };
+        if self.observations.len() < MAX_OBSERVATIONS {
+            self.observations.push(&observation);
+        } else {
+            self.observations
+                .replace(self.num_observations % MAX_OBSERVATIONS, &observation);
+        }
+        self.num_observations += 1;
+    }
+
+    /// Observation `age` places before the newest one.
+    fn observation(&self, age: u64) -> Option<Observation> {
+        if age >= self.observations.len() {
+            return None;
+        }
+        self.observations
+            .get((self.num_observations - 1 - age) % MAX_OBSERVATIONS)
+    }
+}
@@ -0,0 +1,292 @@
+//! Stable-swap math for pegged tokens, used instead of `bmath` once the pool has an
+//! amplification coefficient, see `BPool::setAmplification`.
+//! Balances `x_i` of `n` tokens keep invariant `D` for amplification `A`:
+//! `A * n^n * sum(x_i) + D = A * n^n * D + D^(n+1) / (n^n * prod(x_i))`.
+//! Balances are compared as is, so the pool scales them to the same decimals beforehand.
+//! Intermediate products take more than 256 bits for large balances and amplification, so the
+//! math is done in 512 bits.
+
+use near_sdk::Balance;
+use uint::construct_uint;
+
+use crate::bconst::BONE;
+use crate::bmath::{badd, bdiv, bmul, bsub};
+
+construct_uint! {
+    /// 512-bit unsigned integer.
+    pub struct U512(8);
+}
+
+/// Max Newton iterations to find the invariant or a balance.
+const MAX_ITERATIONS: usize = 256;
+
+/// `A * n^n`.
+fn ann(amp: u64, n: usize) -> U512 {
+    U512::from(amp) * U512::from(n).pow(U512::from(n))
+}
+
+fn converged(a: U512, b: U512) -> bool {
+    if a > b {
+        a - b <= U512::one()
+    } else {
+        b - a <= U512::one()
+    }
+}
+
+/// `D^(n+1) / (n^n * prod(x_i))`.
+fn calc_d_p(balances: &[Balance], d: U512) -> U512 {
+    let n = U512::from(balances.len());
+    balances
+        .iter()
+        .fold(d, |d_p, x| d_p * d / (U512::from(*x) * n))
+}
+
+/// Invariant of `balances`.
+pub fn calc_d(balances: &[Balance], amp: u64) -> Balance {
+    let n = U512::from(balances.len());
+    let sum = balances
+        .iter()
+        .fold(U512::zero(), |sum, x| sum + U512::from(*x));
+    if sum.is_zero() {
+        return 0;
+    }
+    let ann = ann(amp, balances.len());
+    let mut d = sum;
+    for _ in 0..MAX_ITERATIONS {
+        let d_p = calc_d_p(balances, d);
+        let d_prev = d;
+        d = (ann * sum + d_p * n) * d / ((ann - 1) * d + (n + 1) * d_p);
+        if converged(d, d_prev) {
+            break;
+        }
+    }
+    d.as_u128()
+}
+
+/// Balance of token `index` that keeps invariant `d` with the other `balances`.
+pub fn calc_y(balances: &[Balance], index: usize, d: Balance, amp: u64) -> Balance {
+    let n = U512::from(balances.len());
+    let ann = ann(amp, balances.len());
+    let d = U512::from(d);
+    let mut c = d;
+    let mut sum = U512::zero();
+    for (k, x) in balances.iter().enumerate() {
+        if k != index {
+            let x = U512::from(*x);
+            sum += x;
+            c = c * d / (x * n);
+        }
+    }
+    c = c * d / (ann * n);
+    let b = sum + d / ann;
+    let mut y = d;
+    for _ in 0..MAX_ITERATIONS {
+        let y_prev = y;
+        y = (y * y + c) / (y * 2 + b - d);
+        if converged(y, y_prev) {
+            break;
+        }
+    }
+    y.as_u128()
+}
+
+/// Price of token `index_out` in token `index_in` including `swap_fee`, from the slope of
+/// the invariant: `x_in * (A * n^n * x_out + D_P) / (x_out * (A * n^n * x_in + D_P))`.
+pub fn calc_spot_price(
+    balances: &[Balance],
+    amp: u64,
+    index_in: usize,
+    index_out: usize,
+    swap_fee: Balance,
+) -> Balance {
+    let bone = U512::from(BONE);
+    let d_p = calc_d_p(balances, U512::from(calc_d(balances, amp)));
+    let ann = ann(amp, balances.len());
+    let numer = ann * bone + d_p * bone / U512::from(balances[index_out]);
+    let denom = ann * bone + d_p * bone / U512::from(balances[index_in]);
+    let ratio = (numer * bone / denom).as_u128();
+    bdiv(ratio, bsub(BONE, swap_fee))
+}
+
+pub fn calc_out_given_in(
+    balances: &[Balance],
+    amp: u64,
+    index_in: usize,
+    index_out: usize,
+    token_amount_in: Balance,
+    swap_fee: Balance,
+) -> Balance {
+    let d = calc_d(balances, amp);
+    let mut new_balances = balances.to_vec();
+    new_balances[index_in] = badd(
+        balances[index_in],
+        bmul(token_amount_in, bsub(BONE, swap_fee)),
+    );
+    let new_balance_out = calc_y(&new_balances, index_out, d, amp);
+    // Rounds in favor of the pool.
+    balances[index_out].saturating_sub(new_balance_out + 1)
+}
+
+pub fn calc_in_given_out(
+    balances: &[Balance],
+    amp: u64,
+    index_in: usize,
+    index_out: usize,
+    token_amount_out: Balance,
+    swap_fee: Balance,
+) -> Balance {
+    let d = calc_d(balances, amp);
+    let mut new_balances = balances.to_vec();
+    new_balances[index_out] = bsub(balances[index_out], token_amount_out);
+    let new_balance_in = calc_y(&new_balances, index_in, d, amp);
+    // Rounds in favor of the pool.
+    let token_amount_in_after_fee = bsub(new_balance_in + 1, balances[index_in]);
+    bdiv(token_amount_in_after_fee, bsub(BONE, swap_fee))
+}
+
+/// Share of a single-asset join or exit in token `index` that isn't charged `swap_fee`.
+/// Only the part beyond the token's share of the pool is charged, as it's effectively swapped.
+fn fee_factor(balances: &[Balance], index: usize, swap_fee: Balance) -> Balance {
+    let sum = balances.iter().fold(0, |sum, x| badd(sum, *x));
+    let taxable = bsub(BONE, bdiv(balances[index], sum));
+    bsub(BONE, bmul(taxable, swap_fee))
+}
+
+pub fn calc_pool_out_given_single_in(
+    balances: &[Balance],
+    amp: u64,
+    index_in: usize,
+    pool_supply: Balance,
+    token_amount_in: Balance,
+    swap_fee: Balance,
+) -> Balance {
+    let d = calc_d(balances, amp);
+    let mut new_balances = balances.to_vec();
+    new_balances[index_in] = badd(
+        balances[index_in],
+        bmul(token_amount_in, fee_factor(balances, index_in, swap_fee)),
+    );
+    let new_d = calc_d(&new_balances, amp);
+    // Pool supply grows with the invariant.
+    bmul(pool_supply, bdiv(bsub(new_d, d), d))
+}
+
+pub fn calc_single_in_given_pool_out(
+    balances: &[Balance],
+    amp: u64,
+    index_in: usize,
+    pool_supply: Balance,
+    pool_amount_out: Balance,
+    swap_fee: Balance,
+) -> Balance {
+    let d = calc_d(balances, amp);
+    let new_d = bmul(d, bdiv(badd(pool_supply, pool_amount_out), pool_supply));
+    let new_balance_in = calc_y(balances, index_in, new_d, amp);
+    let token_amount_in_after_fee = bsub(new_balance_in, balances[index_in]);
+    bdiv(
+        token_amount_in_after_fee,
+        fee_factor(balances, index_in, swap_fee),
+    )
+}
+
+pub fn calc_single_out_given_pool_in(
+    balances: &[Balance],
+    amp: u64,
+    index_out: usize,
+    pool_supply: Balance,
+    pool_amount_in: Balance,
+    swap_fee: Balance,
+) -> Balance {
+    let d = calc_d(balances, amp);
+    let new_d = bmul(d, bdiv(bsub(pool_supply, pool_amount_in), pool_supply));
+    let new_balance_out = calc_y(balances, index_out, new_d, amp);
+    let token_amount_out_before_fee = bsub(balances[index_out], new_balance_out);
+    bmul(
+        token_amount_out_before_fee,
+        fee_factor(balances, index_out, swap_fee),
+    )
+}
+
+pub fn calc_pool_in_given_single_out(
+    balances: &[Balance],
+    amp: u64,
+    index_out: usize,
+    pool_supply: Balance,
+    token_amount_out: Balance,
+    swap_fee: Balance,
+) -> Balance {
+    let d = calc_d(balances, amp);
+    let mut new_balances = balances.to_vec();
+    new_balances[index_out] = bsub(
+        balances[index_out],
+        bdiv(token_amount_out, fee_factor(balances, index_out, swap_fee)),
+    );
+    let new_d = calc_d(&new_balances, amp);
+    bmul(pool_supply, bdiv(bsub(d, new_d), d))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bconst::MAX_AMP;
+    use crate::bmath::bsub_sign;
+
+    const AMP: u64 = 100;
+
+    #[test]
+    fn test_calc_d() {
+        // Balanced pool keeps the sum.
+        assert_eq!(calc_d(&[100 * BONE, 100 * BONE], AMP), 200 * BONE);
+        let d = calc_d(&[150 * BONE, 50 * BONE], AMP);
+        assert!(d < 200 * BONE && d > 199 * BONE);
+        let y = calc_y(&[150 * BONE, 50 * BONE], 1, d, AMP);
+        assert!(bsub_sign(y, 50 * BONE).0 <= 2);
+    }
+
+    #[test]
+    fn test_calc_d_max_amp() {
+        // 8 tokens of 10 million each at 24 decimals.
+        let balances = [10_000_000 * BONE; 8];
+        assert_eq!(calc_d(&balances, MAX_AMP), 80_000_000 * BONE);
+        let mut balances = balances.to_vec();
+        balances[0] = 20_000_000 * BONE;
+        let d = calc_d(&balances, MAX_AMP);
+        let y = calc_y(&balances, 0, d, MAX_AMP);
+        assert!(bsub_sign(y, 20_000_000 * BONE).0 <= 2);
+    }
+
+    #[test]
+    fn test_swap() {
+        let balances = [100 * BONE, 100 * BONE];
+        assert_eq!(calc_spot_price(&balances, AMP, 0, 1, 0), BONE);
+        let amount_out = calc_out_given_in(&balances, AMP, 0, 1, BONE, 0);
+        // Pegged tokens swap close to 1:1, unlike weighted math.
+        assert!(amount_out < BONE && amount_out > BONE / 1_000 * 999);
+        let amount_in = calc_in_given_out(&balances, AMP, 0, 1, amount_out, 0);
+        assert!(bsub_sign(amount_in, BONE).0 <= 2);
+    }
+
+    #[test]
+    fn test_join_exit_single() {
+        let balances = [100 * BONE, 100 * BONE];
+        let pool_supply = 100 * BONE;
+        let swap_fee = BONE / 1_000;
+        let pool_amount_out =
+            calc_pool_out_given_single_in(&balances, AMP, 0, pool_supply, 10 * BONE, swap_fee);
+        let token_amount_in = calc_single_in_given_pool_out(
+            &balances,
+            AMP,
+            0,
+            pool_supply,
+            pool_amount_out,
+            swap_fee,
+        );
+        assert!(bsub_sign(token_amount_in, 10 * BONE).0 < BONE / 1_000_000);
+
+        let pool_amount_in =
+            calc_pool_in_given_single_out(&balances, AMP, 1, pool_supply, 10 * BONE, swap_fee);
+        let token_amount_out =
+            calc_single_out_given_pool_in(&balances, AMP, 1, pool_supply, pool_amount_in, swap_fee);
+        assert!(bsub_sign(token_amount_out, 10 * BONE).0 < BONE / 1_000_000);
+    }
+}
@@ -0,0 +1,1983 @@
+use std::collections::BTreeMap;
+
+use borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::{UnorderedMap, UnorderedSet};
+use near_sdk::json_types::U128;
+use near_sdk::{
+    env, ext_contract, near_bindgen, AccountId, Balance, BlockHeight, Promise, PromiseOrValue,
+    PromiseResult,
+};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use balancer_common::bconst::{MAX_IN_RATIO, MAX_OUT_RATIO, POOL_DECIMALS};
+use balancer_common::bmath::{
+    badd, bdiv, bmul, bsub, calc_pool_out_given_single_in, calc_single_out_given_pool_in,
+};
+use balancer_common::events::Event;
+use near_lib::token::{
+    ext_nep141, ext_nep21, FungibleToken, FungibleTokenCore, FungibleTokenMetadata,
+    FungibleTokenMetadataProvider, FungibleTokenReceiver, FungibleTokenResolver, StorageBalance,
+    StorageBalanceBounds, StorageManagement, Token,
+};
+
+const NO_DEPOSIT: Balance = 0;
+
+/// Deposit required by NEP-141 transfers.
+const ONE_YOCTO: Balance = 1;
+
+const POOL_NAME: &str = "Smart Pool Token";
+const POOL_SYMBOL: &str = "SPT";
+
+pub mod gas {
+    pub const BASE_GAS: u64 = 10_000_000_000_000;
+
+    pub const POOL_VIEW: u64 = BASE_GAS;
+
+    /// Pool calls that may transfer tokens and call back the pool.
+    pub const POOL_CALL: u64 = 4 * BASE_GAS;
+
+    /// Pool calls that may push tokens out, covering the pool's transfer and its callback.
+    pub const POOL_PUSH_CALL: u64 = 2 * POOL_CALL;
+
+    pub const NEP21_TRANSFER: u64 = BASE_GAS;
+
+    pub const NEP21_INC_ALLOWANCE: u64 = BASE_GAS;
+
+    /// Covers the transfer, pool's `ft_on_transfer` and `ft_resolve_transfer`.
+    pub const NEP141_TRANSFER_CALL: u64 = 5 * BASE_GAS;
+
+    pub const ON_CLAIM_CALLBACK: u64 = BASE_GAS;
+
+    pub const ON_WITHDRAW_CALLBACK: u64 = NEP21_TRANSFER + ON_CLAIM_CALLBACK + BASE_GAS;
+
+    pub const ON_JOIN_REBIND_CALLBACK: u64 = POOL_PUSH_CALL + ON_WITHDRAW_CALLBACK + BASE_GAS;
+
+    pub const ON_JOIN_DEPOSIT_CALLBACK: u64 = POOL_CALL + ON_JOIN_REBIND_CALLBACK + BASE_GAS;
+
+    pub const ON_JOIN_CALLBACK: u64 = NEP141_TRANSFER_CALL + ON_JOIN_DEPOSIT_CALLBACK + BASE_GAS;
+
+    pub const ON_EXIT_REBIND_CALLBACK: u64 = NEP21_TRANSFER + ON_CLAIM_CALLBACK + BASE_GAS;
+
+    pub const ON_EXIT_CALLBACK: u64 = POOL_PUSH_CALL + ON_EXIT_REBIND_CALLBACK + BASE_GAS;
+
+    pub const ON_UPDATE_WEIGHT_CALLBACK: u64 = BASE_GAS;
+
+    pub const ON_UNBIND_CALLBACK: u64 = BASE_GAS;
+
+    pub const ON_REMOVE_TOKEN_CALLBACK: u64 = POOL_PUSH_CALL + ON_UNBIND_CALLBACK + BASE_GAS;
+
+    pub const ON_SYNC_DEPOSIT_CALLBACK: u64 = POOL_CALL + BASE_GAS;
+
+    pub const ON_DEPOSIT_CALLBACK: u64 = BASE_GAS;
+
+    pub const ON_JOIN_POOL_REBIND_CALLBACK: u64 = BASE_GAS;
+
+    pub const ON_JOIN_POOL_CALLBACK: u64 = POOL_CALL + ON_JOIN_POOL_REBIND_CALLBACK + BASE_GAS;
+
+    pub const ON_EXIT_POOL_CALLBACK: u64 = BASE_GAS;
+}
+
+/// Powers of the controller over the pool, fixed on creation.
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Default, Debug, PartialEq,
+)]
+pub struct Rights {
+    pub can_pause_swapping: bool,
+    pub can_change_swap_fee: bool,
+    pub can_change_weights: bool,
+    pub can_add_remove_tokens: bool,
+    pub can_whitelist_lps: bool,
+    pub can_change_cap: bool,
+}
+
+/// Action passed as `msg` of `ft_transfer_call` to the smart pool.
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferAction {
+    /// Joins with all received tokens, same as `joinswapExternAmountIn` of the pool.
+    JoinSingle { min_pool_amount_out: U128 },
+    /// Deposits all received tokens for `joinPool`.
+    Deposit,
+}
+
+/// Smart pool that controls a non-finalized `BPool` and issues its own pool shares.
+/// All liquidity of the pool belongs to the smart pool: liquidity providers join and exit with
+/// single tokens, that the smart pool moves in and out of the pool with `rebind`, or with all
+/// tokens in proportion from and to their deposits, see `joinPool` and `exitPool`.
+/// Tokens are moved into the pool as the smart pool's deposit there, so each `rebind` settles
+/// without transfers. Tokens owed to accounts that couldn't be sent right away are kept as
+/// claims, see `claim`.
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct ConfigurableRightsPool {
+    controller: AccountId,
+    bpool: AccountId,
+    rights: Rights,
+    token: Token,
+    whitelist: UnorderedSet<AccountId>,
+    /// Max total supply of pool shares.
+    cap: Balance,
+    /// Taken by joins, exits and changes of the pool, until the pool calls resolve.
+    mutex: bool,
+    /// Tokens owed to accounts, by account and token.
+    claims: UnorderedMap<AccountId, BTreeMap<AccountId, Balance>>,
+    /// Tokens of accounts for `joinPool` and from `exitPool`, by account and token. These are
+    /// kept as this contract's deposit in the pool.
+    deposits: UnorderedMap<AccountId, BTreeMap<AccountId, Balance>>,
+    /// Sum of `deposits` by token.
+    total_deposits: UnorderedMap<AccountId, Balance>,
+    name: Option<String>,
+    symbol: Option<String>,
+    /// Tokens that called `ft_on_transfer`, which are sent with NEP-141 `ft_transfer` instead
+    /// of NEP-21 `transfer`.
+    nep141_tokens: UnorderedSet<AccountId>,
+}
+
+impl Default for ConfigurableRightsPool {
+    fn default() -> Self {
+        panic!("ConfigurableRightsPool should be initialized before usage")
+    }
+}
+
+#[near_bindgen]
+impl ConfigurableRightsPool {
+    /// Smart pool for `bpool`, which controller should be transferred to this contract before
+    /// `createPool`.
+    #[init]
+    pub fn new(
+        bpool: AccountId,
+        rights: Rights,
+        name: Option<String>,
+        symbol: Option<String>,
+    ) -> Self {
+        assert!(!env::state_exists(), "Already initialized");
+        assert!(
+            env::is_valid_account_id(bpool.as_bytes()),
+            "ERR_INVALID_BPOOL"
+        );
+        let mut token = Token::new(env::current_account_id(), 0);
+        // The controller receives the initial supply.
+        token.register_account(&env::predecessor_account_id());
+        let crp = Self {
+            controller: env::predecessor_account_id(),
+            bpool,
+            rights,
+            token,
+            whitelist: UnorderedSet::new(b"w".to_vec()),
+            cap: Balance::max_value(),
+            mutex: false,
+            claims: UnorderedMap::new(b"c".to_vec()),
+            deposits: UnorderedMap::new(b"d".to_vec()),
+            total_deposits: UnorderedMap::new(b"t".to_vec()),
+            name,
+            symbol,
+            nep141_tokens: UnorderedSet::new(b"n".to_vec()),
+        };
+        crp.ft_metadata().assert_valid();
+        crp
+    }
+
+    // Getters
+
+    pub fn getController(&self) -> AccountId {
+        self.controller.clone()
+    }
+
+    pub fn getBPool(&self) -> AccountId {
+        self.bpool.clone()
+    }
+
+    pub fn getRights(&self) -> Rights {
+        self.rights.clone()
+    }
+
+    pub fn getCap(&self) -> U128 {
+        self.cap.into()
+    }
+
+    /// Whether `provider` can join, always true unless LPs are whitelisted.
+    pub fn canProvideLiquidity(&self, provider: AccountId) -> bool {
+        !self.rights.can_whitelist_lps
+            || provider == self.controller
+            || self.whitelist.contains(&provider)
+    }
+
+    /// Returns the amount of `token` owed to `account_id`.
+    pub fn getClaim(&self, account_id: AccountId, token: AccountId) -> U128 {
+        self.claims
+            .get(&account_id)
+            .and_then(|claims| claims.get(&token).cloned())
+            .unwrap_or(0)
+            .into()
+    }
+
+    /// Returns the amount of `token` deposited by `account_id` for `joinPool`.
+    pub fn getDeposit(&self, account_id: AccountId, token: AccountId) -> U128 {
+        self.deposits
+            .get(&account_id)
+            .and_then(|deposits| deposits.get(&token).cloned())
+            .unwrap_or(0)
+            .into()
+    }
+
+    // Setters
+
+    pub fn setController(&mut self, controller: AccountId) {
+        self.assert_controller();
+        self.controller = controller;
+    }
+
+    /// Mints the initial supply of pool shares to the controller, which keeps the liquidity it
+    /// already bound to the pool. Attached deposit registers this contract with the pool, which
+    /// keeps the tokens of joins as its deposits.
+    #[payable]
+    pub fn createPool(&mut self, initialSupply: U128) -> Promise {
+        self.assert_controller();
+        assert_eq!(self.token.get_total_supply(), 0, "ERR_IS_CREATED");
+        assert!(initialSupply.0 > 0, "ERR_INIT_SUPPLY");
+        assert!(initialSupply.0 <= self.cap, "ERR_CAP_LIMIT_REACHED");
+        self.token
+            .mint(self.controller.clone(), initialSupply.into());
+        ext_bpool::storage_deposit(
+            None,
+            Some(true),
+            &self.bpool,
+            env::attached_deposit(),
+            gas::POOL_CALL,
+        )
+    }
+
+    pub fn setSwapFee(&mut self, swapFee: U128) -> Promise {
+        self.assert_controller();
+        assert!(
+            self.rights.can_change_swap_fee,
+            "ERR_NOT_CONFIGURABLE_SWAP_FEE"
+        );
+        ext_bpool::setSwapFee(swapFee, &self.bpool, NO_DEPOSIT, gas::POOL_CALL)
+    }
+
+    pub fn setPublicSwap(&mut self, public: bool) -> Promise {
+        self.assert_controller();
+        assert!(self.rights.can_pause_swapping, "ERR_NOT_PAUSABLE_SWAP");
+        ext_bpool::setPublicSwap(public, &self.bpool, NO_DEPOSIT, gas::POOL_CALL)
+    }
+
+    pub fn setCap(&mut self, cap: U128) {
+        self.assert_controller();
+        assert!(self.rights.can_change_cap, "ERR_CANNOT_CHANGE_CAP");
+        self.cap = cap.into();
+    }
+
+    pub fn whitelistLiquidityProvider(&mut self, provider: AccountId) {
+        self.assert_controller();
+        assert!(self.rights.can_whitelist_lps, "ERR_CANNOT_WHITELIST_LPS");
+        self.whitelist.insert(&provider);
+    }
+
+    pub fn removeWhitelistedLiquidityProvider(&mut self, provider: AccountId) {
+        self.assert_controller();
+        assert!(self.rights.can_whitelist_lps, "ERR_CANNOT_WHITELIST_LPS");
+        assert!(self.whitelist.remove(&provider), "ERR_LP_NOT_WHITELISTED");
+    }
+
+    /// Changes the weight of `token`, keeping its balance.
+    pub fn updateWeight(&mut self, token: AccountId, newWeight: U128) -> Promise {
+        self.assert_controller();
+        assert!(
+            self.rights.can_change_weights,
+            "ERR_NOT_CONFIGURABLE_WEIGHTS"
+        );
+        self.lock();
+        ext_bpool::rebindDelta(
+            token,
+            0.into(),
+            0.into(),
+            Some(newWeight),
+            &self.bpool,
+            NO_DEPOSIT,
+            gas::POOL_CALL,
+        )
+        .then(ext_self::on_update_weight(
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            gas::ON_UPDATE_WEIGHT_CALLBACK,
+        ))
+    }
+
+    /// Shifts weights to `newWeights` from `startBlock` to `endBlock`, as anyone pokes the pool.
+    pub fn updateWeightsGradually(
+        &mut self,
+        newWeights: Vec<U128>,
+        startBlock: BlockHeight,
+        endBlock: BlockHeight,
+    ) -> Promise {
+        self.assert_controller();
+        assert!(
+            self.rights.can_change_weights,
+            "ERR_NOT_CONFIGURABLE_WEIGHTS"
+        );
+        ext_bpool::updateWeightsGradually(
+            newWeights,
+            startBlock,
+            endBlock,
+            &self.bpool,
+            NO_DEPOSIT,
+            gas::POOL_CALL,
+        )
+    }
+
+    /// Binds `balance` of `token`, that the controller sent to this contract beforehand.
+    /// No pool shares are minted for the added liquidity.
+    /// Attached deposit covers the allowance storage on the token.
+    #[payable]
+    pub fn addToken(&mut self, token: AccountId, balance: U128, denorm: U128) -> Promise {
+        self.assert_controller();
+        assert!(
+            self.rights.can_add_remove_tokens,
+            "ERR_CANNOT_ADD_REMOVE_TOKENS"
+        );
+        ext_nep21::inc_allowance(
+            self.bpool.clone(),
+            balance,
+            &token,
+            env::attached_deposit(),
+            gas::NEP21_INC_ALLOWANCE,
+        )
+        .then(ext_bpool::bind(
+            token,
+            balance,
+            denorm,
+            &self.bpool,
+            NO_DEPOSIT,
+            gas::POOL_CALL,
+        ))
+    }
+
+    /// Unbinds `token` and burns controller's pool shares of its weight. Removed balance is
+    /// claimed by the controller. Shares are held in escrow until the pool returns the balance.
+    pub fn removeToken(&mut self, token: AccountId) -> Promise {
+        self.assert_controller();
+        assert!(
+            self.rights.can_add_remove_tokens,
+            "ERR_CANNOT_ADD_REMOVE_TOKENS"
+        );
+        self.lock();
+        self.pool_state(&token).then(ext_self::on_remove_token(
+            token,
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            gas::ON_REMOVE_TOKEN_CALLBACK,
+        ))
+    }
+
+    // Liquidity
+
+    /// Exits with `poolAmountIn` of shares for at least `minAmountOut` of `tokenOut`, that is
+    /// sent to the caller. Shares are held in escrow until the pool releases the tokens.
+    pub fn exitswapPoolAmountIn(
+        &mut self,
+        tokenOut: AccountId,
+        poolAmountIn: U128,
+        minAmountOut: U128,
+    ) -> Promise {
+        assert!(self.token.get_total_supply() > 0, "ERR_NOT_CREATED");
+        self.lock();
+        let account_id = env::predecessor_account_id();
+        self.token.internal_transfer(
+            account_id.clone(),
+            env::current_account_id(),
+            poolAmountIn.into(),
+        );
+        self.pool_state(&tokenOut).then(ext_self::on_exit(
+            account_id,
+            tokenOut,
+            poolAmountIn,
+            minAmountOut,
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            gas::ON_EXIT_CALLBACK,
+        ))
+    }
+
+    /// Joins with `poolAmountOut` of shares for at most `maxAmountsIn` of every token, in the
+    /// order of the pool's `getCurrentTokens`, taken from the caller's deposits.
+    pub fn joinPool(&mut self, poolAmountOut: U128, maxAmountsIn: Vec<U128>) -> Promise {
+        let account_id = env::predecessor_account_id();
+        assert!(self.token.get_total_supply() > 0, "ERR_NOT_CREATED");
+        assert!(self.token.is_registered(&account_id), "ERR_NOT_REGISTERED");
+        assert!(
+            self.canProvideLiquidity(account_id.clone()),
+            "ERR_NOT_ON_WHITELIST"
+        );
+        assert!(
+            badd(self.token.get_total_supply(), poolAmountOut.0) <= self.cap,
+            "ERR_CAP_LIMIT_REACHED"
+        );
+        self.lock();
+        ext_bpool::getCurrentTokens(&self.bpool, NO_DEPOSIT, gas::POOL_VIEW).then(
+            ext_self::on_join_pool(
+                account_id,
+                poolAmountOut,
+                maxAmountsIn,
+                &env::current_account_id(),
+                NO_DEPOSIT,
+                gas::ON_JOIN_POOL_CALLBACK,
+            ),
+        )
+    }
+
+    /// Exits with `poolAmountIn` of shares for at least `minAmountsOut` of every token, in the
+    /// order of the pool's `getCurrentTokens`, added to the caller's deposits. Shares are held
+    /// in escrow until the pool releases the tokens.
+    pub fn exitPool(&mut self, poolAmountIn: U128, minAmountsOut: Vec<U128>) -> Promise {
+        self.lock();
+        let account_id = env::predecessor_account_id();
+        let ratio = bdiv(poolAmountIn.into(), self.token.get_total_supply());
+        self.token.internal_transfer(
+            account_id.clone(),
+            env::current_account_id(),
+            poolAmountIn.into(),
+        );
+        ext_bpool::rebindProportional(
+            ratio.into(),
+            false,
+            minAmountsOut,
+            &self.bpool,
+            NO_DEPOSIT,
+            gas::POOL_CALL,
+        )
+        .then(ext_self::on_exit_pool(
+            account_id,
+            poolAmountIn,
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            gas::ON_EXIT_POOL_CALLBACK,
+        ))
+    }
+
+    /// Withdraws `amount` of the caller's deposited `token` from the pool and sends it to them.
+    pub fn withdraw(&mut self, token: AccountId, amount: U128) -> Promise {
+        self.lock();
+        assert!(amount.0 > 0, "ERR_ZERO_AMOUNT");
+        let account_id = env::predecessor_account_id();
+        self.withdraw_deposit(&account_id, &token, amount.into());
+        ext_bpool::withdraw(
+            token.clone(),
+            amount,
+            &self.bpool,
+            NO_DEPOSIT,
+            gas::POOL_PUSH_CALL,
+        )
+        .then(ext_self::on_withdraw(
+            account_id,
+            token,
+            amount,
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            gas::ON_WITHDRAW_CALLBACK,
+        ))
+    }
+
+    /// Sends all `token` owed to the caller.
+    pub fn claim(&mut self, token: AccountId) -> Promise {
+        let account_id = env::predecessor_account_id();
+        let amount = self.withdraw_claim(&account_id, &token);
+        assert!(amount > 0, "ERR_NO_CLAIM");
+        self.send_claim(account_id, token, amount)
+    }
+
+    /// Withdraws whatever of `token` this contract has deposited in the pool beyond the
+    /// deposits of accounts, so it can be claimed. Anyone can call it.
+    pub fn syncDeposit(&mut self, token: AccountId) -> Promise {
+        self.lock();
+        ext_bpool::getDeposit(
+            env::current_account_id(),
+            token.clone(),
+            &self.bpool,
+            NO_DEPOSIT,
+            gas::POOL_VIEW,
+        )
+        .then(ext_self::on_sync_deposit(
+            token,
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            gas::ON_SYNC_DEPOSIT_CALLBACK,
+        ))
+    }
+
+    // Callbacks
+
+    /// Callback with the pool state of `token`, that computes the shares for `amount` and moves
+    /// the tokens into the pool. Returns the amount refunded to the sender.
+    pub fn on_join(
+        &mut self,
+        account_id: AccountId,
+        token: AccountId,
+        amount: U128,
+        min_pool_amount_out: U128,
+    ) -> PromiseOrValue<U128> {
+        self.assert_self();
+        let values = promise_values().unwrap_or_default();
+        let pool_amount_out = match self.pool_out_given_single_in(&values, amount.into()) {
+            Some(pool_amount_out)
+                if pool_amount_out >= min_pool_amount_out.0
+                    && badd(self.token.get_total_supply(), pool_amount_out) <= self.cap =>
+            {
+                pool_amount_out
+            }
+            _ => {
+                self.unlock();
+                return PromiseOrValue::Value(amount);
+            }
+        };
+        ext_nep141::ft_transfer_call(
+            self.bpool.clone(),
+            amount,
+            None,
+            "\"deposit\"".to_string(),
+            &token,
+            ONE_YOCTO,
+            gas::NEP141_TRANSFER_CALL,
+        )
+        .then(ext_self::on_join_deposit(
+            account_id,
+            token,
+            amount,
+            pool_amount_out.into(),
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            gas::ON_JOIN_DEPOSIT_CALLBACK,
+        ))
+        .into()
+    }
+
+    /// Callback after the tokens were deposited into the pool, that adds them to the balance.
+    pub fn on_join_deposit(
+        &mut self,
+        account_id: AccountId,
+        token: AccountId,
+        amount: U128,
+        pool_amount_out: U128,
+    ) -> PromiseOrValue<U128> {
+        self.assert_self();
+        if promise_values() != Some(vec![amount.0]) {
+            self.unlock();
+            return PromiseOrValue::Value(amount);
+        }
+        ext_bpool::rebindDelta(
+            token.clone(),
+            amount,
+            0.into(),
+            None,
+            &self.bpool,
+            NO_DEPOSIT,
+            gas::POOL_CALL,
+        )
+        .then(ext_self::on_join_rebind(
+            account_id,
+            token,
+            amount,
+            pool_amount_out,
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            gas::ON_JOIN_REBIND_CALLBACK,
+        ))
+        .into()
+    }
+
+    /// Callback after the rebind, that mints the shares. If the rebind failed, the deposit is
+    /// withdrawn from the pool and sent back to the sender, see `on_withdraw`.
+    pub fn on_join_rebind(
+        &mut self,
+        account_id: AccountId,
+        token: AccountId,
+        amount: U128,
+        pool_amount_out: U128,
+    ) -> U128 {
+        self.assert_self();
+        if is_promise_true() {
+            self.token.mint(account_id.clone(), pool_amount_out.into());
+            Event::Join {
+                caller: account_id,
+                token_in: token,
+                token_amount_in: amount,
+            }
+            .emit();
+        } else {
+            // Lock is released once the tokens are back.
+            ext_bpool::withdraw(
+                token.clone(),
+                amount,
+                &self.bpool,
+                NO_DEPOSIT,
+                gas::POOL_PUSH_CALL,
+            )
+            .then(ext_self::on_withdraw(
+                account_id,
+                token,
+                amount,
+                &env::current_account_id(),
+                NO_DEPOSIT,
+                gas::ON_WITHDRAW_CALLBACK,
+            ));
+            return 0.into();
+        }
+        self.unlock();
+        0.into()
+    }
+
+    /// Callback with the pool state of `token`, that computes the amount for escrowed shares and
+    /// rebinds the pool without it.
+    pub fn on_exit(
+        &mut self,
+        account_id: AccountId,
+        token: AccountId,
+        pool_amount_in: U128,
+        min_amount_out: U128,
+    ) -> bool {
+        self.assert_self();
+        let values = promise_values().unwrap_or_default();
+        match self.single_out_given_pool_in(&values, pool_amount_in.into()) {
+            Some(amount_out) if amount_out >= min_amount_out.0 => {
+                ext_bpool::rebindDelta(
+                    token.clone(),
+                    0.into(),
+                    amount_out.into(),
+                    None,
+                    &self.bpool,
+                    NO_DEPOSIT,
+                    gas::POOL_PUSH_CALL,
+                )
+                .then(ext_self::on_exit_rebind(
+                    account_id,
+                    token,
+                    pool_amount_in,
+                    amount_out.into(),
+                    &env::current_account_id(),
+                    NO_DEPOSIT,
+                    gas::ON_EXIT_REBIND_CALLBACK,
+                ));
+                true
+            }
+            _ => {
+                self.token.internal_transfer(
+                    env::current_account_id(),
+                    account_id,
+                    pool_amount_in.into(),
+                );
+                self.unlock();
+                false
+            }
+        }
+    }
+
+    /// Callback after the rebind, that burns the escrowed shares and sends the tokens released
+    /// by the pool, or returns the shares if the rebind or the pool's transfer failed.
+    pub fn on_exit_rebind(
+        &mut self,
+        account_id: AccountId,
+        token: AccountId,
+        pool_amount_in: U128,
+        amount_out: U128,
+    ) -> bool {
+        self.assert_self();
+        self.unlock();
+        if !is_promise_true() {
+            self.token.internal_transfer(
+                env::current_account_id(),
+                account_id,
+                pool_amount_in.into(),
+            );
+            return false;
+        }
+        self.token
+            .burn(env::current_account_id(), pool_amount_in.into());
+        Event::Exit {
+            caller: account_id.clone(),
+            token_out: token.clone(),
+            token_amount_out: amount_out,
+        }
+        .emit();
+        self.send_claim(account_id, token, amount_out.into());
+        true
+    }
+
+    /// Callback with the pool's tokens, that takes `max_amounts_in` from the deposits of
+    /// `account_id` and moves the ratio of `pool_amount_out` of each into the pool.
+    pub fn on_join_pool(
+        &mut self,
+        account_id: AccountId,
+        pool_amount_out: U128,
+        max_amounts_in: Vec<U128>,
+    ) -> bool {
+        self.assert_self();
+        let tokens = match promise_result::<Vec<AccountId>>() {
+            Some(tokens)
+                if tokens.len() == max_amounts_in.len()
+                    && tokens.iter().zip(&max_amounts_in).all(|(token, max)| {
+                        self.getDeposit(account_id.clone(), token.clone()).0 >= max.0
+                    }) =>
+            {
+                tokens
+            }
+            _ => {
+                self.unlock();
+                return false;
+            }
+        };
+        for (token, max) in tokens.iter().zip(&max_amounts_in) {
+            self.withdraw_deposit(&account_id, token, max.0);
+        }
+        let ratio = bdiv(pool_amount_out.into(), self.token.get_total_supply());
+        ext_bpool::rebindProportional(
+            ratio.into(),
+            true,
+            max_amounts_in.clone(),
+            &self.bpool,
+            NO_DEPOSIT,
+            gas::POOL_CALL,
+        )
+        .then(ext_self::on_join_pool_rebind(
+            account_id,
+            pool_amount_out,
+            tokens,
+            max_amounts_in,
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            gas::ON_JOIN_POOL_REBIND_CALLBACK,
+        ));
+        true
+    }
+
+    /// Callback with the amounts the pool took, that mints the shares and returns the rest of
+    /// `max_amounts_in` to the deposits. All of it is returned if the rebind failed.
+    pub fn on_join_pool_rebind(
+        &mut self,
+        account_id: AccountId,
+        pool_amount_out: U128,
+        tokens: Vec<AccountId>,
+        max_amounts_in: Vec<U128>,
+    ) -> bool {
+        self.assert_self();
+        self.unlock();
+        let amounts: Vec<(AccountId, U128)> = match promise_result() {
+            Some(amounts) => amounts,
+            None => {
+                for (token, max) in tokens.iter().zip(max_amounts_in) {
+                    self.deposit(&account_id, token, max.0);
+                }
+                return false;
+            }
+        };
+        for ((token, max), (_, amount)) in tokens.into_iter().zip(max_amounts_in).zip(amounts) {
+            self.deposit(&account_id, &token, bsub(max.0, amount.0));
+            Event::Join {
+                caller: account_id.clone(),
+                token_in: token,
+                token_amount_in: amount,
+            }
+            .emit();
+        }
+        self.token.mint(account_id, pool_amount_out.into());
+        true
+    }
+
+    /// Callback with the amounts the pool released, that burns the escrowed shares and adds the
+    /// amounts to the deposits of `account_id`. Shares are returned if the rebind failed.
+    pub fn on_exit_pool(&mut self, account_id: AccountId, pool_amount_in: U128) -> bool {
+        self.assert_self();
+        self.unlock();
+        let amounts: Vec<(AccountId, U128)> = match promise_result() {
+            Some(amounts) => amounts,
+            None => {
+                self.token.internal_transfer(
+                    env::current_account_id(),
+                    account_id,
+                    pool_amount_in.into(),
+                );
+                return false;
+            }
+        };
+        self.token
+            .burn(env::current_account_id(), pool_amount_in.into());
+        for (token, amount) in amounts {
+            self.deposit(&account_id, &token, amount.into());
+            Event::Exit {
+                caller: account_id.clone(),
+                token_out: token,
+                token_amount_out: amount,
+            }
+            .emit();
+        }
+        true
+    }
+
+    /// Callback after received tokens were forwarded to the pool, that adds the amount the pool
+    /// took to the deposit of `account_id`. Returns the rest, refunded to it.
+    pub fn on_deposit(&mut self, account_id: AccountId, token: AccountId, amount: U128) -> U128 {
+        self.assert_self();
+        self.unlock();
+        let used = promise_values()
+            .map(|values| values[0])
+            .unwrap_or(0)
+            .min(amount.0);
+        self.deposit(&account_id, &token, used);
+        bsub(amount.0, used).into()
+    }
+
+    /// Callback after the pool sent the withdrawn tokens, that sends them on to `account_id`.
+    /// The deposit is restored if the pool's withdraw failed.
+    pub fn on_withdraw(&mut self, account_id: AccountId, token: AccountId, amount: U128) -> bool {
+        self.assert_self();
+        self.unlock();
+        if !is_promise_true() {
+            self.deposit(&account_id, &token, amount.into());
+            return false;
+        }
+        self.send_claim(account_id, token, amount.into());
+        true
+    }
+
+    /// Callback after sending claimed tokens, that restores the claim if the transfer failed.
+    pub fn on_claim(&mut self, account_id: AccountId, token: AccountId, amount: U128) -> bool {
+        self.assert_self();
+        let success = is_promise_success();
+        if !success {
+            self.add_claim(&account_id, &token, amount.into());
+        }
+        success
+    }
+
+    /// Callback after the weight was changed, that releases the lock.
+    pub fn on_update_weight(&mut self) -> bool {
+        self.assert_self();
+        self.unlock();
+        is_promise_true()
+    }
+
+    /// Callback with the pool state of `token`, that takes controller's shares of its weight in
+    /// escrow and unbinds it.
+    pub fn on_remove_token(&mut self, token: AccountId) -> bool {
+        self.assert_self();
+        let values = match promise_values() {
+            Some(values) if values.len() == 4 => values,
+            _ => {
+                self.unlock();
+                return false;
+            }
+        };
+        let pool_shares = bmul(self.token.get_total_supply(), bdiv(values[1], values[2]));
+        if self.token.get_balance(self.controller.clone()) < pool_shares {
+            self.unlock();
+            return false;
+        }
+        self.token.internal_transfer(
+            self.controller.clone(),
+            env::current_account_id(),
+            pool_shares,
+        );
+        ext_bpool::unbind(token.clone(), &self.bpool, NO_DEPOSIT, gas::POOL_PUSH_CALL).then(
+            ext_self::on_unbind(
+                token,
+                pool_shares.into(),
+                &env::current_account_id(),
+                NO_DEPOSIT,
+                gas::ON_UNBIND_CALLBACK,
+            ),
+        );
+        true
+    }
+
+    /// Callback with the balance the pool returned in `unbind`, that burns the escrowed shares
+    /// and lets the controller claim the balance. Shares are returned if the unbind failed.
+    pub fn on_unbind(&mut self, token: AccountId, pool_shares: U128) -> U128 {
+        self.assert_self();
+        self.unlock();
+        let amount = promise_values().map(|values| values[0]).unwrap_or(0);
+        let controller = self.controller.clone();
+        if amount == 0 {
+            self.token
+                .internal_transfer(env::current_account_id(), controller, pool_shares.into());
+            return 0.into();
+        }
+        self.token
+            .burn(env::current_account_id(), pool_shares.into());
+        self.add_claim(&controller, &token, amount);
+        amount.into()
+    }
+
+    /// Callback with the deposit of `token` in the pool, that withdraws what isn't owed to
+    /// accounts.
+    pub fn on_sync_deposit(&mut self, token: AccountId) -> U128 {
+        self.assert_self();
+        self.unlock();
+        let amount = promise_values()
+            .map(|values| values[0])
+            .unwrap_or(0)
+            .saturating_sub(self.total_deposits.get(&token).unwrap_or(0));
+        if amount > 0 {
+            ext_bpool::withdraw(
+                token,
+                amount.into(),
+                &self.bpool,
+                NO_DEPOSIT,
+                gas::POOL_CALL,
+            );
+        }
+        amount.into()
+    }
+}
+
+#[near_bindgen]
+impl FungibleToken for ConfigurableRightsPool {
+    #[payable]
+    fn inc_allowance(&mut self, escrow_account_id: String, amount: U128) {
+        self.token.inc_allowance(escrow_account_id, amount.into());
+    }
+
+    #[payable]
+    fn dec_allowance(&mut self, escrow_account_id: String, amount: U128) {
+        self.token.dec_allowance(escrow_account_id, amount.into());
+    }
+
+    #[payable]
+    fn transfer_from(&mut self, owner_id: String, new_owner_id: String, amount: U128) {
+        self.token
+            .transfer_from(owner_id, new_owner_id, amount.into());
+    }
+
+    #[payable]
+    fn transfer(&mut self, new_owner_id: String, amount: U128) {
+        self.token.transfer(new_owner_id, amount.into());
+    }
+
+    fn get_total_supply(&self) -> U128 {
+        self.token.get_total_supply().into()
+    }
+
+    fn get_balance(&self, owner_id: String) -> U128 {
+        self.token.get_balance(owner_id).into()
+    }
+
+    fn get_allowance(&self, owner_id: String, escrow_account_id: String) -> U128 {
+        self.token.get_allowance(owner_id, escrow_account_id).into()
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenCore for ConfigurableRightsPool {
+    #[payable]
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>) {
+        self.token.ft_transfer(receiver_id, amount.into(), memo);
+    }
+
+    #[payable]
+    fn ft_transfer_call(
+        &mut self,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+        msg: String,
+    ) -> Promise {
+        self.token
+            .ft_transfer_call(receiver_id, amount.into(), memo, msg)
+    }
+
+    fn ft_total_supply(&self) -> U128 {
+        self.token.get_total_supply().into()
+    }
+
+    fn ft_balance_of(&self, account_id: AccountId) -> U128 {
+        self.token.get_balance(account_id).into()
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenResolver for ConfigurableRightsPool {
+    fn ft_resolve_transfer(
+        &mut self,
+        sender_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+    ) -> U128 {
+        self.token
+            .ft_resolve_transfer(sender_id, receiver_id, amount.into())
+            .into()
+    }
+}
+
+#[near_bindgen]
+impl StorageManagement for ConfigurableRightsPool {
+    #[payable]
+    fn storage_deposit(
+        &mut self,
+        account_id: Option<AccountId>,
+        registration_only: Option<bool>,
+    ) -> StorageBalance {
+        // Storage balance is fixed, so any excess is refunded regardless of `registration_only`.
+        let _ = registration_only;
+        self.token.storage_deposit(account_id)
+    }
+
+    #[payable]
+    fn storage_withdraw(&mut self, amount: Option<U128>) -> StorageBalance {
+        self.token
+            .storage_withdraw(amount.map(|amount| amount.into()))
+    }
+
+    #[payable]
+    fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+        self.token.storage_unregister(force)
+    }
+
+    fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+        self.token.storage_balance_bounds()
+    }
+
+    fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance> {
+        self.token.storage_balance_of(account_id)
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenMetadataProvider for ConfigurableRightsPool {
+    fn ft_metadata(&self) -> FungibleTokenMetadata {
+        FungibleTokenMetadata::new(
+            self.name.clone().unwrap_or_else(|| POOL_NAME.to_string()),
+            self.symbol
+                .clone()
+                .unwrap_or_else(|| POOL_SYMBOL.to_string()),
+            POOL_DECIMALS,
+        )
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenReceiver for ConfigurableRightsPool {
+    /// Executes `TransferAction` from `msg` with `amount` of tokens received from `sender_id`.
+    /// Returns the amount refunded if the join fails.
+    fn ft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        let token = env::predecessor_account_id();
+        let action: TransferAction = serde_json::from_str(&msg).expect("ERR_WRONG_MSG");
+        self.nep141_tokens.insert(&token);
+        match action {
+            TransferAction::JoinSingle {
+                min_pool_amount_out,
+            } => {
+                assert!(self.token.get_total_supply() > 0, "ERR_NOT_CREATED");
+                assert!(self.token.is_registered(&sender_id), "ERR_NOT_REGISTERED");
+                assert!(
+                    self.canProvideLiquidity(sender_id.clone()),
+                    "ERR_NOT_ON_WHITELIST"
+                );
+                self.lock();
+                self.pool_state(&token)
+                    .then(ext_self::on_join(
+                        sender_id,
+                        token,
+                        amount,
+                        min_pool_amount_out,
+                        &env::current_account_id(),
+                        NO_DEPOSIT,
+                        gas::ON_JOIN_CALLBACK,
+                    ))
+                    .into()
+            }
+            TransferAction::Deposit => {
+                self.lock();
+                ext_nep141::ft_transfer_call(
+                    self.bpool.clone(),
+                    amount,
+                    None,
+                    "\"deposit\"".to_string(),
+                    &token,
+                    ONE_YOCTO,
+                    gas::NEP141_TRANSFER_CALL,
+                )
+                .then(ext_self::on_deposit(
+                    sender_id,
+                    token,
+                    amount,
+                    &env::current_account_id(),
+                    NO_DEPOSIT,
+                    gas::ON_DEPOSIT_CALLBACK,
+                ))
+                .into()
+            }
+        }
+    }
+}
+
+#[ext_contract(ext_bpool)]
+pub trait ExtBPool {
+    fn setSwapFee(&mut self, swapFee: U128);
+
+    fn setPublicSwap(&mut self, public: bool);
+
+    fn bind(&mut self, token: AccountId, balance: U128, denorm: U128);
+
+    fn rebindDelta(
+        &mut self,
+        token: AccountId,
+        balanceIn: U128,
+        balanceOut: U128,
+        denorm: Option<U128>,
+    );
+
+    fn rebindProportional(&mut self, ratio: U128, join: bool, limitAmounts: Vec<U128>);
+
+    fn unbind(&mut self, token: AccountId);
+
+    fn updateWeightsGradually(
+        &mut self,
+        newWeights: Vec<U128>,
+        startBlock: BlockHeight,
+        endBlock: BlockHeight,
+    );
+
+    fn withdraw(&mut self, token: AccountId, amount: U128);
+
+    fn getCurrentTokens(&self) -> Vec<AccountId>;
+
+    fn getBalance(&self, token: AccountId) -> U128;
+
+    fn getDenormalizedWeight(&self, token: AccountId) -> U128;
+
+    fn getTotalDenormalizedWeight(&self) -> U128;
+
+    fn getEffectiveSwapFee(&self) -> U128;
+
+    fn getDeposit(&self, account_id: AccountId, token: AccountId) -> U128;
+
+    fn storage_deposit(&mut self, account_id: Option<AccountId>, registration_only: Option<bool>);
+}
+
+#[ext_contract(ext_self)]
+pub trait ExtSelf {
+    fn on_join(
+        &mut self,
+        account_id: AccountId,
+        token: AccountId,
+        amount: U128,
+        min_pool_amount_out: U128,
+    ) -> PromiseOrValue<U128>;
+
+    fn on_join_deposit(
+        &mut self,
+        account_id: AccountId,
+        token: AccountId,
+        amount: U128,
+        pool_amount_out: U128,
+    ) -> PromiseOrValue<U128>;
+
+    fn on_join_rebind(
+        &mut self,
+        account_id: AccountId,
+        token: AccountId,
+        amount: U128,
+        pool_amount_out: U128,
+    ) -> U128;
+
+    fn on_exit(
+        &mut self,
+        account_id: AccountId,
+        token: AccountId,
+        pool_amount_in: U128,
+        min_amount_out: U128,
+    ) -> bool;
+
+    fn on_exit_rebind(
+        &mut self,
+        account_id: AccountId,
+        token: AccountId,
+        pool_amount_in: U128,
+        amount_out: U128,
+    ) -> bool;
+
+    fn on_join_pool(
+        &mut self,
+        account_id: AccountId,
+        pool_amount_out: U128,
+        max_amounts_in: Vec<U128>,
+    ) -> bool;
+
+    fn on_join_pool_rebind(
+        &mut self,
+        account_id: AccountId,
+        pool_amount_out: U128,
+        tokens: Vec<AccountId>,
+        max_amounts_in: Vec<U128>,
+    ) -> bool;
+
+    fn on_exit_pool(&mut self, account_id: AccountId, pool_amount_in: U128) -> bool;
+
+    fn on_deposit(&mut self, account_id: AccountId, token: AccountId, amount: U128) -> U128;
+
+    fn on_withdraw(&mut self, account_id: AccountId, token: AccountId, amount: U128) -> bool;
+
+    fn on_claim(&mut self, account_id: AccountId, token: AccountId, amount: U128) -> bool;
+
+    fn on_update_weight(&mut self) -> bool;
+
+    fn on_remove_token(&mut self, token: AccountId) -> bool;
+
+    fn on_unbind(&mut self, token: AccountId, pool_shares: U128) -> U128;
+
+    fn on_sync_deposit(&mut self, token: AccountId) -> U128;
+}
+
+fn is_promise_success() -> bool {
+    assert_eq!(env::promise_results_count(), 1, "ERR_PROMISE_RESULTS");
+    match env::promise_result(0) {
+        PromiseResult::Successful(_) => true,
+        _ => false,
+    }
+}
+
+/// Whether the promise this callback depends on succeeded and returned `true`.
+fn is_promise_true() -> bool {
+    assert_eq!(env::promise_results_count(), 1, "ERR_PROMISE_RESULTS");
+    match env::promise_result(0) {
+        PromiseResult::Successful(value) => serde_json::from_slice(&value).unwrap_or(false),
+        _ => false,
+    }
+}
+
+/// Parses the value of the promise this callback depends on, `None` if it failed.
+fn promise_result<T: DeserializeOwned>() -> Option<T> {
+    assert_eq!(env::promise_results_count(), 1, "ERR_PROMISE_RESULTS");
+    match env::promise_result(0) {
+        PromiseResult::Successful(value) => serde_json::from_slice(&value).ok(),
+        _ => None,
+    }
+}
+
+/// Returns values of the promises this callback depends on, `None` if any of them failed.
+fn promise_values() -> Option<Vec<Balance>> {
+    (0..env::promise_results_count())
+        .map(|i| match env::promise_result(i) {
+            PromiseResult::Successful(value) => serde_json::from_slice::<U128>(&value)
+                .ok()
+                .map(|value| value.0),
+            _ => None,
+        })
+        .collect()
+}
+
+impl ConfigurableRightsPool {
+    fn assert_controller(&self) {
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.controller,
+            "ERR_NOT_CONTROLLER"
+        );
+    }
+
+    fn assert_self(&self) {
+        assert_eq!(
+            env::predecessor_account_id(),
+            env::current_account_id(),
+            "ERR_NOT_SELF"
+        );
+    }
+
+    fn lock(&mut self) {
+        assert!(!self.mutex, "ERR_REENTRY");
+        self.mutex = true;
+    }
+
+    fn unlock(&mut self) {
+        self.mutex = false;
+    }
+
+    /// Reads balance and weight of `token`, total weight and swap fee of the pool, in this order.
+    fn pool_state(&self, token: &AccountId) -> Promise {
+        ext_bpool::getBalance(token.clone(), &self.bpool, NO_DEPOSIT, gas::POOL_VIEW)
+            .and(ext_bpool::getDenormalizedWeight(
+                token.clone(),
+                &self.bpool,
+                NO_DEPOSIT,
+                gas::POOL_VIEW,
+            ))
+            .and(ext_bpool::getTotalDenormalizedWeight(
+                &self.bpool,
+                NO_DEPOSIT,
+                gas::POOL_VIEW,
+            ))
+            .and(ext_bpool::getEffectiveSwapFee(
+                &self.bpool,
+                NO_DEPOSIT,
+                gas::POOL_VIEW,
+            ))
+    }
+
+    /// Shares for `token_amount_in` given `pool_state`, `None` if it's over the pool's limits.
+    fn pool_out_given_single_in(
+        &self,
+        pool_state: &[Balance],
+        token_amount_in: Balance,
+    ) -> Option<Balance> {
+        if pool_state.len() != 4 || token_amount_in > bmul(pool_state[0], MAX_IN_RATIO) {
+            return None;
+        }
+        Some(calc_pool_out_given_single_in(
+            pool_state[0],
+            pool_state[1],
+            self.token.get_total_supply(),
+            pool_state[2],
+            token_amount_in,
+            pool_state[3],
+        ))
+    }
+
+    /// Tokens for `pool_amount_in` of shares given `pool_state`, `None` if it's over the pool's
+    /// limits.
+    fn single_out_given_pool_in(
+        &self,
+        pool_state: &[Balance],
+        pool_amount_in: Balance,
+    ) -> Option<Balance> {
+        if pool_state.len() != 4 {
+            return None;
+        }
+        let token_amount_out = calc_single_out_given_pool_in(
+            pool_state[0],
+            pool_state[1],
+            self.token.get_total_supply(),
+            pool_state[2],
+            pool_amount_in,
+            pool_state[3],
+        );
+        if token_amount_out > bmul(pool_state[0], MAX_OUT_RATIO) {
+            return None;
+        }
+        Some(token_amount_out)
+    }
+
+    fn add_claim(&mut self, account_id: &AccountId, token: &AccountId, amount: Balance) {
+        let mut claims = self.claims.get(account_id).unwrap_or_default();
+        let claim = claims.entry(token.clone()).or_insert(0);
+        *claim = badd(*claim, amount);
+        self.claims.insert(account_id, &claims);
+    }
+
+    fn withdraw_claim(&mut self, account_id: &AccountId, token: &AccountId) -> Balance {
+        let mut claims = self.claims.get(account_id).unwrap_or_default();
+        let amount = claims.remove(token).unwrap_or(0);
+        if claims.is_empty() {
+            self.claims.remove(account_id);
+        } else {
+            self.claims.insert(account_id, &claims);
+        }
+        amount
+    }
+
+    fn deposit(&mut self, account_id: &AccountId, token: &AccountId, amount: Balance) {
+        if amount == 0 {
+            return;
+        }
+        let mut deposits = self.deposits.get(account_id).unwrap_or_default();
+        let deposit = deposits.entry(token.clone()).or_insert(0);
+        *deposit = badd(*deposit, amount);
+        self.deposits.insert(account_id, &deposits);
+        let total_deposit = self.total_deposits.get(token).unwrap_or(0);
+        self.total_deposits
+            .insert(token, &badd(total_deposit, amount));
+    }
+
+    fn withdraw_deposit(&mut self, account_id: &AccountId, token: &AccountId, amount: Balance) {
+        let mut deposits = self.deposits.get(account_id).unwrap_or_default();
+        let deposit = *deposits.get(token).expect("ERR_INSUFFICIENT_DEPOSIT");
+        assert!(deposit >= amount, "ERR_INSUFFICIENT_DEPOSIT");
+        if deposit == amount {
+            deposits.remove(token);
+        } else {
+            deposits.insert(token.clone(), bsub(deposit, amount));
+        }
+        if deposits.is_empty() {
+            self.deposits.remove(account_id);
+        } else {
+            self.deposits.insert(account_id, &deposits);
+        }
+        let total_deposit = self.total_deposits.get(token).unwrap_or(0);
+        self.total_deposits
+            .insert(token, &bsub(total_deposit, amount));
+    }
+
+    /// Sends `amount` of `token` to `account_id`, keeping it as a claim if the transfer fails.
+    fn send_claim(&mut self, account_id: AccountId, token: AccountId, amount: Balance) -> Promise {
+        let transfer = if self.nep141_tokens.contains(&token) {
+            ext_nep141::ft_transfer(
+                account_id.clone(),
+                amount.into(),
+                None,
+                &token,
+                ONE_YOCTO,
+                gas::NEP21_TRANSFER,
+            )
+        } else {
+            ext_nep21::transfer(
+                account_id.clone(),
+                amount.into(),
+                &token,
+                NO_DEPOSIT,
+                gas::NEP21_TRANSFER,
+            )
+        };
+        transfer.then(ext_self::on_claim(
+            account_id,
+            token,
+            amount.into(),
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            gas::ON_CLAIM_CALLBACK,
+        ))
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::{testing_env, MockedBlockchain, VMContext};
+
+    use balancer_common::bconst::BONE;
+    use balancer_common::events::{take_logs, EventLog};
+
+    fn crp_account() -> AccountId {
+        "crp".to_string()
+    }
+    fn bpool_account() -> AccountId {
+        "bpool".to_string()
+    }
+    fn controller_account() -> AccountId {
+        "controller".to_string()
+    }
+    fn alice_account() -> AccountId {
+        "alice".to_string()
+    }
+    fn token_account() -> AccountId {
+        "token".to_string()
+    }
+    fn token2_account() -> AccountId {
+        "token2".to_string()
+    }
+
+    fn get_context(predecessor_account_id: AccountId) -> VMContext {
+        VMContext {
+            current_account_id: crp_account(),
+            signer_account_id: predecessor_account_id.clone(),
+            signer_account_pk: vec![0, 1, 2],
+            predecessor_account_id,
+            input: vec![],
+            block_index: 1,
+            block_timestamp: 0,
+            epoch_height: 1,
+            account_balance: 10u128.pow(26),
+            account_locked_balance: 0,
+            storage_usage: 10u64.pow(6),
+            attached_deposit: 0,
+            prepaid_gas: 10u64.pow(15),
+            random_seed: vec![0, 1, 2],
+            is_view: false,
+            output_data_receivers: vec![],
+        }
+    }
+
+    /// Switches to the smart pool calling itself back with given results of the promises.
+    fn testing_env_with_promise_results(promise_results: Vec<PromiseResult>) {
+        let storage = env::take_blockchain_interface()
+            .unwrap()
+            .as_mut_mocked_blockchain()
+            .unwrap()
+            .take_storage();
+        env::set_blockchain_interface(Box::new(MockedBlockchain::new(
+            get_context(crp_account()),
+            Default::default(),
+            Default::default(),
+            promise_results,
+            storage,
+            Default::default(),
+        )));
+    }
+
+    fn values(values: &[Balance]) -> Vec<PromiseResult> {
+        values
+            .iter()
+            .map(|value| PromiseResult::Successful(serde_json::to_vec(&U128(*value)).unwrap()))
+            .collect()
+    }
+
+    fn bool_result(value: bool) -> PromiseResult {
+        json_result(&value)
+    }
+
+    fn json_result<T: Serialize>(value: &T) -> PromiseResult {
+        PromiseResult::Successful(serde_json::to_vec(value).unwrap())
+    }
+
+    fn all_rights() -> Rights {
+        Rights {
+            can_pause_swapping: true,
+            can_change_swap_fee: true,
+            can_change_weights: true,
+            can_add_remove_tokens: true,
+            can_whitelist_lps: true,
+            can_change_cap: true,
+        }
+    }
+
+    /// Pool state of the token: balance 10, weight 5 of total 15 and the minimal fee.
+    fn pool_state() -> Vec<Balance> {
+        vec![10 * BONE, 5 * BONE, 15 * BONE, BONE / 1_000_000]
+    }
+
+    fn setup_crp(rights: Rights) -> ConfigurableRightsPool {
+        testing_env!(get_context(controller_account()));
+        let mut crp = ConfigurableRightsPool::new(bpool_account(), rights, None, None);
+        crp.createPool((100 * BONE).into());
+        let mut context = get_context(alice_account());
+        context.attached_deposit = 10u128.pow(24);
+        testing_env!(context);
+        crp.storage_deposit(None, None);
+        testing_env!(get_context(controller_account()));
+        crp
+    }
+
+    /// Joins alice with `amount` of the token through all the callbacks, with the rebind
+    /// succeeding if `rebound`.
+    fn join(crp: &mut ConfigurableRightsPool, amount: Balance, rebound: bool) -> Balance {
+        testing_env!(get_context(token_account()));
+        crp.ft_on_transfer(
+            alice_account(),
+            amount.into(),
+            r#"{"join_single": {"min_pool_amount_out": "0"}}"#.to_string(),
+        );
+        let pool_amount_out = crp.pool_out_given_single_in(&pool_state(), amount).unwrap();
+        testing_env_with_promise_results(values(&pool_state()));
+        crp.on_join(alice_account(), token_account(), amount.into(), 0.into());
+        testing_env_with_promise_results(values(&[amount]));
+        crp.on_join_deposit(
+            alice_account(),
+            token_account(),
+            amount.into(),
+            pool_amount_out.into(),
+        );
+        testing_env_with_promise_results(vec![bool_result(rebound)]);
+        assert_eq!(
+            crp.on_join_rebind(
+                alice_account(),
+                token_account(),
+                amount.into(),
+                pool_amount_out.into()
+            ),
+            0.into()
+        );
+        pool_amount_out
+    }
+
+    #[test]
+    fn test_rights() {
+        let mut crp = setup_crp(all_rights());
+        assert_eq!(crp.getRights(), all_rights());
+        assert_eq!(crp.get_balance(controller_account()), (100 * BONE).into());
+        crp.setSwapFee((BONE / 100).into());
+        crp.setPublicSwap(true);
+        crp.updateWeightsGradually(vec![(5 * BONE).into(), (10 * BONE).into()], 10, 20);
+        crp.setCap((200 * BONE).into());
+        assert_eq!(crp.getCap(), (200 * BONE).into());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_CONFIGURABLE_SWAP_FEE")]
+    fn test_no_rights() {
+        let mut crp = setup_crp(Rights::default());
+        crp.setSwapFee((BONE / 100).into());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_CONTROLLER")]
+    fn test_not_controller() {
+        let mut crp = setup_crp(all_rights());
+        testing_env!(get_context(alice_account()));
+        crp.setCap(0.into());
+    }
+
+    #[test]
+    fn test_whitelist() {
+        let mut crp = setup_crp(all_rights());
+        assert!(!crp.canProvideLiquidity(alice_account()));
+        crp.whitelistLiquidityProvider(alice_account());
+        assert!(crp.canProvideLiquidity(alice_account()));
+        let pool_amount_out = join(&mut crp, BONE, true);
+        assert!(pool_amount_out > 0);
+        assert_eq!(crp.get_balance(alice_account()), pool_amount_out.into());
+        assert_eq!(
+            crp.get_total_supply(),
+            (100 * BONE + pool_amount_out).into()
+        );
+        testing_env!(get_context(controller_account()));
+        crp.removeWhitelistedLiquidityProvider(alice_account());
+        assert!(!crp.canProvideLiquidity(alice_account()));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_ON_WHITELIST")]
+    fn test_join_not_whitelisted() {
+        let mut crp = setup_crp(all_rights());
+        join(&mut crp, BONE, true);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_REGISTERED")]
+    fn test_join_not_registered() {
+        let mut crp = setup_crp(Default::default());
+        testing_env!(get_context(token_account()));
+        crp.ft_on_transfer(
+            bpool_account(),
+            BONE.into(),
+            r#"{"join_single": {"min_pool_amount_out": "0"}}"#.to_string(),
+        );
+    }
+
+    #[test]
+    fn test_join_over_cap() {
+        let mut crp = setup_crp(Rights {
+            can_change_cap: true,
+            ..Default::default()
+        });
+        crp.setCap((100 * BONE).into());
+        testing_env!(get_context(token_account()));
+        crp.ft_on_transfer(
+            alice_account(),
+            BONE.into(),
+            r#"{"join_single": {"min_pool_amount_out": "0"}}"#.to_string(),
+        );
+        testing_env_with_promise_results(values(&pool_state()));
+        match crp.on_join(alice_account(), token_account(), BONE.into(), 0.into()) {
+            PromiseOrValue::Value(refund) => assert_eq!(refund, BONE.into()),
+            _ => panic!("Join over the cap should be refunded"),
+        }
+        // Lock is released, so the join goes through once the cap is raised.
+        testing_env!(get_context(controller_account()));
+        crp.setCap((200 * BONE).into());
+        assert!(join(&mut crp, BONE, true) > 0);
+    }
+
+    #[test]
+    fn test_join_rebind_failed() {
+        let mut crp = setup_crp(Rights::default());
+        join(&mut crp, BONE, false);
+        assert_eq!(crp.get_balance(alice_account()), 0.into());
+        // Locked until the tokens are withdrawn from the pool.
+        assert!(crp.mutex);
+        assert_eq!(crp.getClaim(alice_account(), token_account()), 0.into());
+        testing_env_with_promise_results(vec![bool_result(true)]);
+        assert!(crp.on_withdraw(alice_account(), token_account(), BONE.into()));
+        assert!(!crp.mutex);
+        // Tokens that couldn't be sent are kept as a claim.
+        testing_env_with_promise_results(vec![PromiseResult::Failed]);
+        assert!(!crp.on_claim(alice_account(), token_account(), BONE.into()));
+        assert_eq!(crp.getClaim(alice_account(), token_account()), BONE.into());
+        testing_env!(get_context(alice_account()));
+        crp.claim(token_account());
+        assert_eq!(crp.getClaim(alice_account(), token_account()), 0.into());
+    }
+
+    #[test]
+    fn test_join_rebind_failed_withdraw_failed() {
+        let mut crp = setup_crp(Rights::default());
+        join(&mut crp, BONE, false);
+        testing_env_with_promise_results(vec![PromiseResult::Failed]);
+        assert!(!crp.on_withdraw(alice_account(), token_account(), BONE.into()));
+        assert!(!crp.mutex);
+        assert_eq!(crp.getClaim(alice_account(), token_account()), 0.into());
+        assert_eq!(
+            crp.getDeposit(alice_account(), token_account()),
+            BONE.into()
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_CREATED")]
+    fn test_exit_not_created() {
+        testing_env!(get_context(controller_account()));
+        let mut crp = ConfigurableRightsPool::new(bpool_account(), Rights::default(), None, None);
+        crp.exitswapPoolAmountIn(token_account(), BONE.into(), 0.into());
+    }
+
+    #[test]
+    fn test_exit() {
+        let mut crp = setup_crp(Rights::default());
+        let pool_amount_in = 10 * BONE;
+        crp.exitswapPoolAmountIn(token_account(), pool_amount_in.into(), 0.into());
+        assert_eq!(crp.get_balance(controller_account()), (90 * BONE).into());
+        let amount_out = crp
+            .single_out_given_pool_in(&pool_state(), pool_amount_in)
+            .unwrap();
+        testing_env_with_promise_results(values(&pool_state()));
+        assert!(crp.on_exit(
+            controller_account(),
+            token_account(),
+            pool_amount_in.into(),
+            0.into()
+        ));
+        testing_env_with_promise_results(vec![bool_result(true)]);
+        assert!(crp.on_exit_rebind(
+            controller_account(),
+            token_account(),
+            pool_amount_in.into(),
+            amount_out.into()
+        ));
+        assert_eq!(crp.get_total_supply(), (90 * BONE).into());
+        let logs = take_logs();
+        assert_eq!(
+            EventLog::from_log(logs.last().unwrap()).unwrap().event,
+            Event::Exit {
+                caller: controller_account(),
+                token_out: token_account(),
+                token_amount_out: amount_out.into(),
+            }
+        );
+        // Tokens that couldn't be sent are kept as a claim.
+        testing_env_with_promise_results(vec![PromiseResult::Failed]);
+        assert!(!crp.on_claim(controller_account(), token_account(), amount_out.into()));
+        assert_eq!(
+            crp.getClaim(controller_account(), token_account()),
+            amount_out.into()
+        );
+    }
+
+    #[test]
+    fn test_exit_over_limit() {
+        let mut crp = setup_crp(Rights::default());
+        crp.exitswapPoolAmountIn(token_account(), (10 * BONE).into(), (10 * BONE).into());
+        testing_env_with_promise_results(values(&pool_state()));
+        assert!(!crp.on_exit(
+            controller_account(),
+            token_account(),
+            (10 * BONE).into(),
+            (10 * BONE).into()
+        ));
+        assert_eq!(crp.get_balance(controller_account()), (100 * BONE).into());
+        assert_eq!(crp.get_total_supply(), (100 * BONE).into());
+    }
+
+    #[test]
+    fn test_exit_push_failed() {
+        let mut crp = setup_crp(Rights::default());
+        crp.exitswapPoolAmountIn(token_account(), (10 * BONE).into(), 0.into());
+        testing_env_with_promise_results(values(&pool_state()));
+        assert!(crp.on_exit(
+            controller_account(),
+            token_account(),
+            (10 * BONE).into(),
+            0.into()
+        ));
+        // The pool restores its balance if it couldn't send the tokens.
+        testing_env_with_promise_results(vec![bool_result(false)]);
+        assert!(!crp.on_exit_rebind(
+            controller_account(),
+            token_account(),
+            (10 * BONE).into(),
+            BONE.into()
+        ));
+        assert_eq!(crp.get_balance(controller_account()), (100 * BONE).into());
+        assert_eq!(crp.get_total_supply(), (100 * BONE).into());
+        assert_eq!(
+            crp.getClaim(controller_account(), token_account()),
+            0.into()
+        );
+    }
+
+    #[test]
+    fn test_update_weight() {
+        let mut crp = setup_crp(all_rights());
+        crp.updateWeight(token_account(), (10 * BONE).into());
+        testing_env_with_promise_results(vec![bool_result(false)]);
+        assert!(!crp.on_update_weight());
+        // Lock is released either way.
+        testing_env!(get_context(controller_account()));
+        crp.updateWeight(token_account(), (10 * BONE).into());
+        testing_env_with_promise_results(vec![bool_result(true)]);
+        assert!(crp.on_update_weight());
+    }
+
+    #[test]
+    fn test_remove_token() {
+        let mut crp = setup_crp(all_rights());
+        crp.removeToken(token_account());
+        testing_env_with_promise_results(values(&pool_state()));
+        assert!(crp.on_remove_token(token_account()));
+        // Shares of the token's third of the weight are in escrow until the unbind resolves.
+        let pool_shares = bmul(100 * BONE, bdiv(5 * BONE, 15 * BONE));
+        assert_eq!(
+            crp.get_balance(controller_account()).0,
+            100 * BONE - pool_shares
+        );
+        testing_env_with_promise_results(vec![PromiseResult::Failed]);
+        assert_eq!(crp.on_unbind(token_account(), pool_shares.into()), 0.into());
+        assert_eq!(crp.get_balance(controller_account()), (100 * BONE).into());
+
+        testing_env!(get_context(controller_account()));
+        crp.removeToken(token_account());
+        testing_env_with_promise_results(values(&pool_state()));
+        assert!(crp.on_remove_token(token_account()));
+        // The pool returns the balance it had at the time of the unbind.
+        testing_env_with_promise_results(values(&[11 * BONE]));
+        assert_eq!(
+            crp.on_unbind(token_account(), pool_shares.into()),
+            (11 * BONE).into()
+        );
+        assert_eq!(crp.get_total_supply().0, 100 * BONE - pool_shares);
+        assert_eq!(
+            crp.getClaim(controller_account(), token_account()),
+            (11 * BONE).into()
+        );
+    }
+
+    /// Deposits `amount` of `token` for alice, with the pool taking all of it.
+    fn deposit(crp: &mut ConfigurableRightsPool, token: AccountId, amount: Balance) {
+        testing_env!(get_context(token.clone()));
+        crp.ft_on_transfer(alice_account(), amount.into(), r#""deposit""#.to_string());
+        testing_env_with_promise_results(values(&[amount]));
+        assert_eq!(
+            crp.on_deposit(alice_account(), token, amount.into()),
+            0.into()
+        );
+        testing_env!(get_context(alice_account()));
+    }
+
+    #[test]
+    fn test_join_exit_pool() {
+        let mut crp = setup_crp(Rights::default());
+        deposit(&mut crp, token_account(), 10 * BONE);
+        deposit(&mut crp, token2_account(), 10 * BONE);
+        let tokens = vec![token_account(), token2_account()];
+        let max_amounts_in = vec![(5 * BONE).into(), (5 * BONE).into()];
+
+        crp.joinPool((10 * BONE).into(), max_amounts_in.clone());
+        testing_env_with_promise_results(vec![json_result(&tokens)]);
+        assert!(crp.on_join_pool(alice_account(), (10 * BONE).into(), max_amounts_in.clone()));
+        assert_eq!(
+            crp.getDeposit(alice_account(), token_account()),
+            (5 * BONE).into()
+        );
+        let amounts: Vec<(AccountId, U128)> = vec![
+            (token_account(), BONE.into()),
+            (token2_account(), (2 * BONE).into()),
+        ];
+        testing_env_with_promise_results(vec![json_result(&amounts)]);
+        assert!(crp.on_join_pool_rebind(
+            alice_account(),
+            (10 * BONE).into(),
+            tokens.clone(),
+            max_amounts_in
+        ));
+        assert_eq!(crp.get_balance(alice_account()), (10 * BONE).into());
+        assert_eq!(
+            crp.getDeposit(alice_account(), token_account()),
+            (9 * BONE).into()
+        );
+        assert_eq!(
+            crp.getDeposit(alice_account(), token2_account()),
+            (8 * BONE).into()
+        );
+
+        testing_env!(get_context(alice_account()));
+        crp.exitPool((5 * BONE).into(), vec![0.into(), 0.into()]);
+        assert_eq!(crp.get_balance(alice_account()), (5 * BONE).into());
+        testing_env_with_promise_results(vec![PromiseResult::Failed]);
+        assert!(!crp.on_exit_pool(alice_account(), (5 * BONE).into()));
+        assert_eq!(crp.get_balance(alice_account()), (10 * BONE).into());
+
+        testing_env!(get_context(alice_account()));
+        crp.exitPool((5 * BONE).into(), vec![0.into(), 0.into()]);
+        testing_env_with_promise_results(vec![json_result(&amounts)]);
+        assert!(crp.on_exit_pool(alice_account(), (5 * BONE).into()));
+        assert_eq!(crp.get_balance(alice_account()), (5 * BONE).into());
+        assert_eq!(crp.get_total_supply(), (105 * BONE).into());
+        assert_eq!(
+            crp.getDeposit(alice_account(), token2_account()),
+            (10 * BONE).into()
+        );
+        let logs = take_logs();
+        assert_eq!(
+            EventLog::from_log(logs.last().unwrap()).unwrap().event,
+            Event::Exit {
+                caller: alice_account(),
+                token_out: token2_account(),
+                token_amount_out: (2 * BONE).into(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_join_pool_no_deposit() {
+        let mut crp = setup_crp(Rights::default());
+        deposit(&mut crp, token_account(), 10 * BONE);
+        let max_amounts_in = vec![(5 * BONE).into(), (5 * BONE).into()];
+        crp.joinPool((10 * BONE).into(), max_amounts_in.clone());
+        testing_env_with_promise_results(vec![json_result(&vec![
+            token_account(),
+            token2_account(),
+        ])]);
+        assert!(!crp.on_join_pool(alice_account(), (10 * BONE).into(), max_amounts_in));
+        assert_eq!(
+            crp.getDeposit(alice_account(), token_account()),
+            (10 * BONE).into()
+        );
+        // The lock is released.
+        testing_env!(get_context(alice_account()));
+        crp.withdraw(token_account(), BONE.into());
+    }
+
+    #[test]
+    fn test_withdraw_sync_deposit() {
+        let mut crp = setup_crp(Rights::default());
+        deposit(&mut crp, token_account(), 10 * BONE);
+        crp.withdraw(token_account(), (4 * BONE).into());
+        testing_env_with_promise_results(vec![bool_result(false)]);
+        assert!(!crp.on_withdraw(alice_account(), token_account(), (4 * BONE).into()));
+        assert_eq!(
+            crp.getDeposit(alice_account(), token_account()),
+            (10 * BONE).into()
+        );
+
+        testing_env!(get_context(alice_account()));
+        crp.withdraw(token_account(), (4 * BONE).into());
+        testing_env_with_promise_results(vec![bool_result(true)]);
+        assert!(crp.on_withdraw(alice_account(), token_account(), (4 * BONE).into()));
+        assert_eq!(
+            crp.getDeposit(alice_account(), token_account()),
+            (6 * BONE).into()
+        );
+
+        // Only the deposit beyond the deposits of accounts is withdrawn.
+        crp.syncDeposit(token_account());
+        testing_env_with_promise_results(values(&[7 * BONE]));
+        assert_eq!(crp.on_sync_deposit(token_account()), BONE.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_ZERO_AMOUNT")]
+    fn test_withdraw_zero() {
+        let mut crp = setup_crp(Rights::default());
+        testing_env!(get_context(alice_account()));
+        crp.withdraw(token_account(), 0.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_INSUFFICIENT_DEPOSIT")]
+    fn test_withdraw_unknown_token() {
+        let mut crp = setup_crp(Rights::default());
+        testing_env!(get_context(alice_account()));
+        crp.withdraw(token_account(), BONE.into());
+    }
+}
@@ -1,11 +1,20 @@
-use near_sdk::{AccountId, Balance, env, ext_contract, Promise, StorageUsage};
+use near_sdk::{AccountId, Balance, env, ext_contract, Gas, Promise, PromiseOrValue, PromiseResult, StorageUsage};
 use borsh::{self, BorshDeserialize, BorshSerialize};
 use near_sdk::collections::UnorderedMap;
-use near_sdk::json_types::U128;
+use near_sdk::json_types::{Base64VecU8, U128};
+use serde::{Deserialize, Serialize};
 
 /// Price per 1 byte of storage from mainnet genesis config.
 const STORAGE_PRICE_PER_BYTE: Balance = 100000000000000000000;
 
+const NO_DEPOSIT: Balance = 0;
+
+/// Gas reserved for `ft_resolve_transfer` callback of `ft_transfer_call`.
+const GAS_FOR_RESOLVE_TRANSFER: Gas = 5_000_000_000_000;
+
+/// Gas `ft_transfer_call` keeps for itself, the rest is passed to `ft_on_transfer`.
+const GAS_FOR_FT_TRANSFER_CALL: Gas = 25_000_000_000_000 + GAS_FOR_RESOLVE_TRANSFER;
+
 /// Contains balance and allowances information for one account.
 #[derive(BorshDeserialize, BorshSerialize)]
 pub struct Account {
@@ -43,6 +52,70 @@ impl Account {
     }
 }
 
+/// Version of NEP-148 metadata standard.
+pub const FT_METADATA_SPEC: &str = "ft-1.0.0";
+
+/// Fungible token metadata, see NEP-148.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FungibleTokenMetadata {
+    pub spec: String,
+    pub name: String,
+    pub symbol: String,
+    pub icon: Option<String>,
+    pub reference: Option<String>,
+    pub reference_hash: Option<Base64VecU8>,
+    pub decimals: u8,
+}
+
+impl FungibleTokenMetadata {
+    /// Metadata without icon and reference.
+    pub fn new(name: String, symbol: String, decimals: u8) -> Self {
+        Self {
+            spec: FT_METADATA_SPEC.to_string(),
+            name,
+            symbol,
+            icon: None,
+            reference: None,
+            reference_hash: None,
+            decimals,
+        }
+    }
+
+    pub fn assert_valid(&self) {
+        assert_eq!(self.spec, FT_METADATA_SPEC, "Unsupported metadata spec");
+        assert!(!self.symbol.is_empty(), "Symbol should not be empty");
+        assert_eq!(
+            self.reference.is_some(),
+            self.reference_hash.is_some(),
+            "Reference and reference hash should be set together"
+        );
+        if let Some(reference_hash) = &self.reference_hash {
+            assert_eq!(reference_hash.0.len(), 32, "Hash has to be 32 bytes");
+        }
+    }
+}
+
+/// Storage balance of a registered account, see NEP-145.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct StorageBalance {
+    pub total: U128,
+    pub available: U128,
+}
+
+/// Storage balance required to register an account, see NEP-145.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct StorageBalanceBounds {
+    pub min: U128,
+    pub max: Option<U128>,
+}
+
+/// Token state before NEP-145 storage management, kept to migrate deployed contracts.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct LegacyToken {
+    pub accounts: UnorderedMap<Vec<u8>, Account>,
+    pub total_supply: Balance,
+}
+
 #[derive(BorshDeserialize, BorshSerialize)]
 pub struct Token {
     /// sha256(AccountID) -> Account details.
@@ -50,6 +123,13 @@ pub struct Token {
 
     /// Total supply of the all token.
     pub total_supply: Balance,
+
+    /// sha256(AccountID) -> storage deposit of the registered account.
+    pub storage_deposits: UnorderedMap<Vec<u8>, Balance>,
+
+    /// Storage used by one registered account, measured on initialization. Contracts add the
+    /// storage of their own records of an account to it.
+    pub account_storage_usage: StorageUsage,
 }
 
 impl Token {
@@ -57,13 +137,44 @@ impl Token {
         let mut ft = Self {
             accounts: UnorderedMap::new(b"a".to_vec()),
             total_supply,
+            storage_deposits: UnorderedMap::new(b"s".to_vec()),
+            account_storage_usage: 0,
         };
+        ft.measure_account_storage_usage();
         let mut account = ft.get_account(&owner_id);
         account.balance = total_supply;
         ft.set_account(&owner_id, &account);
         ft
     }
 
+    /// Migrates `legacy` state, registering all its accounts without a storage deposit.
+    pub fn from_legacy(legacy: LegacyToken) -> Self {
+        let mut ft = Self {
+            accounts: legacy.accounts,
+            total_supply: legacy.total_supply,
+            storage_deposits: UnorderedMap::new(b"s".to_vec()),
+            account_storage_usage: 0,
+        };
+        ft.measure_account_storage_usage();
+        for account_hash in ft.accounts.keys() {
+            ft.storage_deposits.insert(&account_hash, &0);
+        }
+        ft
+    }
+
+    /// Measures storage of an account with the longest ID, balance and storage deposit.
+    fn measure_account_storage_usage(&mut self) {
+        let initial_storage = env::storage_usage();
+        let account_hash = env::sha256("a".repeat(64).as_bytes());
+        let mut account = Account::new(account_hash.clone());
+        account.balance = Balance::max_value();
+        self.accounts.insert(&account_hash, &account);
+        self.storage_deposits.insert(&account_hash, &Balance::max_value());
+        self.account_storage_usage = env::storage_usage() - initial_storage;
+        self.accounts.remove(&account_hash);
+        self.storage_deposits.remove(&account_hash);
+    }
+
     pub fn inc_allowance(&mut self, escrow_account_id: AccountId, amount: Balance) {
         let initial_storage = env::storage_usage();
         assert!(
@@ -140,12 +251,222 @@ impl Token {
         self.refund_storage(initial_storage);
     }
 
+    /// Moves `amount` from `owner_id` to `new_owner_id` without checking allowance or charging
+    /// for storage. Used by contracts that move tokens on their own behalf.
+    pub fn internal_transfer(&mut self, owner_id: AccountId, new_owner_id: AccountId, amount: Balance) {
+        assert!(
+            env::is_valid_account_id(new_owner_id.as_bytes()),
+            "New owner's account ID is invalid"
+        );
+        if amount == 0 {
+            env::panic(b"Can't transfer 0 tokens");
+        }
+        assert_ne!(
+            owner_id, new_owner_id,
+            "The new owner should be different from the current owner"
+        );
+        // Retrieving the account from the state.
+        let mut account = self.get_account(&owner_id);
+
+        // Checking and updating unlocked balance
+        if account.balance < amount {
+            env::panic(b"Not enough balance");
+        }
+        account.balance -= amount;
+
+        // Saving the account back to the state.
+        self.set_account(&owner_id, &account);
+
+        // Deposit amount to the new owner and save the new account to the state.
+        let mut new_account = self.get_account(&new_owner_id);
+        new_account.balance += amount;
+        self.set_account(&new_owner_id, &new_account);
+    }
+
     pub fn transfer(&mut self, new_owner_id: AccountId, amount: Balance) {
         // NOTE: New owner's Account ID checked in transfer_from.
         // Storage fees are also refunded in transfer_from.
         self.transfer_from(env::predecessor_account_id(), new_owner_id, amount);
     }
 
+    /// NEP-141 transfer of `amount` from the caller to `receiver_id`.
+    /// Requires exactly 1 yoctoNEAR attached and `receiver_id` registered with `storage_deposit`.
+    pub fn ft_transfer(&mut self, receiver_id: AccountId, amount: Balance, memo: Option<String>) {
+        assert_one_yocto();
+        self.assert_registered(&receiver_id);
+        let sender_id = env::predecessor_account_id();
+        self.internal_transfer(sender_id, receiver_id, amount);
+        log_memo(memo);
+    }
+
+    /// NEP-141 transfer of `amount` from the caller to `receiver_id` contract, that is notified
+    /// with `ft_on_transfer(sender_id, amount, msg)` and returns the amount it didn't use.
+    /// Unused amount is refunded back to the sender in `ft_resolve_transfer`.
+    pub fn ft_transfer_call(
+        &mut self,
+        receiver_id: AccountId,
+        amount: Balance,
+        memo: Option<String>,
+        msg: String,
+    ) -> Promise {
+        assert_one_yocto();
+        assert!(
+            env::prepaid_gas() > GAS_FOR_FT_TRANSFER_CALL,
+            "More gas is required"
+        );
+        self.assert_registered(&receiver_id);
+        let sender_id = env::predecessor_account_id();
+        self.internal_transfer(sender_id.clone(), receiver_id.clone(), amount);
+        log_memo(memo);
+        ext_ft_receiver::ft_on_transfer(
+            sender_id.clone(),
+            amount.into(),
+            msg,
+            &receiver_id,
+            NO_DEPOSIT,
+            env::prepaid_gas() - GAS_FOR_FT_TRANSFER_CALL,
+        )
+        .then(ext_ft_resolver::ft_resolve_transfer(
+            sender_id,
+            receiver_id,
+            amount.into(),
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_RESOLVE_TRANSFER,
+        ))
+    }
+
+    /// Callback of `ft_transfer_call`: refunds to `sender_id` the amount that `receiver_id`
+    /// didn't use, or all of it if `ft_on_transfer` failed. Returns the used amount.
+    pub fn ft_resolve_transfer(
+        &mut self,
+        sender_id: AccountId,
+        receiver_id: AccountId,
+        amount: Balance,
+    ) -> Balance {
+        assert_eq!(
+            env::predecessor_account_id(),
+            env::current_account_id(),
+            "Method is private"
+        );
+        assert_eq!(env::promise_results_count(), 1, "Expected 1 promise result");
+        let unused_amount = match env::promise_result(0) {
+            PromiseResult::Successful(value) => match serde_json::from_slice::<U128>(&value) {
+                Ok(unused_amount) => std::cmp::min(amount, unused_amount.0),
+                Err(_) => amount,
+            },
+            _ => amount,
+        };
+        // Receiver may have already moved the tokens, refund only what's left.
+        let refund_amount = std::cmp::min(unused_amount, self.get_balance(receiver_id.clone()));
+        if refund_amount > 0 {
+            self.internal_transfer(receiver_id, sender_id, refund_amount);
+        }
+        amount - refund_amount
+    }
+
+    /// NEP-145 registration of `account_id` or the caller. Attached deposit should cover
+    /// `storage_balance_bounds().min`, the rest is refunded.
+    pub fn storage_deposit(&mut self, account_id: Option<AccountId>) -> StorageBalance {
+        let account_id = account_id.unwrap_or_else(env::predecessor_account_id);
+        let amount = env::attached_deposit();
+        let refund_amount = if self.is_registered(&account_id) {
+            amount
+        } else {
+            let min_balance = self.storage_balance_bounds().min.0;
+            assert!(
+                amount >= min_balance,
+                "The attached deposit is less than the minimum storage balance"
+            );
+            let account_hash = env::sha256(account_id.as_bytes());
+            self.storage_deposits.insert(&account_hash, &min_balance);
+            let account = self.get_account(&account_id);
+            self.set_account(&account_id, &account);
+            amount - min_balance
+        };
+        if refund_amount > 0 {
+            Promise::new(env::predecessor_account_id()).transfer(refund_amount);
+        }
+        self.storage_balance_of(account_id).unwrap()
+    }
+
+    /// NEP-145 withdrawal of storage balance. Storage deposit is fixed, so nothing is available.
+    pub fn storage_withdraw(&mut self, amount: Option<Balance>) -> StorageBalance {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        self.assert_registered(&account_id);
+        assert_eq!(
+            amount.unwrap_or(0),
+            0,
+            "The amount is greater than the available storage balance"
+        );
+        self.storage_balance_of(account_id).unwrap()
+    }
+
+    /// NEP-145 unregistration of the caller, returning its storage deposit.
+    /// Account with positive balance can only be removed with `force`, burning its tokens.
+    pub fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let account_hash = env::sha256(account_id.as_bytes());
+        let storage_deposit = match self.storage_deposits.get(&account_hash) {
+            Some(storage_deposit) => storage_deposit,
+            None => return false,
+        };
+        let mut account = self.get_account(&account_id);
+        if account.balance > 0 {
+            assert!(
+                force.unwrap_or(false),
+                "Can't unregister the account with the positive balance without force"
+            );
+            self.total_supply -= account.balance;
+        }
+        account.allowances.clear();
+        self.storage_deposits.remove(&account_hash);
+        self.accounts.remove(&account_hash);
+        Promise::new(account_id).transfer(storage_deposit + 1);
+        true
+    }
+
+    pub fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+        let min = Balance::from(self.account_storage_usage) * STORAGE_PRICE_PER_BYTE;
+        StorageBalanceBounds {
+            min: min.into(),
+            max: Some(min.into()),
+        }
+    }
+
+    pub fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance> {
+        self.storage_deposits
+            .get(&env::sha256(account_id.as_bytes()))
+            .map(|total| StorageBalance {
+                total: total.into(),
+                available: 0.into(),
+            })
+    }
+
+    pub fn is_registered(&self, account_id: &AccountId) -> bool {
+        self.storage_deposits
+            .get(&env::sha256(account_id.as_bytes()))
+            .is_some()
+    }
+
+    /// Registers `account_id` without a storage deposit, for accounts whose storage
+    /// the contract covers itself.
+    pub fn register_account(&mut self, account_id: &AccountId) {
+        if !self.is_registered(account_id) {
+            self.storage_deposits.insert(&env::sha256(account_id.as_bytes()), &0);
+            let account = self.get_account(account_id);
+            self.set_account(account_id, &account);
+        }
+    }
+
+    fn assert_registered(&self, account_id: &AccountId) {
+        if !self.is_registered(account_id) {
+            env::panic(format!("The account {} is not registered", account_id).as_bytes());
+        }
+    }
+
     pub fn mint(&mut self, account_id: AccountId, amount: Balance) {
         let mut account = self.get_account(&account_id);
         account.balance += amount;
@@ -192,9 +513,13 @@ impl Token {
     }
 
     /// Helper method to set the account details for `owner_id` to the state.
+    /// Empty accounts are removed, unless registered with `storage_deposit`.
     pub fn set_account(&mut self, owner_id: &AccountId, account: &Account) {
         let account_hash = env::sha256(owner_id.as_bytes());
-        if account.balance > 0 || !account.allowances.is_empty() {
+        if account.balance > 0
+            || !account.allowances.is_empty()
+            || self.storage_deposits.get(&account_hash).is_some()
+        {
             self.accounts.insert(&account_hash, &account);
         } else {
             self.accounts.remove(&account_hash);
@@ -225,6 +550,21 @@ impl Token {
     }
 }
 
+/// Requires exactly 1 yoctoNEAR attached, so that the call is signed with a full access key.
+fn assert_one_yocto() {
+    assert_eq!(
+        env::attached_deposit(),
+        1,
+        "Requires attached deposit of exactly 1 yoctoNEAR"
+    );
+}
+
+fn log_memo(memo: Option<String>) {
+    if let Some(memo) = memo {
+        env::log(format!("Memo: {}", memo).as_bytes());
+    }
+}
+
 /// Trait with FungibleToken interface that implements NEP-21 standard.
 pub trait FungibleToken {
     /// Increments the `allowance` for `escrow_account_id` by `amount` on the account of the caller of this contract
@@ -277,9 +617,114 @@ pub trait FungibleToken {
 
 #[ext_contract(ext_nep21)]
 pub trait ExtNEP21 {
+    fn inc_allowance(&mut self, escrow_account_id: AccountId, amount: U128);
+
     fn transfer(&mut self, new_owner_id: AccountId, amount: U128);
 
     fn transfer_from(&mut self, owner_id: AccountId, new_owner_id: AccountId, amount: U128);
 
     fn get_balance(&self, owner_id: AccountId) -> U128;
 }
+
+/// Trait with core interface of NEP-141 fungible token standard.
+pub trait FungibleTokenCore {
+    /// Transfers `amount` of tokens from the caller of the contract (`predecessor_id`) to
+    /// `receiver_id`.
+    /// Requirements:
+    /// * Caller of the method has to attach exactly 1 yoctoNEAR.
+    /// * `amount` should be a positive integer, not greater than the balance of the caller.
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>);
+
+    /// Transfers `amount` of tokens to `receiver_id` and calls `ft_on_transfer` on it with `msg`.
+    /// Whatever `receiver_id` returns as unused gets refunded to the caller in
+    /// `ft_resolve_transfer`.
+    /// Requirements:
+    /// * Caller of the method has to attach exactly 1 yoctoNEAR.
+    /// * Enough gas attached for `ft_on_transfer` and `ft_resolve_transfer`.
+    fn ft_transfer_call(
+        &mut self,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+        msg: String,
+    ) -> Promise;
+
+    /// Returns total supply of tokens.
+    fn ft_total_supply(&self) -> U128;
+
+    /// Returns balance of the `account_id` account.
+    fn ft_balance_of(&self, account_id: AccountId) -> U128;
+}
+
+/// Callback of the token contract that finishes `ft_transfer_call`.
+pub trait FungibleTokenResolver {
+    /// Refunds the amount unused by `receiver_id` to `sender_id`, returns the used amount.
+    fn ft_resolve_transfer(&mut self, sender_id: AccountId, receiver_id: AccountId, amount: U128) -> U128;
+}
+
+/// Trait for contracts that receive tokens via `ft_transfer_call`.
+pub trait FungibleTokenReceiver {
+    /// Called by the token contract after `amount` of tokens was transferred from `sender_id`.
+    /// Returns the amount of tokens that should be refunded to the sender.
+    fn ft_on_transfer(&mut self, sender_id: AccountId, amount: U128, msg: String) -> PromiseOrValue<U128>;
+}
+
+#[ext_contract(ext_nep141)]
+pub trait ExtNEP141 {
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>);
+
+    fn ft_transfer_call(
+        &mut self,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+        msg: String,
+    );
+
+    fn ft_balance_of(&self, account_id: AccountId) -> U128;
+}
+
+#[ext_contract(ext_ft_receiver)]
+pub trait ExtFungibleTokenReceiver {
+    fn ft_on_transfer(&mut self, sender_id: AccountId, amount: U128, msg: String);
+}
+
+#[ext_contract(ext_ft_resolver)]
+pub trait ExtFungibleTokenResolver {
+    fn ft_resolve_transfer(&mut self, sender_id: AccountId, receiver_id: AccountId, amount: U128);
+}
+
+/// Trait with NEP-145 storage management interface.
+pub trait StorageManagement {
+    /// Registers `account_id` or the caller of the contract (`predecessor_id`) if not given.
+    /// Requirements:
+    /// * Caller of the method has to attach deposit of at least `storage_balance_bounds().min`,
+    ///   any excess or the whole deposit for already registered account is refunded.
+    fn storage_deposit(
+        &mut self,
+        account_id: Option<AccountId>,
+        registration_only: Option<bool>,
+    ) -> StorageBalance;
+
+    /// Withdraws `amount` of available storage balance of the caller.
+    /// Requirements:
+    /// * Caller of the method has to attach exactly 1 yoctoNEAR.
+    fn storage_withdraw(&mut self, amount: Option<U128>) -> StorageBalance;
+
+    /// Unregisters the caller and returns its storage deposit.
+    /// Requirements:
+    /// * Caller of the method has to attach exactly 1 yoctoNEAR.
+    /// * Balance of the caller should be 0, unless `force` is set, which burns it.
+    fn storage_unregister(&mut self, force: Option<bool>) -> bool;
+
+    /// Returns the storage balance required to register an account.
+    fn storage_balance_bounds(&self) -> StorageBalanceBounds;
+
+    /// Returns storage balance of `account_id` or `None` if it's not registered.
+    fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance>;
+}
+
+/// Trait with NEP-148 metadata view.
+pub trait FungibleTokenMetadataProvider {
+    fn ft_metadata(&self) -> FungibleTokenMetadata;
+}
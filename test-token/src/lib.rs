@@ -1,8 +1,11 @@
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
 use near_sdk::json_types::U128;
-use near_sdk::{env, near_bindgen, AccountId};
+use near_sdk::{env, near_bindgen, AccountId, Promise};
 
-use near_lib::token::{FungibleToken, Token};
+use near_lib::token::{
+    FungibleToken, FungibleTokenCore, FungibleTokenMetadata, FungibleTokenMetadataProvider,
+    FungibleTokenResolver, StorageBalance, StorageBalanceBounds, StorageManagement, Token,
+};
 
 #[global_allocator]
 static ALLOC: near_sdk::wee_alloc::WeeAlloc<'_> = near_sdk::wee_alloc::WeeAlloc::INIT;
@@ -11,6 +14,7 @@ static ALLOC: near_sdk::wee_alloc::WeeAlloc<'_> = near_sdk::wee_alloc::WeeAlloc:
 #[derive(BorshDeserialize, BorshSerialize)]
 pub struct TToken {
     token: Token,
+    metadata: FungibleTokenMetadata,
 }
 
 impl Default for TToken {
@@ -22,12 +26,22 @@ impl Default for TToken {
 #[near_bindgen]
 impl TToken {
     /// Initializes the contract with the given total supply owned by the given `owner_id`.
+    /// Symbol defaults to the contract's account ID and decimals to 24.
     #[init]
-    pub fn new(owner_id: AccountId, total_supply: U128) -> Self {
+    pub fn new(
+        owner_id: AccountId,
+        total_supply: U128,
+        decimals: Option<u8>,
+        symbol: Option<String>,
+    ) -> Self {
         let total_supply = total_supply.into();
         assert!(!env::state_exists(), "Already initialized");
+        let symbol = symbol.unwrap_or_else(|| env::current_account_id().to_uppercase());
+        let metadata = FungibleTokenMetadata::new(symbol.clone(), symbol, decimals.unwrap_or(24));
+        metadata.assert_valid();
         Self {
             token: Token::new(owner_id, total_supply),
+            metadata,
         }
     }
 
@@ -71,3 +85,85 @@ impl FungibleToken for TToken {
         self.token.get_allowance(owner_id, escrow_account_id).into()
     }
 }
+
+#[near_bindgen]
+impl FungibleTokenCore for TToken {
+    #[payable]
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>) {
+        self.token.ft_transfer(receiver_id, amount.into(), memo);
+    }
+
+    #[payable]
+    fn ft_transfer_call(
+        &mut self,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+        msg: String,
+    ) -> Promise {
+        self.token
+            .ft_transfer_call(receiver_id, amount.into(), memo, msg)
+    }
+
+    fn ft_total_supply(&self) -> U128 {
+        self.token.get_total_supply().into()
+    }
+
+    fn ft_balance_of(&self, account_id: AccountId) -> U128 {
+        self.token.get_balance(account_id).into()
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenResolver for TToken {
+    fn ft_resolve_transfer(
+        &mut self,
+        sender_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+    ) -> U128 {
+        self.token
+            .ft_resolve_transfer(sender_id, receiver_id, amount.into())
+            .into()
+    }
+}
+
+#[near_bindgen]
+impl StorageManagement for TToken {
+    #[payable]
+    fn storage_deposit(
+        &mut self,
+        account_id: Option<AccountId>,
+        registration_only: Option<bool>,
+    ) -> StorageBalance {
+        // Storage balance is fixed, so any excess is refunded regardless of `registration_only`.
+        let _ = registration_only;
+        self.token.storage_deposit(account_id)
+    }
+
+    #[payable]
+    fn storage_withdraw(&mut self, amount: Option<U128>) -> StorageBalance {
+        self.token
+            .storage_withdraw(amount.map(|amount| amount.into()))
+    }
+
+    #[payable]
+    fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+        self.token.storage_unregister(force)
+    }
+
+    fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+        self.token.storage_balance_bounds()
+    }
+
+    fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance> {
+        self.token.storage_balance_of(account_id)
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenMetadataProvider for TToken {
+    fn ft_metadata(&self) -> FungibleTokenMetadata {
+        self.metadata.clone()
+    }
+}